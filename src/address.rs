@@ -0,0 +1,350 @@
+use crate::flags::MapiStatusCode;
+use crate::logger;
+
+/// longest local part allowed by RFC 5321
+const MAX_LOCAL_PART_LENGTH: usize = 64;
+/// longest domain allowed by RFC 1035
+const MAX_DOMAIN_LENGTH: usize = 253;
+/// longest label in a domain allowed by RFC 1035
+const MAX_LABEL_LENGTH: usize = 63;
+
+/// characters besides ascii alphanumerics that are allowed in a dot-atom (RFC 5322 atext)
+const ATEXT_SPECIALS: &str = "!#$%&'*+-/=?^_`{|}~";
+
+// prefixes apps put in front of addresses. they're all matched case-insensitively.
+const SMTP_PREFIX: &str = "smtp:";
+const MAILTO_PREFIX: &str = "mailto:";
+// prefix of legacy exchange addresses ("EX:/o=org/ou=.../cn=Recipients/cn=jdoe")
+const EXCHANGE_PREFIX: &str = "ex:";
+
+/// turn the address of a recipient into a plain addr-spec (local@domain).
+///
+/// strips SMTP:, smtp: and mailto: prefixes and surrounding angle brackets,
+/// maps X.500 addresses if they contain an smtp address and converts
+/// internationalized domains to punycode.
+/// X.500 addresses we can't map give UnknownRecipient, anything that's not
+/// an addr-spec after that gives InvalidRecips.
+pub fn normalize(address: &str) -> Result<String, MapiStatusCode> {
+    let mut address = address.trim();
+    let mut is_url = false;
+    loop {
+        let mut stripped = strip_angle_brackets(address).trim();
+        if let Some(rest) = strip_prefix_ignore_case(stripped, SMTP_PREFIX) {
+            stripped = rest.trim();
+        } else if let Some(rest) = strip_prefix_ignore_case(stripped, MAILTO_PREFIX) {
+            stripped = rest.trim();
+            is_url = true;
+        }
+        if stripped == address {
+            break;
+        }
+        address = stripped;
+    }
+
+    let address = if is_url {
+        // a mailto: url can carry headers after the address.
+        let address = address.split('?').next().unwrap_or_default();
+        urlencoding::decode(address)
+            .map(|decoded| decoded.into_owned())
+            .unwrap_or_else(|_| address.to_owned())
+    } else {
+        address.to_owned()
+    };
+
+    let address = if let Some(dn) = strip_prefix_ignore_case(&address, EXCHANGE_PREFIX) {
+        map_x500(dn)?
+    } else if address.starts_with("/o=") || address.starts_with("/O=") {
+        map_x500(&address)?
+    } else {
+        address
+    };
+
+    to_addr_spec(&address).ok_or_else(|| {
+        logger::warn(
+            "normalize",
+            &format!("{:?} is not a valid address", address),
+        );
+        MapiStatusCode::InvalidRecips
+    })
+}
+
+fn strip_angle_brackets(s: &str) -> &str {
+    s.strip_prefix('<')
+        .and_then(|s| s.strip_suffix('>'))
+        .unwrap_or(s)
+}
+
+fn strip_prefix_ignore_case<'a>(s: &'a str, prefix: &str) -> Option<&'a str> {
+    match s.get(..prefix.len()) {
+        Some(start) if start.eq_ignore_ascii_case(prefix) => Some(&s[prefix.len()..]),
+        _ => None,
+    }
+}
+
+/// legacy exchange DNs are only meaningful to the exchange server that issued them.
+/// some migrated address books put the smtp address into the last cn though.
+fn map_x500(dn: &str) -> Result<String, MapiStatusCode> {
+    let last_cn = dn
+        .split('/')
+        .filter_map(|rdn| strip_prefix_ignore_case(rdn.trim(), "cn="))
+        .next_back();
+    match last_cn {
+        Some(cn) if cn.contains('@') => Ok(cn.to_owned()),
+        _ => {
+            logger::warn(
+                "normalize",
+                &format!("can't map X.500 address {:?} to an smtp address", dn),
+            );
+            Err(MapiStatusCode::UnknownRecipient)
+        }
+    }
+}
+
+/// check that address is an addr-spec and return it with the domain in punycode.
+fn to_addr_spec(address: &str) -> Option<String> {
+    let at = address.rfind('@')?;
+    let (local, domain) = (&address[..at], &address[at + 1..]);
+    if !is_valid_local_part(local) {
+        return None;
+    }
+    Some(format!("{}@{}", local, to_ascii_domain(domain)?))
+}
+
+fn is_valid_local_part(local: &str) -> bool {
+    if local.is_empty() || local.len() > MAX_LOCAL_PART_LENGTH {
+        return false;
+    }
+    if let Some(quoted) = local.strip_prefix('"').and_then(|l| l.strip_suffix('"')) {
+        let mut escaped = false;
+        for c in quoted.chars() {
+            match c {
+                '\r' | '\n' => return false,
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => return false,
+                _ => {}
+            }
+        }
+        return !escaped;
+    }
+    // internationalized local parts (RFC 6532) are allowed, the client deals with those.
+    local.split('.').all(|atom| {
+        !atom.is_empty()
+            && atom
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || ATEXT_SPECIALS.contains(c) || !c.is_ascii())
+    })
+}
+
+/// convert a domain to its ascii form, with the non-ascii labels in punycode.
+/// domain literals ("[192.168.0.1]") are returned as they are.
+fn to_ascii_domain(domain: &str) -> Option<String> {
+    if domain.starts_with('[') && domain.ends_with(']') {
+        let literal = &domain[1..domain.len() - 1];
+        let valid = !literal.is_empty()
+            && literal
+                .chars()
+                .all(|c| c.is_ascii_graphic() && !"[]\\".contains(c));
+        return if valid { Some(domain.to_owned()) } else { None };
+    }
+
+    // IDNA treats the ideographic full stops like '.'
+    let domain = domain.replace(['\u{3002}', '\u{ff0e}', '\u{ff61}'], ".");
+    let labels = domain
+        .split('.')
+        .map(|label| {
+            let label = if label.is_ascii() {
+                label.to_owned()
+            } else {
+                format!("xn--{}", punycode_encode(&label.to_lowercase())?)
+            };
+            if is_valid_label(&label) {
+                Some(label)
+            } else {
+                None
+            }
+        })
+        .collect::<Option<Vec<String>>>()?;
+    let domain = labels.join(".");
+    if domain.len() > MAX_DOMAIN_LENGTH {
+        None
+    } else {
+        Some(domain)
+    }
+}
+
+fn is_valid_label(label: &str) -> bool {
+    !label.is_empty()
+        && label.len() <= MAX_LABEL_LENGTH
+        && !label.starts_with('-')
+        && !label.ends_with('-')
+        && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+}
+
+// parameters of the punycode bootstring algorithm, RFC 3492 section 5
+const BASE: u32 = 36;
+const T_MIN: u32 = 1;
+const T_MAX: u32 = 26;
+const SKEW: u32 = 38;
+const DAMP: u32 = 700;
+const INITIAL_BIAS: u32 = 72;
+const INITIAL_N: u32 = 128;
+
+/// RFC 3492 section 6.3. returns None on overflow.
+fn punycode_encode(input: &str) -> Option<String> {
+    let code_points: Vec<u32> = input.chars().map(|c| c as u32).collect();
+    let mut output: String = input.chars().filter(|c| c.is_ascii()).collect();
+    let basic_count = output.len() as u32;
+    if basic_count > 0 {
+        output.push('-');
+    }
+
+    let mut n = INITIAL_N;
+    let mut delta: u32 = 0;
+    let mut bias = INITIAL_BIAS;
+    let mut handled = basic_count;
+    while (handled as usize) < code_points.len() {
+        let m = code_points.iter().copied().filter(|&c| c >= n).min()?;
+        delta = delta.checked_add((m - n).checked_mul(handled + 1)?)?;
+        n = m;
+        for &c in &code_points {
+            if c < n {
+                delta = delta.checked_add(1)?;
+            }
+            if c == n {
+                let mut q = delta;
+                let mut k = BASE;
+                loop {
+                    let t = if k <= bias {
+                        T_MIN
+                    } else if k >= bias + T_MAX {
+                        T_MAX
+                    } else {
+                        k - bias
+                    };
+                    if q < t {
+                        break;
+                    }
+                    output.push(punycode_digit(t + (q - t) % (BASE - t)));
+                    q = (q - t) / (BASE - t);
+                    k += BASE;
+                }
+                output.push(punycode_digit(q));
+                bias = punycode_adapt(delta, handled + 1, handled == basic_count);
+                delta = 0;
+                handled += 1;
+            }
+        }
+        delta = delta.checked_add(1)?;
+        n += 1;
+    }
+    Some(output)
+}
+
+fn punycode_adapt(delta: u32, num_points: u32, first_time: bool) -> u32 {
+    let mut delta = if first_time { delta / DAMP } else { delta / 2 };
+    delta += delta / num_points;
+    let mut k = 0;
+    while delta > ((BASE - T_MIN) * T_MAX) / 2 {
+        delta /= BASE - T_MIN;
+        k += BASE;
+    }
+    k + (BASE - T_MIN + 1) * delta / (delta + SKEW)
+}
+
+fn punycode_digit(d: u32) -> char {
+    match d {
+        0..=25 => (b'a' + d as u8) as char,
+        _ => (b'0' + (d - 26) as u8) as char,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::address::{normalize, punycode_encode};
+    use crate::flags::MapiStatusCode;
+
+    #[test]
+    fn normalize_strips_prefixes() {
+        let ok = |a: &str| normalize(a).unwrap();
+        assert_eq!(ok("a@b.c"), "a@b.c");
+        assert_eq!(ok("SMTP:a@b.c"), "a@b.c");
+        assert_eq!(ok("smtp:a@b.c"), "a@b.c");
+        assert_eq!(ok("mailto:a@b.c"), "a@b.c");
+        assert_eq!(ok("MAILTO:a%2Bb@c.d?subject=hi"), "a+b@c.d");
+        assert_eq!(ok("<a@b.c>"), "a@b.c");
+        assert_eq!(ok(" <SMTP:a@b.c> "), "a@b.c");
+        assert_eq!(ok("SMTP:<a@b.c>"), "a@b.c");
+        assert_eq!(ok("\"smtp:a?b\"@b.c"), "\"smtp:a?b\"@b.c");
+        assert_eq!(ok("a?b%20@c.d"), "a?b%20@c.d");
+    }
+
+    #[test]
+    fn normalize_maps_x500() {
+        assert_eq!(
+            normalize("EX:/o=Org/ou=First Administrative Group/cn=Recipients/cn=j.doe@org.de"),
+            Ok("j.doe@org.de".to_owned())
+        );
+        assert_eq!(
+            normalize("EX:/o=Org/ou=Exchange Administrative Group (FYDIBOHF23SPDLT)/cn=Recipients/cn=jdoe"),
+            Err(MapiStatusCode::UnknownRecipient)
+        );
+        assert_eq!(
+            normalize("/O=ORG/OU=SITE/CN=RECIPIENTS/CN=JDOE"),
+            Err(MapiStatusCode::UnknownRecipient)
+        );
+    }
+
+    #[test]
+    fn normalize_validates() {
+        assert_eq!(normalize("a.b+c@d-e.f"), Ok("a.b+c@d-e.f".to_owned()));
+        assert_eq!(normalize("\"a b\"@c.d"), Ok("\"a b\"@c.d".to_owned()));
+        assert_eq!(normalize("a@[127.0.0.1]"), Ok("a@[127.0.0.1]".to_owned()));
+        for invalid in [
+            "",
+            "a",
+            "@b.c",
+            "a@",
+            "a b@c.d",
+            "a..b@c.d",
+            ".a@c.d",
+            "a@b..c",
+            "a@-b.c",
+            "a@b_c.d",
+            "\"a\"b\"@c.d",
+            "a@b.c\r\nBcc: e@f.g",
+        ] {
+            assert_eq!(
+                normalize(invalid),
+                Err(MapiStatusCode::InvalidRecips),
+                "{}",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn normalize_converts_idn() {
+        assert_eq!(
+            normalize("a@münchen.de"),
+            Ok("a@xn--mnchen-3ya.de".to_owned())
+        );
+        assert_eq!(
+            normalize("a@MÜNCHEN.de"),
+            Ok("a@xn--mnchen-3ya.de".to_owned())
+        );
+        assert_eq!(
+            normalize("a@日本語。jp"),
+            Ok("a@xn--wgv71a119e.jp".to_owned())
+        );
+        assert_eq!(normalize("jörg@b.c"), Ok("jörg@b.c".to_owned()));
+    }
+
+    #[test]
+    fn punycode_encode_works() {
+        assert_eq!(punycode_encode("bücher"), Some("bcher-kva".to_owned()));
+        assert_eq!(punycode_encode("ü"), Some("tda".to_owned()));
+        assert_eq!(punycode_encode("日本語"), Some("wgv71a119e".to_owned()));
+        assert_eq!(punycode_encode("abc"), Some("abc-".to_owned()));
+    }
+}
@@ -0,0 +1,199 @@
+use std::any::Any;
+use std::ffi::CString;
+use std::sync::Mutex;
+
+use crate::types::LpStr;
+
+static ALLOCATIONS: Mutex<Vec<Allocation>> = Mutex::new(Vec::new());
+
+/// a heap buffer that is owned by an allocation.
+/// we never look at it again, it's only there to be dropped.
+struct Buffer {
+    _owned: Box<dyn Any>,
+}
+
+// SAFETY: the buffers contain raw pointers, which makes them !Send. those pointers only
+// point into other buffers of the same allocation. we never read or write through them
+// on our side after handing the root out, the only thing we do is drop the whole
+// allocation, which doesn't care what thread it happens on.
+unsafe impl Send for Buffer {}
+
+/// memory we handed out to the calling app. the address of the root is what the
+/// calling app gets and later passes to MAPIFreeBuffer, the chain contains everything
+/// that the root points into (strings, arrays, ...), like the buffers allocated
+/// with MAPIAllocateMore.
+struct Allocation {
+    root_address: usize,
+    _root: Buffer,
+    _chain: Vec<Buffer>,
+}
+
+/// collects the buffers a value that's handed to the calling app points into,
+/// so they can be freed together with it.
+///
+/// the pointers returned from the add_* methods stay valid until the allocation
+/// this chain becomes part of is freed, because they point to heap memory that
+/// doesn't move when the chain is moved.
+#[derive(Default)]
+pub struct BufferChain {
+    buffers: Vec<Buffer>,
+}
+
+impl BufferChain {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_string(&mut self, s: CString) -> LpStr {
+        let ptr = s.as_ptr();
+        self.buffers.push(Buffer {
+            _owned: Box::new(s),
+        });
+        ptr
+    }
+
+    /// returns null for empty vecs, so it can be used for the (count, pointer) pairs
+    /// in the MAPI structs.
+    pub fn add_vec<T: 'static>(&mut self, v: Vec<T>) -> *const T {
+        if v.is_empty() {
+            return std::ptr::null();
+        }
+        let ptr = v.as_ptr();
+        self.buffers.push(Buffer {
+            _owned: Box::new(v),
+        });
+        ptr
+    }
+}
+
+/// move a value to the heap and keep track of it (and everything in the chain)
+/// until it is freed with free(). returns the pointer that's handed to the calling app.
+pub fn allocate<T: 'static>(root: T, chain: BufferChain) -> *mut T {
+    let mut boxed = Box::new(root);
+    let ptr: *mut T = &mut *boxed;
+    let allocation = Allocation {
+        root_address: ptr as usize,
+        _root: Buffer { _owned: boxed },
+        _chain: chain.buffers,
+    };
+    match ALLOCATIONS.lock() {
+        Ok(mut allocations) => allocations.push(allocation),
+        // if the lock is poisoned, we can't keep track of the allocation. leaking it is the
+        // best we can do since the calling app will use the pointer.
+        Err(_) => std::mem::forget(allocation),
+    }
+    ptr
+}
+
+/// like allocate, but for an array of values like the lpMapiRecipDesc of MAPIAddress.
+/// the pointer that's handed to the calling app points to the first element.
+///
+/// returns null for empty vecs, nothing is allocated then.
+pub fn allocate_array<T: 'static>(items: Vec<T>, chain: BufferChain) -> *mut T {
+    if items.is_empty() {
+        return std::ptr::null_mut();
+    }
+    let mut boxed: Box<[T]> = items.into_boxed_slice();
+    let ptr: *mut T = boxed.as_mut_ptr();
+    let allocation = Allocation {
+        root_address: ptr as usize,
+        _root: Buffer {
+            _owned: Box::new(boxed),
+        },
+        _chain: chain.buffers,
+    };
+    match ALLOCATIONS.lock() {
+        Ok(mut allocations) => allocations.push(allocation),
+        // see allocate
+        Err(_) => std::mem::forget(allocation),
+    }
+    ptr
+}
+
+/// free an allocation and all the buffers chained to it.
+///
+/// returns Err if the pointer is not the root of an allocation we handed out,
+/// in which case nothing is freed.
+pub fn free(ptr: *const libc::c_void) -> Result<(), ()> {
+    let address = ptr as usize;
+    let allocation = {
+        let mut allocations = ALLOCATIONS.lock().map_err(|_| ())?;
+        let idx = allocations
+            .iter()
+            .position(|a| a.root_address == address)
+            .ok_or(())?;
+        allocations.swap_remove(idx)
+    };
+    // dropped outside of the lock
+    drop(allocation);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::{CStr, CString};
+    use std::sync::Arc;
+
+    use crate::allocation::{allocate, allocate_array, free, BufferChain};
+    use crate::types::LpStr;
+
+    struct Root {
+        name: LpStr,
+        items: *const u32,
+    }
+
+    #[test]
+    fn allocate_and_free_work() {
+        let marker = Arc::new(());
+        let mut chain = BufferChain::new();
+        let name = chain.add_string(CString::new("hello").unwrap());
+        let items = chain.add_vec(vec![1u32, 2, 3]);
+        chain.add_vec(vec![marker.clone()]);
+        assert_eq!(chain.add_vec(Vec::<u32>::new()), std::ptr::null());
+
+        let root = allocate(Root { name, items }, chain);
+        let root_ref = unsafe { &*root };
+        assert_eq!(
+            unsafe { CStr::from_ptr(root_ref.name) }.to_str(),
+            Ok("hello")
+        );
+        assert_eq!(unsafe { *root_ref.items.add(2) }, 3);
+        assert_eq!(Arc::strong_count(&marker), 2);
+
+        // sub-allocations can't be freed on their own
+        assert_eq!(free(items as *const libc::c_void), Err(()));
+        assert_eq!(Arc::strong_count(&marker), 2);
+
+        assert_eq!(free(root as *const libc::c_void), Ok(()));
+        // everything in the chain is gone with the root
+        assert_eq!(Arc::strong_count(&marker), 1);
+        // double free
+        assert_eq!(free(root as *const libc::c_void), Err(()));
+    }
+
+    #[test]
+    fn allocate_array_works() {
+        let marker = Arc::new(());
+        let mut chain = BufferChain::new();
+        chain.add_vec(vec![marker.clone()]);
+        let items = allocate_array(vec![7u32, 8, 9], chain);
+        assert_eq!(unsafe { *items.add(1) }, 8);
+        assert_eq!(Arc::strong_count(&marker), 2);
+        assert_eq!(free(items as *const libc::c_void), Ok(()));
+        assert_eq!(Arc::strong_count(&marker), 1);
+
+        let empty = allocate_array(Vec::<u32>::new(), BufferChain::new());
+        assert!(empty.is_null());
+    }
+
+    #[test]
+    fn foreign_pointers_are_rejected() {
+        let on_stack: u32 = 5;
+        assert_eq!(
+            free(&on_stack as *const u32 as *const libc::c_void),
+            Err(())
+        );
+        assert_eq!(free(std::ptr::null()), Err(()));
+        assert_eq!(on_stack, 5);
+    }
+}
@@ -1,33 +1,37 @@
-use std::io::Write;
-// NOTE: enables creation_flags on the command builder, only works on windows
-use std::os::windows::process::CommandExt;
-use std::process::Command;
+use std::sync::Once;
 
-use crate::environment::{client_path, current_time_formatted, log_file};
-use crate::structs::Message;
-
-const CREATE_NO_WINDOW: u32 = 0x08000000;
-const DETACHED_PROCESS: u32 = 0x00000008;
+use serde::{Deserialize, Serialize};
 
-pub fn send_mail(msg: Message) -> std::io::Result<()> {
-    let exe = client_path()?;
+use crate::flags::MapiStatusCode;
+use crate::logger;
+use crate::retention;
+use crate::settings;
+use crate::structs::Message;
+use crate::transport;
 
-    Command::new(&exe)
-        .args(&[msg.make_mailto_link()])
-        .creation_flags(DETACHED_PROCESS | CREATE_NO_WINDOW)
-        .spawn()?;
-    log_to_file("send_mail", "spawned tutanota client");
-    Ok(())
+/// what the client should do with a message we hand to it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SendMode {
+    /// open the composer with the message so the user can edit and send it
+    Compose,
+    /// send the message without showing anything (MAPISendMail without MAPI_DIALOG)
+    Unattended,
 }
 
-pub fn log_to_file(caller: &str, stuff: &str) {
-    let written = if let Ok(mut lf) = log_file() {
-        writeln!(lf, "{} | {}: {}", current_time_formatted(), caller, stuff)
-    } else {
-        eprintln!("Couldn't open file");
-        Ok(())
-    };
-    if written.is_err() {
-        eprintln!("Couldn't write to file");
-    }
+/// hand a message to the client with the configured transport. TMPPath is
+/// cleaned up afterwards, once per process, so the message isn't held up by it.
+pub fn send_mail(msg: Message, mode: SendMode) -> Result<(), MapiStatusCode> {
+    static CLEANED: Once = Once::new();
+    let transport = transport::configured();
+    logger::debug("send_mail", &format!("delivering with {:?}", transport));
+    let delivered = transport.deliver(&msg, mode);
+    CLEANED.call_once(
+        || match retention::clean_tmp(settings::get().tmp_retention) {
+            Ok(0) => {}
+            Ok(removed) => logger::info("send_mail", &format!("removed {} old files", removed)),
+            Err(e) => logger::error("send_mail", &format!("could not clean up: {:?}", e)),
+        },
+    );
+    delivered
 }
@@ -0,0 +1,339 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use crate::environment;
+use crate::flags::MapiStatusCode;
+use crate::logger;
+
+/// score for a query that is exactly a contact's name or address
+const SCORE_EXACT: u32 = 100;
+/// score for a query that's the start of a contact's name, a word in it or the address
+const SCORE_PREFIX: u32 = 70;
+/// score for a query that's somewhere inside a contact's name or address
+const SCORE_CONTAINS: u32 = 50;
+/// score for a query that's only a couple of typos away from a contact's name
+const SCORE_TYPO: u32 = 30;
+
+/// a single name/address pair from the contact file the client exports.
+/// contacts with multiple addresses show up multiple times.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct Contact {
+    pub name: String,
+    pub address: String,
+}
+
+/// read the contact file configured in the registry. JSON files (.json)
+/// are expected to contain an array of {"name": ..., "address": ...} objects,
+/// anything else is read as vCard.
+pub fn load_contacts() -> io::Result<Vec<Contact>> {
+    let path: PathBuf = environment::contacts_path()?.into();
+    let contents = fs::read_to_string(&path)?;
+    let contacts = if is_json(&path) {
+        parse_json(&contents)?
+    } else {
+        parse_vcard(&contents)
+    };
+    logger::debug(
+        "load_contacts",
+        &format!("read {} contacts from {:?}", contacts.len(), path),
+    );
+    Ok(contacts)
+}
+
+fn is_json(path: &Path) -> bool {
+    path.extension()
+        .map(|ext| ext.eq_ignore_ascii_case("json"))
+        .unwrap_or(false)
+}
+
+pub fn parse_json(contents: &str) -> io::Result<Vec<Contact>> {
+    serde_json::from_str(contents).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// extract the FN and EMAIL properties from a vCard (2.1, 3.0 and 4.0) file.
+/// contacts without FN use their address as name, contacts without EMAIL are skipped.
+pub fn parse_vcard(contents: &str) -> Vec<Contact> {
+    let mut contacts = vec![];
+    let mut name: Option<String> = None;
+    let mut addresses: Vec<String> = vec![];
+
+    for line in unfold_lines(contents) {
+        let (key, value) = match line.split_once(':') {
+            Some(kv) => kv,
+            None => continue,
+        };
+        // property names may be grouped (item1.EMAIL) and have parameters (EMAIL;TYPE=work)
+        let prop = key.split(';').next().unwrap_or("");
+        let prop = prop.rsplit('.').next().unwrap_or("").to_ascii_uppercase();
+        match prop.as_str() {
+            "BEGIN" => {
+                name = None;
+                addresses.clear();
+            }
+            "FN" => name = Some(unescape_vcard_value(value)),
+            "EMAIL" => addresses.push(value.trim().to_owned()),
+            "END" => {
+                for address in addresses.drain(..).filter(|a| !a.is_empty()) {
+                    contacts.push(Contact {
+                        name: name.clone().unwrap_or_else(|| address.clone()),
+                        address,
+                    });
+                }
+            }
+            _ => {}
+        }
+    }
+    contacts
+}
+
+/// vCard lines may be folded by inserting a line break followed by a space or tab.
+fn unfold_lines(contents: &str) -> Vec<String> {
+    let mut lines: Vec<String> = vec![];
+    for line in contents.lines() {
+        match (
+            line.strip_prefix(' ').or_else(|| line.strip_prefix('\t')),
+            lines.last_mut(),
+        ) {
+            (Some(continuation), Some(last)) => last.push_str(continuation),
+            _ => lines.push(line.to_owned()),
+        }
+    }
+    lines
+}
+
+fn unescape_vcard_value(value: &str) -> String {
+    value
+        .trim()
+        .replace("\\,", ",")
+        .replace("\\;", ";")
+        .replace("\\n", " ")
+        .replace("\\\\", "\\")
+}
+
+/// find the contact a (possibly partial) name or address refers to.
+///
+/// returns AmbiguousRecipient if the best match is not unique and
+/// UnknownRecipient if nothing matches. queries that look like a mail address
+/// but are not in the contacts resolve to themselves.
+pub fn resolve(contacts: &[Contact], query: &str) -> Result<Contact, MapiStatusCode> {
+    let query = query.trim();
+    if query.is_empty() {
+        return Err(MapiStatusCode::UnknownRecipient);
+    }
+
+    let best_score = contacts.iter().map(|c| score(c, query)).max().unwrap_or(0);
+    if best_score == 0 {
+        return if query.contains('@') {
+            Ok(Contact {
+                name: query.to_owned(),
+                address: query.to_owned(),
+            })
+        } else {
+            Err(MapiStatusCode::UnknownRecipient)
+        };
+    }
+
+    let mut best: Vec<&Contact> = vec![];
+    for c in contacts.iter().filter(|c| score(c, query) == best_score) {
+        // the same address may be in the contacts more than once
+        if !best
+            .iter()
+            .any(|b| b.address.eq_ignore_ascii_case(&c.address))
+        {
+            best.push(c);
+        }
+    }
+    if best.len() == 1 {
+        Ok(best[0].clone())
+    } else {
+        logger::info(
+            "resolve",
+            &format!("{} contacts match with score {}", best.len(), best_score),
+        );
+        Err(MapiStatusCode::AmbiguousRecipient)
+    }
+}
+
+/// fill in what's missing from a recipient the calling app gave us: recipients without
+/// an address are resolved by name, the ones with an address get the name from the
+/// contacts if the address is in there.
+pub fn complete(
+    contacts: &[Contact],
+    name: &str,
+    address: Option<&str>,
+) -> Result<Contact, MapiStatusCode> {
+    match address.filter(|a| !a.is_empty()) {
+        None => resolve(contacts, name),
+        Some(address) => Ok(find_by_address(contacts, address)
+            .cloned()
+            .unwrap_or_else(|| Contact {
+                name: if name.is_empty() { address } else { name }.to_owned(),
+                address: address.to_owned(),
+            })),
+    }
+}
+
+/// the first contact with exactly this address, ignoring case
+pub fn find_by_address<'a>(contacts: &'a [Contact], address: &str) -> Option<&'a Contact> {
+    contacts
+        .iter()
+        .find(|c| c.address.eq_ignore_ascii_case(address))
+}
+
+/// how well a query matches a contact. 0 means it doesn't match at all.
+fn score(contact: &Contact, query: &str) -> u32 {
+    let query = query.to_lowercase();
+    let name = contact.name.to_lowercase();
+    let address = contact.address.to_lowercase();
+
+    if name == query || address == query {
+        SCORE_EXACT
+    } else if name.starts_with(&query)
+        || name.split_whitespace().any(|word| word.starts_with(&query))
+        || address.starts_with(&query)
+    {
+        SCORE_PREFIX
+    } else if name.contains(&query) || address.contains(&query) {
+        SCORE_CONTAINS
+    } else if query.chars().count() >= 4 && edit_distance(&name, &query) <= 2 {
+        SCORE_TYPO
+    } else {
+        0
+    }
+}
+
+/// levenshtein distance between two strings, counted in chars
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    let mut cur: Vec<usize> = vec![0; b.len() + 1];
+    for (i, ca) in a.chars().enumerate() {
+        cur[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = prev[j] + if ca == *cb { 0 } else { 1 };
+            cur[j + 1] = substitution.min(prev[j + 1] + 1).min(cur[j] + 1);
+        }
+        std::mem::swap(&mut prev, &mut cur);
+    }
+    prev[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::contacts::{complete, edit_distance, parse_json, parse_vcard, resolve, Contact};
+    use crate::flags::MapiStatusCode;
+
+    fn contact(name: &str, address: &str) -> Contact {
+        Contact {
+            name: name.to_owned(),
+            address: address.to_owned(),
+        }
+    }
+
+    #[test]
+    fn parse_vcard_works() {
+        let vcf = "BEGIN:VCARD\r\n\
+                   VERSION:3.0\r\n\
+                   FN:Jane Doe\r\n\
+                   EMAIL;TYPE=work:jane@example.com\r\n\
+                   item1.EMAIL:jane.doe@pri\r\n vate.de\r\n\
+                   END:VCARD\r\n\
+                   BEGIN:VCARD\r\n\
+                   FN:Nobody\\, Esq.\r\n\
+                   END:VCARD\r\n\
+                   BEGIN:VCARD\r\n\
+                   email:john@example.com\r\n\
+                   END:VCARD\r\n";
+        assert_eq!(
+            parse_vcard(vcf),
+            vec![
+                contact("Jane Doe", "jane@example.com"),
+                contact("Jane Doe", "jane.doe@private.de"),
+                contact("john@example.com", "john@example.com"),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_json_works() {
+        let json = r#"[{"name": "Jane Doe", "address": "jane@example.com", "phone": "123"}]"#;
+        assert_eq!(
+            parse_json(json).unwrap(),
+            vec![contact("Jane Doe", "jane@example.com")]
+        );
+        assert!(parse_json("{").is_err());
+    }
+
+    #[test]
+    fn resolve_works() {
+        let contacts = vec![
+            contact("Jane Doe", "jane@example.com"),
+            contact("John Doe", "john@example.com"),
+            contact("Jürgen Müller", "jm@example.de"),
+        ];
+
+        assert_eq!(resolve(&contacts, "jane doe"), Ok(contacts[0].clone()));
+        assert_eq!(resolve(&contacts, "Jan"), Ok(contacts[0].clone()));
+        assert_eq!(resolve(&contacts, "müller"), Ok(contacts[2].clone()));
+        assert_eq!(resolve(&contacts, "JM@EXAMPLE.DE"), Ok(contacts[2].clone()));
+        assert_eq!(resolve(&contacts, "Jane Doo"), Ok(contacts[0].clone()));
+        assert_eq!(
+            resolve(&contacts, "Doe"),
+            Err(MapiStatusCode::AmbiguousRecipient)
+        );
+        assert_eq!(
+            resolve(&contacts, "Erika"),
+            Err(MapiStatusCode::UnknownRecipient)
+        );
+        assert_eq!(
+            resolve(&contacts, " "),
+            Err(MapiStatusCode::UnknownRecipient)
+        );
+        assert_eq!(
+            resolve(&contacts, "erika@example.org"),
+            Ok(contact("erika@example.org", "erika@example.org"))
+        );
+    }
+
+    #[test]
+    fn complete_works() {
+        let contacts = vec![
+            contact("Jane Doe", "jane@example.com"),
+            contact("John Doe", "john@example.com"),
+        ];
+
+        assert_eq!(complete(&contacts, "Jane", None), Ok(contacts[0].clone()));
+        assert_eq!(
+            complete(&contacts, "Jane", Some("")),
+            Ok(contacts[0].clone())
+        );
+        assert_eq!(
+            complete(&contacts, "", Some("JOHN@example.com")),
+            Ok(contacts[1].clone())
+        );
+        assert_eq!(
+            complete(&contacts, "Erika", Some("erika@example.org")),
+            Ok(contact("Erika", "erika@example.org"))
+        );
+        assert_eq!(
+            complete(&contacts, "", Some("erika@example.org")),
+            Ok(contact("erika@example.org", "erika@example.org"))
+        );
+        assert_eq!(
+            complete(&contacts, "Doe", None),
+            Err(MapiStatusCode::AmbiguousRecipient)
+        );
+    }
+
+    #[test]
+    fn edit_distance_works() {
+        assert_eq!(edit_distance("", ""), 0);
+        assert_eq!(edit_distance("jane", ""), 4);
+        assert_eq!(edit_distance("jane doe", "jane doo"), 1);
+        assert_eq!(edit_distance("müller", "muller"), 1);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+    }
+}
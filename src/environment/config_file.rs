@@ -0,0 +1,145 @@
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use directories::BaseDirs;
+
+use crate::environment::Provider;
+
+/// the path of the config file, if it's not in the default location
+const CONFIG_PATH_VAR: &str = "MAPIRS_CONFIG";
+/// values can be set with environment variables named like this prefix and the
+/// name of the value in upper case, like MAPIRS_EXEPATH. they win over the config file.
+const VAR_PREFIX: &str = "MAPIRS_";
+/// the default config file, in the tutanota directory of the user's config dir
+const CONFIG_FILE_NAME: &str = "mapirs.json";
+
+/// the ANSI code page if ACP isn't configured. that's what most western windows systems use.
+const DEFAULT_ANSI_CODE_PAGE: u32 = 1252;
+
+/// looks up an environment variable
+type VarLookup = Box<dyn Fn(&str) -> Option<String> + Send + Sync>;
+
+/// reads the values from a JSON object with the same names as the registry
+/// values, like {"EXEPath": "/usr/bin/tutanota-desktop", "TMPPath": "/tmp/tutanota"}
+pub struct ConfigProvider {
+    values: HashMap<String, String>,
+    var: VarLookup,
+    problems: Vec<String>,
+}
+
+impl ConfigProvider {
+    /// read the config file. a missing or broken config file is treated like an empty one.
+    pub fn load() -> Self {
+        let mut problems = vec![];
+        let values = match config_path() {
+            Some(path) => read_values(&path).unwrap_or_else(|e| {
+                // we can't log yet, finding the log depends on the config.
+                if e.kind() != io::ErrorKind::NotFound {
+                    problems.push(format!("could not read config file {:?}: {:?}", path, e));
+                }
+                HashMap::new()
+            }),
+            None => HashMap::new(),
+        };
+        ConfigProvider {
+            values,
+            var: Box::new(|name| std::env::var(name).ok()),
+            problems,
+        }
+    }
+}
+
+fn config_path() -> Option<PathBuf> {
+    if let Some(path) = std::env::var_os(CONFIG_PATH_VAR) {
+        return Some(path.into());
+    }
+    BaseDirs::new().map(|dirs| dirs.config_dir().join("tutanota").join(CONFIG_FILE_NAME))
+}
+
+fn read_values(path: &Path) -> io::Result<HashMap<String, String>> {
+    let contents = fs::read(path)?;
+    Ok(serde_json::from_slice(&contents)?)
+}
+
+impl Provider for ConfigProvider {
+    fn value(&self, name: &str) -> io::Result<String> {
+        let var = format!("{}{}", VAR_PREFIX, name.to_uppercase());
+        (self.var)(&var)
+            .or_else(|| self.values.get(name).cloned())
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))
+    }
+
+    fn ansi_code_page(&self) -> io::Result<u32> {
+        match self.value("ACP") {
+            Ok(acp) => acp
+                .trim()
+                .parse()
+                .map_err(|_e| io::Error::from(io::ErrorKind::InvalidData)),
+            Err(_) => Ok(DEFAULT_ANSI_CODE_PAGE),
+        }
+    }
+
+    fn spawn(&self, exe: &OsStr, args: &[String]) -> io::Result<()> {
+        Command::new(exe).args(args).spawn()?;
+        Ok(())
+    }
+
+    fn problems(&self) -> Vec<String> {
+        self.problems.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::fs;
+    use std::sync::{Arc, Mutex};
+
+    use crate::environment::config_file::{read_values, ConfigProvider};
+    use crate::environment::Provider;
+
+    #[test]
+    fn config_provider_works() {
+        let dir = std::env::temp_dir()
+            .join("mapirs_test")
+            .join("config_provider_works");
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("mapirs.json");
+        fs::write(
+            &path,
+            r#"{"EXEPath": "/usr/bin/tutanota-desktop", "TMPPath": "/tmp/tutanota", "ACP": "1250"}"#,
+        )
+        .unwrap();
+
+        let vars = Arc::new(Mutex::new(HashMap::new()));
+        let lookup = vars.clone();
+        let provider = ConfigProvider {
+            values: read_values(&path).unwrap(),
+            var: Box::new(move |name| lookup.lock().unwrap().get(name).cloned()),
+            problems: vec![],
+        };
+        assert_eq!(
+            provider.value("EXEPath").unwrap(),
+            "/usr/bin/tutanota-desktop"
+        );
+        assert_eq!(
+            provider.value("LOGPath").unwrap_err().kind(),
+            std::io::ErrorKind::NotFound
+        );
+        assert_eq!(provider.ansi_code_page().unwrap(), 1250);
+
+        vars.lock()
+            .unwrap()
+            .insert("MAPIRS_TMPPATH", "/var/tmp/tutanota".to_owned());
+        assert_eq!(provider.value("TMPPath").unwrap(), "/var/tmp/tutanota");
+        vars.lock().unwrap().clear();
+        assert_eq!(provider.value("TMPPath").unwrap(), "/tmp/tutanota");
+
+        fs::write(&path, "[1, 2]").unwrap();
+        assert!(read_values(&path).is_err());
+    }
+}
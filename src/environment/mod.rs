@@ -0,0 +1,389 @@
+use std::ffi::{OsStr, OsString};
+use std::fmt::Write;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock, PoisonError};
+
+use sha2::digest::OutputSizeUser;
+use sha2::{Digest, Sha256};
+use time::{macros::format_description, OffsetDateTime};
+
+#[cfg(not(windows))]
+mod config_file;
+#[cfg(windows)]
+mod registry;
+
+/// where the configuration (EXEPath, TMPPath, ...) comes from and how the
+/// client is started. that's the registry on windows and a config file or
+/// environment variables everywhere else.
+pub trait Provider {
+    /// the value with this name (the name of the registry value), NotFound if it's not set
+    fn value(&self, name: &str) -> io::Result<String>;
+    /// the system's ANSI code page (the one GetACP() would return)
+    fn ansi_code_page(&self) -> io::Result<u32>;
+    /// start a program without waiting for it to exit
+    fn spawn(&self, exe: &OsStr, args: &[String]) -> io::Result<()>;
+    /// what went wrong while setting up the provider. it can't log that itself,
+    /// the log's location comes from the provider.
+    fn problems(&self) -> Vec<String> {
+        vec![]
+    }
+}
+
+/// remembers what another provider returned, so every value is only looked up once
+/// per process. the configuration doesn't change while an app is sending mail.
+struct CachingProvider<P: Provider> {
+    inner: P,
+    values: Mutex<std::collections::HashMap<String, Result<String, io::ErrorKind>>>,
+    ansi_code_page: OnceLock<Result<u32, io::ErrorKind>>,
+}
+
+impl<P: Provider> CachingProvider<P> {
+    fn new(inner: P) -> Self {
+        CachingProvider {
+            inner,
+            values: Mutex::new(Default::default()),
+            ansi_code_page: OnceLock::new(),
+        }
+    }
+}
+
+impl<P: Provider> Provider for CachingProvider<P> {
+    fn value(&self, name: &str) -> io::Result<String> {
+        let mut values = self.values.lock().unwrap_or_else(PoisonError::into_inner);
+        values
+            .entry(name.to_owned())
+            .or_insert_with(|| self.inner.value(name).map_err(|e| e.kind()))
+            .clone()
+            .map_err(io::Error::from)
+    }
+
+    fn ansi_code_page(&self) -> io::Result<u32> {
+        let acp = self
+            .ansi_code_page
+            .get_or_init(|| self.inner.ansi_code_page().map_err(|e| e.kind()));
+        acp.map_err(io::Error::from)
+    }
+
+    fn spawn(&self, exe: &OsStr, args: &[String]) -> io::Result<()> {
+        self.inner.spawn(exe, args)
+    }
+
+    fn problems(&self) -> Vec<String> {
+        self.inner.problems()
+    }
+}
+
+#[cfg(windows)]
+fn platform_provider() -> Box<dyn Provider + Send + Sync> {
+    Box::new(CachingProvider::new(registry::RegistryProvider))
+}
+
+#[cfg(not(windows))]
+fn platform_provider() -> Box<dyn Provider + Send + Sync> {
+    Box::new(CachingProvider::new(config_file::ConfigProvider::load()))
+}
+
+#[cfg(not(test))]
+fn with_provider<T>(f: impl FnOnce(&dyn Provider) -> T) -> T {
+    static PROVIDER: OnceLock<Box<dyn Provider + Send + Sync>> = OnceLock::new();
+    f(PROVIDER.get_or_init(platform_provider).as_ref())
+}
+
+/// tests get their own provider per thread, so they can change values without
+/// affecting each other and don't depend on how the machine is set up.
+#[cfg(test)]
+fn with_provider<T>(f: impl FnOnce(&dyn Provider) -> T) -> T {
+    TEST_PROVIDER.with(|provider| f(provider))
+}
+
+#[cfg(test)]
+thread_local! {
+    static TEST_PROVIDER: TestProvider = TestProvider::default();
+}
+
+/// keeps its values in memory and records what it's asked to spawn instead of starting it.
+/// everything is in a mapirs_test directory in the system's temp dir.
+#[cfg(test)]
+struct TestProvider {
+    values: std::cell::RefCell<std::collections::HashMap<String, String>>,
+    spawned: std::cell::RefCell<Vec<(OsString, Vec<String>)>>,
+}
+
+#[cfg(test)]
+impl Default for TestProvider {
+    fn default() -> Self {
+        let dir = std::env::temp_dir().join("mapirs_test");
+        let values = [
+            ("LOGPath", dir.join("log")),
+            ("TMPPath", dir.join("tmp")),
+            ("HANDOFF", "recording".into()),
+        ]
+        .into_iter()
+        .map(|(name, value)| (name.to_owned(), value.to_string_lossy().into_owned()))
+        .collect();
+        TestProvider {
+            values: std::cell::RefCell::new(values),
+            spawned: std::cell::RefCell::new(vec![]),
+        }
+    }
+}
+
+#[cfg(test)]
+impl Provider for TestProvider {
+    fn value(&self, name: &str) -> io::Result<String> {
+        self.values
+            .borrow()
+            .get(name)
+            .cloned()
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))
+    }
+
+    fn ansi_code_page(&self) -> io::Result<u32> {
+        Ok(1252)
+    }
+
+    fn spawn(&self, exe: &OsStr, args: &[String]) -> io::Result<()> {
+        self.spawned
+            .borrow_mut()
+            .push((exe.to_owned(), args.to_vec()));
+        Ok(())
+    }
+}
+
+/// set or remove a value for the tests running on this thread
+#[cfg(test)]
+pub fn set_test_value(name: &str, value: Option<&str>) {
+    TEST_PROVIDER.with(|provider| match value {
+        Some(value) => provider
+            .values
+            .borrow_mut()
+            .insert(name.to_owned(), value.to_owned()),
+        None => provider.values.borrow_mut().remove(name),
+    });
+}
+
+/// the programs the tests on this thread tried to start since the last call
+#[cfg(test)]
+pub fn take_test_spawned() -> Vec<(OsString, Vec<String>)> {
+    TEST_PROVIDER.with(|provider| provider.spawned.take())
+}
+
+fn value(name: &str) -> io::Result<String> {
+    with_provider(|provider| provider.value(name))
+}
+
+/// get an OsString containing the absolute path to
+/// the tutanota desktop executable that registered the dll
+/// as the MAPI handler.
+pub fn client_path() -> io::Result<OsString> {
+    // if this fails, the client is not installed correctly.
+    value("EXEPath").map(OsString::from)
+}
+
+/// the directory the log is written to
+pub fn log_path() -> io::Result<OsString> {
+    replace_profile(value("LOGPath")?)
+}
+
+/// retrieve the directory drafts saved with MAPISaveMail are written to.
+/// this is DRAFTSPath if it's set and a drafts
+/// directory inside of TMPPath otherwise.
+pub fn drafts_path() -> io::Result<OsString> {
+    if let Ok(drafts_dir) = value("DRAFTSPath") {
+        return replace_profile(drafts_dir);
+    }
+    let mut drafts_dir = PathBuf::from(tmp_path()?);
+    drafts_dir.push("drafts");
+    Ok(drafts_dir.into_os_string())
+}
+
+/// retrieve the directory the client exports received messages to
+/// (.eml files, optionally split into new and cur like a maildir).
+pub fn inbox_path() -> io::Result<OsString> {
+    replace_profile(value("INBOXPath")?)
+}
+
+/// what went wrong while reading the configuration, see Provider::problems
+pub fn problems() -> Vec<String> {
+    with_provider(|provider| provider.problems())
+}
+
+/// retrieve one of the values the settings are made of, see settings
+pub fn setting(name: &str) -> io::Result<String> {
+    value(name)
+}
+
+/// retrieve the path to the settings file, which overrides the other settings
+pub fn settings_path() -> io::Result<OsString> {
+    replace_profile(value("SETTINGSPath")?)
+}
+
+/// retrieve the path to the contact file exported by the client
+/// (a vCard or JSON file).
+pub fn contacts_path() -> io::Result<OsString> {
+    replace_profile(value("CONTACTSPath")?)
+}
+
+/// the system's ANSI code page (the one GetACP() would return)
+pub fn ansi_code_page() -> io::Result<u32> {
+    with_provider(|provider| provider.ansi_code_page())
+}
+
+/// start the program at exe with args without waiting for it
+pub fn spawn(exe: &OsStr, args: &[String]) -> io::Result<()> {
+    with_provider(|provider| provider.spawn(exe, args))
+}
+
+/// replace the %USERPROFILE% placeholder in a String with
+/// the value of the USERPROFILE env variable
+fn replace_profile(val: String) -> io::Result<OsString> {
+    if !val.contains("%USERPROFILE%") {
+        return Ok(OsString::from(val));
+    }
+    let profile =
+        std::env::var("USERPROFILE").map_err(|_e| io::Error::from(io::ErrorKind::NotFound))?;
+    Ok(OsString::from(
+        val.replace("%USERPROFILE%", profile.as_str()),
+    ))
+}
+
+/// retrieve the configured tmp dir and
+/// try to ensure the directory is there.
+pub fn tmp_path() -> io::Result<OsString> {
+    let tmp_dir = replace_profile(value("TMPPath")?)?;
+    fs::create_dir_all(&tmp_dir)?;
+    Ok(tmp_dir)
+}
+
+/// we may get the same filename multiple times
+/// we put each file into its own subfolder that's named
+/// after the first 4 characters of the hex-encoded SHA256 hash
+/// of the file contents
+pub fn make_subfolder_name_from_content<P: AsRef<Path>>(filepath: P) -> io::Result<String> {
+    let mut file = File::open(filepath)?;
+    let mut sha256 = Sha256::new();
+    io::copy(&mut file, &mut sha256)?;
+    Ok(sha_head(sha256.finalize()))
+}
+
+pub fn sha_head(
+    sha256: sha2::digest::generic_array::GenericArray<u8, <Sha256 as OutputSizeUser>::OutputSize>,
+) -> String {
+    let mut buf = String::with_capacity(4);
+    for byte in &sha256[..2] {
+        if write!(buf, "{:>2x}", byte).is_err() {
+            return "nope".to_owned();
+        };
+    }
+    buf
+}
+
+/// get the current system time as a formatted string
+pub fn current_time_formatted() -> String {
+    let now = OffsetDateTime::now_utc();
+    let desc =
+        format_description!("[year]-[month]-[day] | [hour]:[minute]:[second].[subsecond digits:3]");
+    now.format(desc)
+        .unwrap_or_else(|_| "0000-00-00 | 00:00:00.000".to_owned())
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::Cell;
+    use std::ffi::OsStr;
+    use std::io;
+
+    use crate::environment::{
+        drafts_path, inbox_path, log_path, platform_provider, replace_profile, set_test_value,
+        CachingProvider, Provider,
+    };
+
+    #[test]
+    fn platform_provider_works() {
+        assert!(platform_provider().ansi_code_page().is_ok());
+    }
+
+    /// counts how often it's asked for something
+    #[derive(Default)]
+    struct CountingProvider {
+        lookups: Cell<usize>,
+    }
+
+    impl Provider for CountingProvider {
+        fn value(&self, name: &str) -> io::Result<String> {
+            self.lookups.set(self.lookups.get() + 1);
+            match name {
+                "TMPPath" => Ok("C:\\tmp".to_owned()),
+                _ => Err(io::ErrorKind::NotFound.into()),
+            }
+        }
+
+        fn ansi_code_page(&self) -> io::Result<u32> {
+            self.lookups.set(self.lookups.get() + 1);
+            Ok(1252)
+        }
+
+        fn spawn(&self, _exe: &OsStr, _args: &[String]) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn caching_provider_works() {
+        let provider = CachingProvider::new(CountingProvider::default());
+        for _ in 0..3 {
+            assert_eq!(provider.value("TMPPath").unwrap(), "C:\\tmp");
+            let missing = provider.value("EXEPath").unwrap_err();
+            assert_eq!(missing.kind(), io::ErrorKind::NotFound);
+            assert_eq!(provider.ansi_code_page().unwrap(), 1252);
+        }
+        assert_eq!(provider.inner.lookups.get(), 3);
+    }
+
+    #[test]
+    fn sha_head_works() {
+        use crate::environment::sha_head;
+        use sha2::{Digest, Sha256};
+
+        let sha256 = Sha256::new();
+        let out = sha_head(sha256.finalize());
+        assert_eq!("e3b0", out);
+        assert_eq!(4, out.capacity());
+    }
+
+    #[test]
+    fn paths_come_from_the_provider() {
+        let dir = std::env::temp_dir().join("mapirs_test");
+        assert_eq!(log_path().unwrap(), dir.join("log").into_os_string());
+        assert_eq!(
+            drafts_path().unwrap(),
+            dir.join("tmp").join("drafts").into_os_string()
+        );
+        assert!(inbox_path().is_err());
+        set_test_value("INBOXPath", Some("/inbox"));
+        assert_eq!(inbox_path().unwrap(), "/inbox");
+        set_test_value("INBOXPath", None);
+    }
+
+    #[test]
+    fn replace_profile_works() {
+        let var = std::env::var("USERPROFILE");
+        std::env::set_var("USERPROFILE", "C:\\meck");
+        assert_eq!(
+            "C:\\meck\\a\\file.txt",
+            replace_profile("%USERPROFILE%\\a\\file.txt".to_owned()).unwrap()
+        );
+        std::env::remove_var("USERPROFILE");
+        assert!(replace_profile("%USERPROFILE%\\a\\file.txt".to_owned()).is_err());
+        assert_eq!(
+            "/tmp/a/file.txt",
+            replace_profile("/tmp/a/file.txt".to_owned()).unwrap()
+        );
+        if var.is_ok() {
+            std::env::set_var("USERPROFILE", var.unwrap());
+        }
+    }
+}
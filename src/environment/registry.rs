@@ -0,0 +1,73 @@
+use std::ffi::OsStr;
+use std::io;
+// NOTE: enables creation_flags on the command builder, only works on windows
+use std::os::windows::process::CommandExt;
+use std::process::Command;
+
+use winreg::types::FromRegValue;
+use winreg::{enums::*, RegKey, RegValue};
+
+use crate::environment::Provider;
+
+const CREATE_NO_WINDOW: u32 = 0x08000000;
+const DETACHED_PROCESS: u32 = 0x00000008;
+
+/// reads the values the client wrote to SOFTWARE\Clients\Mail\tutanota
+pub struct RegistryProvider;
+
+fn reg_key() -> io::Result<RegKey> {
+    // it would be possible to get the path via hkcu/software/{tutanota GUID}, but that GUID is
+    // different for release, test and snapshot.
+    // the GUID is the AppID of Tutanota Desktop as assigned by electron-builder
+    // let subkey_path_release = "SOFTWARE\\450699d2-1c81-5ee5-aec6-08dddb7af9d7"
+
+    // the client saves the path to the executable to hklm/software/Clients/Mail/tutanota/EXEPath
+    // or hkcu/software/Clients/Mail/tutanota/EXEPath
+    // that key must be there, otherwise windows couldn't have called this DLL because
+    // the path to it is next to it under DLLPath.
+
+    let hklm = RegKey::predef(HKEY_LOCAL_MACHINE);
+    let hkcu = RegKey::predef(HKEY_CURRENT_USER);
+    let subk = "SOFTWARE\\Clients\\Mail\\tutanota";
+    // if this fails, the client is not installed correctly or the registry is borked.
+    hkcu.open_subkey(subk).or_else(|_| hklm.open_subkey(subk))
+}
+
+/// numbers like LOGMaxSize are naturally set as REG_DWORD or REG_QWORD,
+/// the settings parse them from their decimal representation.
+fn value_to_string(name: &str, value: &RegValue) -> io::Result<String> {
+    match value.vtype {
+        REG_DWORD => u32::from_reg_value(value).map(|n| n.to_string()),
+        REG_QWORD => u64::from_reg_value(value).map(|n| n.to_string()),
+        REG_SZ | REG_EXPAND_SZ => String::from_reg_value(value),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} is neither a string nor a number", name),
+        )),
+    }
+}
+
+impl Provider for RegistryProvider {
+    fn value(&self, name: &str) -> io::Result<String> {
+        let tutanota_key = reg_key()?;
+        value_to_string(name, &tutanota_key.get_raw_value(name)?)
+    }
+
+    fn ansi_code_page(&self) -> io::Result<u32> {
+        let hklm = RegKey::predef(HKEY_LOCAL_MACHINE);
+        let cp_key = hklm.open_subkey("SYSTEM\\CurrentControlSet\\Control\\Nls\\CodePage")?;
+        let acp: String = cp_key.get_value("ACP")?;
+        acp.trim()
+            .parse()
+            .map_err(|_e| io::Error::from(io::ErrorKind::InvalidData))
+    }
+
+    /// the client is started without a console window and isn't killed with the calling app
+    fn spawn(&self, exe: &OsStr, args: &[String]) -> io::Result<()> {
+        Command::new(exe)
+            .args(args)
+            .creation_flags(DETACHED_PROCESS | CREATE_NO_WINDOW)
+            .spawn()?;
+        Ok(())
+    }
+}
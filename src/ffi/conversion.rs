@@ -1,11 +1,117 @@
-use std::convert::{From, TryFrom, TryInto};
-use std::ffi::CStr;
+use std::ffi::{CStr, CString};
+use std::fmt;
 
-use crate::types::LpStr;
+use crate::environment;
+use crate::ffi::code_page;
+use crate::flags::MessageCodePage;
+use crate::logger;
+use crate::types::{InLpStr, LpStr, LpWStr};
 
-/// get an owned String from a raw pointer if it is valid UTF8
-/// copies the contents into a new buffer
-pub fn maybe_string_from_raw_ptr(ptr: LpStr) -> Option<String> {
+/// the code page we assume if we can't find out the system's ANSI code page
+const FALLBACK_ANSI_CODE_PAGE: u32 = 1252;
+
+/// how a string we got over ffi was turned into a String
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeStrategy {
+    Utf8,
+    CodePage(u32),
+    LossyUtf8,
+}
+
+impl fmt::Display for DecodeStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeStrategy::Utf8 => write!(f, "utf8"),
+            DecodeStrategy::CodePage(cp) => write!(f, "cp{}", cp),
+            DecodeStrategy::LossyUtf8 => write!(f, "lossy utf8"),
+        }
+    }
+}
+
+/// decodes the LPSTRs of a RawMapiMessage and its descriptors according to the
+/// code page given in RawMapiMessage.reserved (either CP_UTF8 or the system's
+/// ANSI code page)
+#[derive(Debug, Clone, Copy)]
+pub struct StringDecoder {
+    code_page: MessageCodePage,
+    ansi_code_page: u32,
+}
+
+impl Default for StringDecoder {
+    /// decoder for LPSTRs that don't come with a code page, like the
+    /// ones given to MAPISendDocuments.
+    fn default() -> Self {
+        StringDecoder::new(MessageCodePage::Ansi)
+    }
+}
+
+impl StringDecoder {
+    pub fn new(code_page: MessageCodePage) -> Self {
+        let ansi_code_page = environment::ansi_code_page().unwrap_or_else(|e| {
+            logger::warn(
+                "StringDecoder::new",
+                &format!(
+                    "could not get ansi code page, assuming {}: {:?}",
+                    FALLBACK_ANSI_CODE_PAGE, e
+                ),
+            );
+            FALLBACK_ANSI_CODE_PAGE
+        });
+        StringDecoder {
+            code_page,
+            ansi_code_page,
+        }
+    }
+
+    /// get an owned String from a raw pointer, decoded according to
+    /// the code page. field is used to log the strategy that was used.
+    ///
+    /// returns None if the pointer is null.
+    pub fn decode(&self, ptr: LpStr, field: &str) -> Option<String> {
+        let bytes = maybe_bytes_from_raw_ptr(ptr)?;
+        let (decoded, strategy) = self.decode_bytes(&bytes);
+        logger::trace("StringDecoder::decode", &format!("{}: {}", field, strategy));
+        Some(decoded)
+    }
+
+    /// the reverse of decode, for strings we hand back to the caller.
+    /// chars that can't be represented in the ANSI code page become '?',
+    /// nul bytes are dropped.
+    pub fn encode(&self, s: &str) -> CString {
+        let bytes = match self.code_page {
+            MessageCodePage::Utf8 => s.as_bytes().to_vec(),
+            MessageCodePage::Ansi => code_page::encode_single_byte(self.ansi_code_page, s)
+                .unwrap_or_else(|| s.as_bytes().to_vec()),
+        };
+        let bytes: Vec<u8> = bytes.into_iter().filter(|b| *b != 0).collect();
+        // we removed all nul bytes, so this can't fail.
+        CString::new(bytes).unwrap_or_default()
+    }
+
+    /// in ANSI mode, strings that are valid UTF-8 are still taken as-is. some
+    /// callers don't set CP_UTF8 even though they give us UTF-8, and text in a
+    /// single-byte code page that happens to be valid UTF-8 is very rare.
+    fn decode_bytes(&self, bytes: &[u8]) -> (String, DecodeStrategy) {
+        if let Ok(s) = std::str::from_utf8(bytes) {
+            return (s.to_owned(), DecodeStrategy::Utf8);
+        }
+
+        if self.code_page == MessageCodePage::Ansi {
+            if let Some(s) = code_page::decode_single_byte(self.ansi_code_page, bytes) {
+                return (s, DecodeStrategy::CodePage(self.ansi_code_page));
+            }
+        }
+
+        (
+            String::from_utf8_lossy(bytes).into_owned(),
+            DecodeStrategy::LossyUtf8,
+        )
+    }
+}
+
+/// copy the bytes of a nul-terminated string from a raw pointer
+/// into a new buffer, without the terminator.
+fn maybe_bytes_from_raw_ptr(ptr: LpStr) -> Option<Vec<u8>> {
     if ptr.is_null() {
         return None;
     }
@@ -14,29 +120,51 @@ pub fn maybe_string_from_raw_ptr(ptr: LpStr) -> Option<String> {
     // * There is no guarantee to the validity of ptr:
     //   -> we checked that the ptr is not null, other invalid ptrs would be a bug in the calling app
     // * The returned lifetime is not guaranteed to be the actual lifetime of ptr:
-    //   -> we immediately clone the resulting Cstr into a Heap-allocated String
+    //   -> we immediately clone the resulting Cstr into a Heap-allocated Vec
     // * There is no guarantee that the memory pointed to by ptr contains a valid
     //   nul terminator byte at the end of the string:
     //   -> this would be a bug in the calling app
     // * It is not guaranteed that the memory pointed by ptr won't change before the CStr has been
     //   destroyed:
     //   -> this would be a bug in the calling app. We're only reading from the CStr once to clone
-    //       it into an owned String, so the window for the memory to change is small.
-    let maybe_str = unsafe { CStr::from_ptr(ptr) }.to_str();
+    //       it into an owned Vec, so the window for the memory to change is small.
+    let bytes = unsafe { CStr::from_ptr(ptr) }.to_bytes();
+    // after this, we don't care about the memory pointed by ptr anymore
+    Some(bytes.to_vec())
+}
 
-    if let Ok(s) = maybe_str {
-        // after this, we don't care about the memory pointed by ptr anymore
-        Some(String::from(s))
-    } else {
-        None
+/// get an owned String from a raw pointer to a nul-terminated
+/// UTF-16 string (PWSTR) if it is valid UTF-16.
+/// copies the contents into a new buffer
+pub fn maybe_string_from_raw_wide_ptr(ptr: LpWStr) -> Option<String> {
+    if ptr.is_null() || ptr.align_offset(std::mem::align_of::<u16>()) != 0 {
+        return None;
+    }
+
+    // SAFETY: https://doc.rust-lang.org/std/primitive.pointer.html#method.add
+    // we checked that the ptr is not null and aligned. we only read up to and including
+    // the nul terminator, so we stay inside the allocation as long as the calling app
+    // gave us a properly terminated string. anything else would be a bug in the calling app.
+    let mut len: usize = 0;
+    while unsafe { *ptr.add(len) } != 0 {
+        len += 1;
     }
+
+    // SAFETY: see copy_c_array_to_vec, we determined the length ourselves above.
+    let slc: &[u16] = unsafe { std::slice::from_raw_parts(ptr, len) };
+    // after this, we don't care about the memory pointed by ptr anymore
+    String::from_utf16(slc).ok()
 }
 
 /// convert a raw pointer + an element count into a vec
-/// by copying all the elements into a new vec and calling TryFrom on them
+/// by calling convert on each of the elements and collecting the results
 ///
 /// returns an empty vec if the pointer is invalid or count is 0
-pub fn raw_to_vec<'a, K: TryFrom<&'a T>, T: 'a>(ptr: *const T, count: usize) -> Vec<Option<K>> {
+pub fn raw_to_vec<K, T>(
+    ptr: *const T,
+    count: usize,
+    convert: impl Fn(&T) -> Option<K>,
+) -> Vec<Option<K>> {
     let mut v: Vec<Option<K>> = vec![];
     // the ptr must be aligned to T's alignment for this to be safe:
     // https://doc.rust-lang.org/reference/type-layout.html#reprc-structs
@@ -58,7 +186,7 @@ pub fn raw_to_vec<'a, K: TryFrom<&'a T>, T: 'a>(ptr: *const T, count: usize) ->
      */
     let slc: &[T] = unsafe { std::slice::from_raw_parts(ptr, count) };
     for t in slc.iter() {
-        v.push(t.try_into().ok());
+        v.push(convert(t));
     }
     v
 }
@@ -87,6 +215,42 @@ pub fn copy_c_array_to_vec<T: Clone>(ptr: *const T, count: usize) -> Vec<T> {
     }
 }
 
+/// write a value into an out parameter the calling app gave us, like
+/// the LPLHANDLE of MAPILogon or the lpMapiRecipDesc FAR * of MAPIResolveName.
+///
+/// returns Err if the pointer is null or not aligned.
+pub fn write_to_raw_ptr<T>(ptr: *mut T, val: T) -> Result<(), ()> {
+    if ptr.is_null() || ptr.align_offset(std::mem::align_of::<T>()) != 0 {
+        return Err(());
+    }
+    // SAFETY: https://doc.rust-lang.org/std/ptr/fn.write.html#safety
+    // checked for null and alignment, the pointee must be valid for writes,
+    // which would be a bug in the calling app otherwise.
+    // we use write because the old value may be uninitialized and must not be dropped.
+    unsafe { ptr.write(val) };
+    Ok(())
+}
+
+/// copy a string into a caller-allocated buffer of capacity bytes, like the
+/// lpszMessageID of MAPISaveMail. the string is nul-terminated.
+///
+/// returns Err if the pointer is null or the string doesn't fit.
+pub fn write_to_raw_buffer(ptr: InLpStr, s: &str, capacity: usize) -> Result<(), ()> {
+    let bytes = s.as_bytes();
+    if ptr.is_null() || bytes.contains(&0) || bytes.len() >= capacity {
+        return Err(());
+    }
+    // SAFETY: https://doc.rust-lang.org/std/ptr/fn.copy_nonoverlapping.html#safety
+    // checked for null, u8 has no alignment requirements and we write at most
+    // capacity bytes. the calling app must give us a buffer that's at least that big,
+    // anything else would be a bug in the calling app.
+    unsafe {
+        std::ptr::copy_nonoverlapping(bytes.as_ptr(), ptr as *mut u8, bytes.len());
+        ptr.add(bytes.len()).write(0);
+    }
+    Ok(())
+}
+
 /// MapiSendDocuments gets its file paths as a list packed into a string with
 /// a delimiter:
 /// C:\a.txt;C:\b.txt;A:\d.jpg
@@ -103,7 +267,13 @@ pub fn unpack_strings(packed: String, delim: &str) -> Vec<String> {
 
 #[cfg(test)]
 mod test {
-    use crate::ffi::conversion::unpack_strings;
+    use std::ffi::CString;
+
+    use crate::ffi::conversion::{
+        maybe_string_from_raw_wide_ptr, unpack_strings, write_to_raw_buffer, write_to_raw_ptr,
+        DecodeStrategy, StringDecoder,
+    };
+    use crate::flags::MessageCodePage;
 
     #[test]
     fn unpack_strings_works() {
@@ -132,4 +302,90 @@ mod test {
             vec!["C:\\a.txt"]
         );
     }
+
+    #[test]
+    fn maybe_string_from_raw_wide_ptr_works() {
+        let wide = |s: &str| s.encode_utf16().chain(Some(0)).collect::<Vec<u16>>();
+
+        assert_eq!(maybe_string_from_raw_wide_ptr(std::ptr::null()), None);
+        assert_eq!(
+            maybe_string_from_raw_wide_ptr(wide("").as_ptr()),
+            Some("".to_owned())
+        );
+        assert_eq!(
+            maybe_string_from_raw_wide_ptr(wide("Jürgen Åström 😀").as_ptr()),
+            Some("Jürgen Åström 😀".to_owned())
+        );
+        // lone surrogate
+        assert_eq!(
+            maybe_string_from_raw_wide_ptr(vec![0x0041, 0xD800, 0x0000].as_ptr()),
+            None
+        );
+    }
+
+    #[test]
+    fn string_decoder_works() {
+        let ansi = StringDecoder::new(MessageCodePage::Ansi);
+        let utf8 = StringDecoder::new(MessageCodePage::Utf8);
+
+        assert_eq!(
+            ansi.decode_bytes(b"plain"),
+            ("plain".to_owned(), DecodeStrategy::Utf8)
+        );
+        assert_eq!(
+            ansi.decode_bytes("Grüße".as_bytes()),
+            ("Grüße".to_owned(), DecodeStrategy::Utf8)
+        );
+        assert_eq!(
+            ansi.decode_bytes(b"Gr\xFC\xDFe"),
+            ("Grüße".to_owned(), DecodeStrategy::CodePage(1252))
+        );
+        assert_eq!(
+            utf8.decode_bytes(b"Gr\xFC\xDFe"),
+            ("Gr\u{FFFD}\u{FFFD}e".to_owned(), DecodeStrategy::LossyUtf8)
+        );
+
+        assert_eq!(ansi.encode("Grüße\0").as_bytes(), b"Gr\xFC\xDFe");
+        assert_eq!(utf8.encode("Grüße").as_bytes(), "Grüße".as_bytes());
+
+        assert_eq!(ansi.decode(std::ptr::null(), "subject"), None);
+        let cp1252 = CString::new(b"Gr\xFC\xDFe".to_vec()).unwrap();
+        assert_eq!(
+            ansi.decode(cp1252.as_ptr(), "subject"),
+            Some("Grüße".to_owned())
+        );
+    }
+
+    #[test]
+    fn write_to_raw_ptr_works() {
+        let mut handle: usize = 0;
+        assert_eq!(write_to_raw_ptr(&mut handle as *mut usize, 42), Ok(()));
+        assert_eq!(handle, 42);
+        assert_eq!(write_to_raw_ptr(std::ptr::null_mut::<usize>(), 42), Err(()));
+    }
+
+    #[test]
+    fn write_to_raw_buffer_works() {
+        let mut buf = [0x55 as libc::c_char; 8];
+        assert_eq!(
+            write_to_raw_buffer(buf.as_mut_ptr(), "abc", buf.len()),
+            Ok(())
+        );
+        assert_eq!(
+            unsafe { std::ffi::CStr::from_ptr(buf.as_ptr()) }.to_str(),
+            Ok("abc")
+        );
+        // leaves the rest of the buffer alone
+        assert_eq!(buf[4], 0x55);
+
+        // needs room for the terminator
+        assert_eq!(
+            write_to_raw_buffer(buf.as_mut_ptr(), "12345678", buf.len()),
+            Err(())
+        );
+        assert_eq!(
+            write_to_raw_buffer(std::ptr::null_mut(), "abc", buf.len()),
+            Err(())
+        );
+    }
 }
@@ -1,17 +1,34 @@
 use std::convert::TryFrom;
 
-use crate::commands;
-use crate::commands::send_mail;
-use crate::ffi::conversion::{maybe_string_from_raw_ptr, unpack_strings};
+use time::OffsetDateTime;
+
+use crate::allocation;
+use crate::allocation::BufferChain;
+use crate::commands::{send_mail, SendMode};
+use crate::contacts;
+use crate::environment;
+use crate::ffi::conversion::{unpack_strings, StringDecoder};
 use crate::flags::{
     MapiAddressFlags, MapiDetailsFlags, MapiFindNextFlags, MapiLogonFlags, MapiReadMailFlags,
-    MapiResolveNameFlags, MapiSaveMailFlags, MapiSendMailFlags, MapiStatusCode,
+    MapiRecipClass, MapiResolveNameFlags, MapiSaveMailFlags, MapiSendMailFlags, MapiStatusCode,
+};
+use crate::logger;
+use crate::session;
+use crate::settings;
+use crate::settings::SendPolicy;
+use crate::store;
+use crate::structs::{
+    AttachmentMode, Message, RawMapiMessage, RawMapiMessageW, RawMapiRecipDesc, RecipientDescriptor,
 };
-use crate::structs::{Message, RawMapiMessage, RawMapiRecipDesc};
 use crate::types::*;
 
+pub mod code_page;
 pub mod conversion;
 
+/// MAPIAddress has edit fields for MAPI_TO, MAPI_CC and MAPI_BCC at most. this
+/// number means "one for every class".
+const MAX_EDIT_FIELDS: ULong = 4;
+
 /// https://docs.microsoft.com/en-us/windows/win32/api/mapi/nc-mapi-mapilogon
 #[no_mangle]
 pub extern "C" fn MAPILogon(
@@ -22,21 +39,39 @@ pub extern "C" fn MAPILogon(
     // LPSTR lpszPassword
     _password: LpStr,
     // FLAGS flFlags
-    _flags: MapiLogonFlags,
+    flags: MapiLogonFlags,
     // ULONG ulReserved (mb 0)
     _reserved: ULong,
-    // was LPLHANDLE lplhSession. fix if we ever want to use sessions
-    _session: LpVoid,
+    // LPLHANDLE lplhSession
+    session: LpLHandle,
 ) -> MapiStatusCode {
-    commands::log_to_file("mapilogon", "");
-    MapiStatusCode::NotSupported
+    let _call = logger::begin_call("mapilogon");
+    if session.is_null() {
+        logger::error("mapilogon", "got null session pointer");
+        return MapiStatusCode::Failure;
+    }
+    match session::logon(flags.contains(MapiLogonFlags::NEW_SESSION)) {
+        Ok(handle) => {
+            if conversion::write_to_raw_ptr(session, handle).is_err() {
+                logger::error("mapilogon", "could not write session handle");
+                let _ = session::logoff(handle);
+                return MapiStatusCode::Failure;
+            }
+            logger::info("mapilogon", &format!("logged on session {}", handle));
+            MapiStatusCode::Success
+        }
+        Err(e) => {
+            logger::error("mapilogon", &format!("could not log on: {:?}", e));
+            e
+        }
+    }
 }
 
 /// https://docs.microsoft.com/en-us/windows/win32/api/mapi/nc-mapi-mapilogoff
 #[no_mangle]
 pub extern "C" fn MAPILogoff(
-    // was LPLHANDLE lplhSession. fix if we ever want to use sessions
-    _session: LpVoid,
+    // LHANDLE lhSession
+    session: LHandle,
     // ULONG_PTR ulUIParam
     _ui_param: ULongPtr,
     // FLAGS flFlags (reserved, must be zero)
@@ -44,36 +79,120 @@ pub extern "C" fn MAPILogoff(
     // ULONG ulReserved
     _reserved: ULong,
 ) -> MapiStatusCode {
-    commands::log_to_file("mapilogoff", "");
-    MapiStatusCode::NotSupported
+    let _call = logger::begin_call("mapilogoff");
+    match session::logoff(session) {
+        Ok(()) => MapiStatusCode::Success,
+        Err(e) => {
+            logger::error(
+                "mapilogoff",
+                &format!("could not log off {}: {:?}", session, e),
+            );
+            e
+        }
+    }
 }
 
 /// https://docs.microsoft.com/en-us/windows/win32/api/mapi/nc-mapi-mapisendmail
 #[no_mangle]
 pub extern "C" fn MAPISendMail(
-    // was LPLHANDLE lplhSession. fix if we ever want to use sessions
-    _session: LpVoid,
+    // LHANDLE lhSession
+    session: LHandle,
     // ULONG_PTR
     _ui_param: ULongPtr,
     // lpMapiMessage lpMessage
     message: *const RawMapiMessage,
     // FLAGS flFlags
-    _flags: MapiSendMailFlags,
+    flags: MapiSendMailFlags,
     // ULONG reserved mb 0
     _reserved: ULong,
 ) -> MapiStatusCode {
-    if let Ok(msg) = Message::try_from(message) {
-        commands::log_to_file("mapisendmail", "parsed message, sending...");
-        if let Err(e) = send_mail(msg) {
-            commands::log_to_file("mapisendmail", &format!("could not send mail: {:?}", e));
-            MapiStatusCode::Failure
-        } else {
-            commands::log_to_file("mapisendmail", "sent message!");
-            MapiStatusCode::Success
+    let _call = logger::begin_call("mapisendmail");
+    if let Err(e) = check_session("mapisendmail", session) {
+        return e;
+    }
+    send_parsed_message("mapisendmail", Message::try_from(message), flags)
+}
+
+/// https://docs.microsoft.com/en-us/windows/win32/api/mapi/nc-mapi-mapisendmailw
+/// the same as MAPISendMail, but with UTF-16 strings.
+#[no_mangle]
+pub extern "C" fn MAPISendMailW(
+    // LHANDLE lhSession
+    session: LHandle,
+    // ULONG_PTR
+    _ui_param: ULongPtr,
+    // lpMapiMessageW lpMessage
+    message: *const RawMapiMessageW,
+    // FLAGS flFlags
+    flags: MapiSendMailFlags,
+    // ULONG reserved mb 0
+    _reserved: ULong,
+) -> MapiStatusCode {
+    let _call = logger::begin_call("mapisendmailw");
+    if let Err(e) = check_session("mapisendmailw", session) {
+        return e;
+    }
+    send_parsed_message("mapisendmailw", Message::try_from(message), flags)
+}
+
+/// the session handle passed to the entry points must either be 0 (the implicit session)
+/// or a handle we handed out in MAPILogon that wasn't logged off yet.
+fn check_session(caller: &str, session: LHandle) -> Result<(), MapiStatusCode> {
+    let checked = session::check(session);
+    if let Err(e) = checked {
+        logger::error(caller, &format!("invalid session {}: {:?}", session, e));
+    }
+    checked
+}
+
+/// without MAPI_DIALOG, the message is sent without showing the composer (unless
+/// SENDPolicy says otherwise). that only works if it has someone to go to.
+fn send_parsed_message(
+    caller: &str,
+    parsed: Result<Message, MapiStatusCode>,
+    flags: MapiSendMailFlags,
+) -> MapiStatusCode {
+    match parsed {
+        Ok(msg) => {
+            let msg = match msg.sanitize() {
+                Ok(msg) => msg,
+                Err(e) => {
+                    logger::warn(caller, "refusing a message with unsafe recipients");
+                    return e;
+                }
+            };
+            let settings = settings::get();
+            let mode = if flags.contains(MapiSendMailFlags::DIALOG) {
+                SendMode::Compose
+            } else if settings.send_policy == SendPolicy::AlwaysCompose {
+                logger::info(caller, "unattended sending is turned off, composing");
+                SendMode::Compose
+            } else {
+                SendMode::Unattended
+            };
+            if mode == SendMode::Unattended && !msg.has_valid_recipients() {
+                logger::warn(caller, "no valid recipients for unattended send");
+                return MapiStatusCode::InvalidRecips;
+            }
+            if let Err(e) = msg.check_attachment_sizes(
+                settings.max_attachment_size,
+                settings.max_total_attachment_size,
+            ) {
+                return e;
+            }
+            logger::debug(caller, "parsed message, sending...");
+            if let Err(e) = send_mail(msg, mode) {
+                logger::error(caller, &format!("could not send mail: {:?}", e));
+                e
+            } else {
+                logger::info(caller, "sent message!");
+                MapiStatusCode::Success
+            }
+        }
+        Err(e) => {
+            logger::error(caller, &format!("could not parse arguments: {:?}", e));
+            e
         }
-    } else {
-        commands::log_to_file("mapisendmail", "could not parse arguments.");
-        MapiStatusCode::Failure
     }
 }
 
@@ -89,148 +208,701 @@ pub extern "C" fn MAPISendDocuments(
     file_names: InLpStr,
     _reserved: ULong,
 ) -> MapiStatusCode {
-    commands::log_to_file("mapisenddocuments", "");
+    let _call = logger::begin_call("mapisenddocuments");
+    // there is no code page parameter, so these are in the ANSI code page.
+    let decoder = StringDecoder::default();
     // some app may put null as delim if there's only one path
-    let delim = maybe_string_from_raw_ptr(delim_char).unwrap_or_else(|| "".to_owned());
+    let delim = decoder
+        .decode(delim_char, "delim_char")
+        .unwrap_or_else(|| "".to_owned());
 
     // spec says if this is empty or null, show sendmail dialog without attachments
-    let packed_paths = maybe_string_from_raw_ptr(file_paths).unwrap_or_else(|| "".to_owned());
+    let packed_paths = decoder
+        .decode(file_paths, "file_paths")
+        .unwrap_or_else(|| "".to_owned());
     // spec says if this is empty or null, ignore
-    let packed_names = maybe_string_from_raw_ptr(file_names).unwrap_or_else(|| "".to_owned());
+    let packed_names = decoder
+        .decode(file_names, "file_names")
+        .unwrap_or_else(|| "".to_owned());
 
     let paths = unpack_strings(packed_paths, &delim);
     let names = unpack_strings(packed_names, &delim);
 
     let msg = Message::from_paths(paths, names);
 
-    commands::log_to_file("mapisenddocument", "parsed documents, sending...");
-    if let Err(e) = send_mail(msg) {
-        commands::log_to_file("mapisenddocument", &format!("could not send mail: {:?}", e));
-        MapiStatusCode::Failure
+    logger::debug("mapisenddocument", "parsed documents, sending...");
+    // MAPISendDocuments always shows the composer
+    if let Err(e) = send_mail(msg, SendMode::Compose) {
+        logger::error("mapisenddocument", &format!("could not send mail: {:?}", e));
+        e
     } else {
-        commands::log_to_file("mapisenddocument", "sent message!");
+        logger::info("mapisenddocument", "sent message!");
         MapiStatusCode::Success
     }
 }
 
 /// https://docs.microsoft.com/en-us/windows/win32/api/mapi/nc-mapi-mapifindnext
+/// enumerates the messages in the inbox directory the client exports to.
 #[no_mangle]
 pub extern "C" fn MAPIFindNext(
-    _session: LpVoid,
+    // LHANDLE lhSession
+    session: LHandle,
     _ui_param: ULongPtr,
     // LPSTR lpszMessageType
-    _message_type: LpStr,
+    message_type: LpStr,
     // LPSTR lpszSeedMessageID
-    _seed_message_id: LpStr,
-    _flags: MapiFindNextFlags,
+    seed_message_id: LpStr,
+    flags: MapiFindNextFlags,
     _reserved: ULong,
     // LPSTR lpszMessageID
-    _message_id: LpStr,
+    message_id: InLpStr,
 ) -> MapiStatusCode {
-    commands::log_to_file("mapifindnext", "");
-    MapiStatusCode::NotSupported
+    let _call = logger::begin_call("mapifindnext");
+    if let Err(e) = check_session("mapifindnext", session) {
+        return e;
+    }
+    if message_id.is_null() {
+        logger::error("mapifindnext", "got null message id pointer");
+        return MapiStatusCode::Failure;
+    }
+
+    let decoder = StringDecoder::default();
+    // we only have interpersonal messages, which is what null or "" stands for.
+    let message_type = decoder
+        .decode(message_type, "message_type")
+        .unwrap_or_default();
+    if !message_type.is_empty() && message_type != "IPM" && !message_type.starts_with("IPM.") {
+        return MapiStatusCode::NoMessages;
+    }
+    let seed = decoder
+        .decode(seed_message_id, "seed_message_id")
+        .filter(|id| !id.is_empty());
+
+    let inbox = match store::MessageStore::inbox() {
+        Ok(inbox) => inbox,
+        Err(e) => {
+            logger::warn("mapifindnext", &format!("no inbox dir: {:?}", e));
+            return MapiStatusCode::NoMessages;
+        }
+    };
+    let mut messages = inbox.list();
+    if flags.contains(MapiFindNextFlags::GUARANTEE_FIFO) {
+        // stable, so messages received at the same time stay ordered by id
+        messages.sort_by_key(|m| m.received);
+    }
+
+    let unread_only = flags.contains(MapiFindNextFlags::UNREAD_ONLY);
+    match store::next_after(&messages, seed.as_deref(), unread_only) {
+        Ok(Some(next)) => {
+            let id_buffer_size =
+                store::id_buffer_size(flags.contains(MapiFindNextFlags::LONG_MSGID));
+            if conversion::write_to_raw_buffer(message_id, &next.id, id_buffer_size).is_err() {
+                logger::error("mapifindnext", "could not write message id");
+                return MapiStatusCode::Failure;
+            }
+            MapiStatusCode::Success
+        }
+        Ok(None) => MapiStatusCode::NoMessages,
+        Err(e) => {
+            logger::warn("mapifindnext", &format!("unknown seed: {:?}", seed));
+            e
+        }
+    }
 }
 
+/// https://docs.microsoft.com/en-us/windows/win32/api/mapi/nc-mapi-mapireadmail
+/// reads a message from the inbox directory or the drafts. attachments
+/// are extracted to the tmp dir.
 #[no_mangle]
 pub extern "C" fn MAPIReadMail(
-    _session: LpVoid,
+    // LHANDLE lhSession
+    session: LHandle,
     _ui_param: ULongPtr,
     // __in LPSTR lpszMessageID
-    _message_id: InLpStr,
-    _flags: MapiReadMailFlags,
+    message_id: InLpStr,
+    flags: MapiReadMailFlags,
     _reserved: ULong,
     // lpMapiMessage FAR *lppMessage
-    _message: *const RawMapiMessage,
+    message: *mut *mut RawMapiMessage,
 ) -> MapiStatusCode {
-    commands::log_to_file("mapireadmail", "");
-    MapiStatusCode::NotSupported
+    let _call = logger::begin_call("mapireadmail");
+    if let Err(e) = check_session("mapireadmail", session) {
+        return e;
+    }
+    if message.is_null() {
+        logger::error("mapireadmail", "got null message pointer");
+        return MapiStatusCode::Failure;
+    }
+
+    let decoder = StringDecoder::default();
+    let id = match decoder.decode(message_id, "message_id") {
+        Some(id) => id,
+        None => return MapiStatusCode::InvalidMessage,
+    };
+    let (msg_store, path) = match store::find_message(&id) {
+        Some(found) => found,
+        None => {
+            logger::warn("mapireadmail", &format!("no message with id {}", id));
+            return MapiStatusCode::InvalidMessage;
+        }
+    };
+    let bytes = match std::fs::read(&path) {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            logger::error("mapireadmail", &format!("could not read: {:?}", e));
+            return MapiStatusCode::Failure;
+        }
+    };
+
+    let tmp_path = environment::tmp_path().ok().map(|p| p.into());
+    let mut msg = match Message::from_eml(&bytes, flags, &tmp_path) {
+        Ok(msg) => msg,
+        Err(e) => return e,
+    };
+    let unread = store::is_unread(&path);
+    msg.set_unread(unread);
+
+    let mut chain = BufferChain::new();
+    let raw = msg.to_raw(&mut chain, &decoder);
+    // the calling app frees this with MAPIFreeBuffer
+    let raw = allocation::allocate(raw, chain);
+    if conversion::write_to_raw_ptr(message, raw).is_err() {
+        logger::error("mapireadmail", "could not write message");
+        let _ = allocation::free(raw as LpVoid);
+        return MapiStatusCode::Failure;
+    }
+
+    if unread && !flags.contains(MapiReadMailFlags::PEEK) {
+        if let Err(e) = msg_store.mark_read(&id) {
+            logger::error("mapireadmail", &format!("could not mark read: {:?}", e));
+        }
+    }
+    MapiStatusCode::Success
 }
 
+/// https://docs.microsoft.com/en-us/windows/win32/api/mapi/nc-mapi-mapisavemail
+/// writes the message as an .eml draft. an empty message id creates a new
+/// draft, an existing one is replaced.
 #[no_mangle]
 pub extern "C" fn MAPISaveMail(
-    _session: LpVoid,
+    // LHANDLE lhSession
+    session: LHandle,
     _ui_param: ULongPtr,
     // lpMapimessage lpMessage
-    _message: *const RawMapiMessage,
-    _flags: MapiSaveMailFlags,
+    message: *const RawMapiMessage,
+    flags: MapiSaveMailFlags,
     _reserved: ULong,
     // __in LPSTR lpszMessageID
-    _message_id: InLpStr,
+    message_id: InLpStr,
 ) -> MapiStatusCode {
-    commands::log_to_file("mapisavemail", "");
-    MapiStatusCode::NotSupported
+    let _call = logger::begin_call("mapisavemail");
+    if let Err(e) = check_session("mapisavemail", session) {
+        return e;
+    }
+    if message_id.is_null() {
+        logger::error("mapisavemail", "got null message id pointer");
+        return MapiStatusCode::Failure;
+    }
+    let id_buffer_size = store::id_buffer_size(flags.contains(MapiSaveMailFlags::LONG_MSGID));
+
+    let msg = match Message::try_from(message).and_then(Message::sanitize) {
+        Ok(msg) => msg,
+        Err(MapiStatusCode::Failure) => {
+            logger::error("mapisavemail", "could not parse arguments.");
+            return MapiStatusCode::InvalidMessage;
+        }
+        Err(e) => {
+            logger::warn("mapisavemail", &format!("invalid recipients: {:?}", e));
+            return e;
+        }
+    };
+    let existing_id = StringDecoder::default()
+        .decode(message_id, "message_id")
+        .filter(|id| !id.is_empty());
+
+    // drafts are kept around, so they can't rely on the attachments staying where they are.
+    let contents = match msg.to_eml(OffsetDateTime::now_utc(), AttachmentMode::Embed) {
+        Ok(contents) => contents,
+        Err(e) => return e,
+    };
+    let drafts = match store::MessageStore::drafts() {
+        Ok(drafts) => drafts,
+        Err(e) => {
+            logger::error(
+                "mapisavemail",
+                &format!("could not get drafts dir: {:?}", e),
+            );
+            return MapiStatusCode::Failure;
+        }
+    };
+    match drafts.save(existing_id.as_deref(), &contents) {
+        Ok(id) => {
+            if conversion::write_to_raw_buffer(message_id, &id, id_buffer_size).is_err() {
+                logger::error("mapisavemail", "could not write message id");
+                return MapiStatusCode::Failure;
+            }
+            logger::info("mapisavemail", &format!("saved draft {}", id));
+            MapiStatusCode::Success
+        }
+        Err(e) => {
+            logger::error("mapisavemail", &format!("could not save: {:?}", e));
+            e
+        }
+    }
 }
 
+/// https://docs.microsoft.com/en-us/windows/win32/api/mapi/nc-mapi-mapideletemail
+/// moves the message to the trash directory of the store it's in, so nothing is
+/// lost for good.
 #[no_mangle]
 pub extern "C" fn MAPIDeleteMail(
-    _session: LpVoid,
+    // LHANDLE lhSession
+    session: LHandle,
     _ui_param: ULongPtr,
     // __in LPSTR lpsz MessageID
-    _message_id: InLpStr,
+    message_id: InLpStr,
     // reserved, must be zero
     _flags: ULong,
     _reserved: ULong,
 ) -> MapiStatusCode {
-    commands::log_to_file("mapideletemail", "");
-    MapiStatusCode::NotSupported
+    let _call = logger::begin_call("mapideletemail");
+    if let Err(e) = check_session("mapideletemail", session) {
+        return e;
+    }
+    let id = match StringDecoder::default().decode(message_id, "message_id") {
+        Some(id) => id,
+        None => return MapiStatusCode::InvalidMessage,
+    };
+    let msg_store = match store::find_message(&id) {
+        Some((msg_store, _)) => msg_store,
+        None => {
+            logger::warn("mapideletemail", &format!("no message with id {}", id));
+            return MapiStatusCode::InvalidMessage;
+        }
+    };
+    match msg_store.delete(&id) {
+        Ok(()) => {
+            logger::info("mapideletemail", &format!("moved {} to trash", id));
+            MapiStatusCode::Success
+        }
+        Err(e) => e,
+    }
 }
 
+/// https://docs.microsoft.com/en-us/windows/win32/api/mapi/nc-mapi-mapifreebuffer
+/// frees memory we handed out (and everything chained to it).
+/// pointers we didn't hand out are rejected without touching them.
 #[no_mangle]
-pub extern "C" fn MAPIFreeBuffer(_pv: LpVoid) -> MapiStatusCode {
-    commands::log_to_file("mapifreebuffer", "");
-    MapiStatusCode::NotSupported
+pub extern "C" fn MAPIFreeBuffer(pv: LpVoid) -> MapiStatusCode {
+    let _call = logger::begin_call("mapifreebuffer");
+    if pv.is_null() {
+        return MapiStatusCode::Success;
+    }
+    match allocation::free(pv) {
+        Ok(()) => MapiStatusCode::Success,
+        Err(()) => {
+            logger::warn(
+                "mapifreebuffer",
+                &format!("{:?} was not allocated by us", pv),
+            );
+            MapiStatusCode::Failure
+        }
+    }
 }
 
+/// https://docs.microsoft.com/en-us/windows/win32/api/mapi/nc-mapi-mapiaddress
+/// there is no address book dialog, the recipients the calling app gives us
+/// are completed from the contact file and handed back.
 #[no_mangle]
 pub extern "C" fn MAPIAddress(
-    _session: LpVoid,
+    // LHANDLE lhSession
+    session: LHandle,
     // ULONG_PTR
     _ui_param: ULongPtr,
     // __in LPSTR lpszCaption
     _caption: InLpStr,
     // ULONG
-    _n_edit_fields: ULong,
+    n_edit_fields: ULong,
     // __in LPSTR lpszLabels
     _labels: InLpStr,
     // ULONG
-    _n_recipients: ULong,
+    n_recipients: ULong,
     // lpMapiRecipDesc lpRecips
-    _recipients: *const RawMapiRecipDesc,
+    recipients: *const RawMapiRecipDesc,
     _flags: MapiAddressFlags,
     // ULONG
     _reserved: ULong,
     // LPULONG lpnNewRecips
-    _n_new_recipients: ULongPtr,
+    n_new_recipients: *mut ULong,
     // lpMapiRecipDesc FAR *lppNewRecips
-    _new_recipients: *const RawMapiRecipDesc,
+    new_recipients: *mut *mut RawMapiRecipDesc,
 ) -> MapiStatusCode {
-    commands::log_to_file("mapiaddress", "");
-    MapiStatusCode::NotSupported
+    let _call = logger::begin_call("mapiaddress");
+    if let Err(e) = check_session("mapiaddress", session) {
+        return e;
+    }
+    if n_edit_fields > MAX_EDIT_FIELDS {
+        logger::warn(
+            "mapiaddress",
+            &format!("invalid edit field count {}", n_edit_fields),
+        );
+        return MapiStatusCode::InvalidEditfields;
+    }
+    if n_new_recipients.is_null() || new_recipients.is_null() {
+        logger::error("mapiaddress", "got null out pointer");
+        return MapiStatusCode::Failure;
+    }
+
+    let decoder = StringDecoder::default();
+    let recipients = match conversion::raw_to_vec(recipients, n_recipients as usize, |r| {
+        Some(RecipientDescriptor::from_raw(r, &decoder))
+    })
+    .into_iter()
+    .flatten()
+    .collect::<Result<Vec<Vec<RecipientDescriptor>>, MapiStatusCode>>()
+    {
+        Ok(recipients) => recipients.into_iter().flatten().collect::<Vec<_>>(),
+        Err(e) => return e,
+    };
+    // with zero edit fields the address book can only be browsed, nothing is returned
+    let recipients = if n_edit_fields == 0 {
+        vec![]
+    } else {
+        recipients
+    };
+
+    let contacts = contacts::load_contacts().unwrap_or_else(|e| {
+        logger::error("mapiaddress", &format!("could not load contacts: {:?}", e));
+        vec![]
+    });
+    let mut completed: Vec<RecipientDescriptor> = vec![];
+    for recip in recipients {
+        let class = match MapiRecipClass::try_from(recip.recip_class) {
+            Ok(MapiRecipClass::Orig) | Err(_) => return MapiStatusCode::BadRecipType,
+            Ok(class) => class,
+        };
+        // each edit field holds one class, starting with MAPI_TO. with the maximum
+        // number of edit fields, there is one for every class.
+        if n_edit_fields < MAX_EDIT_FIELDS && class as ULong > n_edit_fields {
+            logger::warn(
+                "mapiaddress",
+                &format!("no edit field for recipient class {:?}", class),
+            );
+            return MapiStatusCode::InvalidEditfields;
+        }
+        let contact = match contacts::complete(&contacts, &recip.name, recip.address.as_deref()) {
+            Ok(contact) => contact,
+            Err(e) => {
+                logger::error("mapiaddress", &format!("could not resolve: {:?}", e));
+                return e;
+            }
+        };
+        let duplicate = completed.iter().any(|c| {
+            c.recip_class == class as ULong
+                && c.address
+                    .as_deref()
+                    .map(|a| a.eq_ignore_ascii_case(&contact.address))
+                    .unwrap_or(false)
+        });
+        if !duplicate {
+            completed.push(RecipientDescriptor::issued(
+                class,
+                contact.name,
+                contact.address,
+            ));
+        }
+    }
+
+    let mut chain = BufferChain::new();
+    let raw: Vec<RawMapiRecipDesc> = completed
+        .iter()
+        .map(|r| r.to_raw(&mut chain, &decoder))
+        .collect();
+    let count = raw.len() as ULong;
+    // the calling app frees this with MAPIFreeBuffer
+    let raw = allocation::allocate_array(raw, chain);
+    if conversion::write_to_raw_ptr(new_recipients, raw).is_err()
+        || conversion::write_to_raw_ptr(n_new_recipients, count).is_err()
+    {
+        logger::error("mapiaddress", "could not write recipients");
+        let _ = allocation::free(raw as LpVoid);
+        return MapiStatusCode::Failure;
+    }
+    MapiStatusCode::Success
 }
 
+/// https://docs.microsoft.com/en-us/windows/win32/api/mapi/nc-mapi-mapidetails
+/// there is no dialog, so the details of a recipient we issued (see
+/// RecipientDescriptor::issued) are looked up in the contact file and logged.
+/// without AB_NOMODIFY the caller wants to let the user edit them, which needs
+/// a dialog, so that's MAPI_E_NOT_SUPPORTED.
 #[no_mangle]
 pub extern "C" fn MAPIDetails(
-    _session: LpVoid,
+    // LHANDLE lhSession
+    session: LHandle,
     _ui_param: ULongPtr,
-    _recipient: *const RawMapiRecipDesc,
-    _flags: MapiDetailsFlags,
+    recipient: *const RawMapiRecipDesc,
+    flags: MapiDetailsFlags,
     _reserved: ULong,
 ) -> MapiStatusCode {
-    commands::log_to_file("mapidetails", "");
-    MapiStatusCode::NotSupported
+    let _call = logger::begin_call("mapidetails");
+    if let Err(e) = check_session("mapidetails", session) {
+        return e;
+    }
+    let decoder = StringDecoder::default();
+    let mut recips = match RecipientDescriptor::try_from_raw_ptr(recipient, &decoder) {
+        Ok(recips) => recips,
+        Err(e) => {
+            logger::error(
+                "mapidetails",
+                &format!("could not convert recipient: {:?}", e),
+            );
+            return e;
+        }
+    };
+    // an address list is not something we can show details for
+    if recips.len() != 1 {
+        return MapiStatusCode::AmbiguousRecipient;
+    }
+    let recip = recips.remove(0);
+    if recip.is_unresolved() {
+        return MapiStatusCode::AmbiguousRecipient;
+    }
+    let address = match recip.issued_address() {
+        Some(address) => address,
+        // not one of ours, we don't know anything about it
+        None => return MapiStatusCode::UnknownRecipient,
+    };
+    if !flags.contains(MapiDetailsFlags::AB_NOMODIFY) {
+        logger::warn(
+            "mapidetails",
+            "can't let the user modify the details without a dialog",
+        );
+        return MapiStatusCode::NotSupported;
+    }
+
+    let contacts = contacts::load_contacts().unwrap_or_else(|e| {
+        logger::error("mapidetails", &format!("could not load contacts: {:?}", e));
+        vec![]
+    });
+    let name = contacts::find_by_address(&contacts, address)
+        .map(|c| c.name.as_str())
+        .unwrap_or(&recip.name);
+    logger::info("mapidetails", &format!("{} <{}>", name, address));
+    MapiStatusCode::Success
 }
 
+/// https://docs.microsoft.com/en-us/windows/win32/api/mapi/nc-mapi-mapiresolvename
+/// resolves against the contact file the client exports, there is no dialog.
 #[no_mangle]
 pub extern "C" fn MAPIResolveName(
-    _session: LpVoid,
+    // LHANDLE lhSession
+    session: LHandle,
     _ui_param: ULong,
     // __in LPSTR lpszName
-    _name: InLpStr,
-    _flags: MapiResolveNameFlags,
+    name: InLpStr,
+    flags: MapiResolveNameFlags,
     _reserved: ULong,
     // lpMapiRecipDesc FAR *lppRecip
-    _recipient: *const RawMapiRecipDesc,
+    recipient: *mut *mut RawMapiRecipDesc,
 ) -> MapiStatusCode {
-    commands::log_to_file("mapiresolvename", "");
-    MapiStatusCode::NotSupported
+    let _call = logger::begin_call("mapiresolvename");
+    if let Err(e) = check_session("mapiresolvename", session) {
+        return e;
+    }
+    if recipient.is_null() {
+        logger::error("mapiresolvename", "got null recipient pointer");
+        return MapiStatusCode::Failure;
+    }
+    if flags.contains(MapiResolveNameFlags::DIALOG) {
+        logger::debug("mapiresolvename", "no dialog available, resolving directly");
+    }
+
+    let decoder = StringDecoder::default();
+    let query = match decoder.decode(name, "name") {
+        Some(q) => q,
+        None => return MapiStatusCode::UnknownRecipient,
+    };
+    let contacts = contacts::load_contacts().unwrap_or_else(|e| {
+        logger::error(
+            "mapiresolvename",
+            &format!("could not load contacts: {:?}", e),
+        );
+        vec![]
+    });
+
+    match contacts::resolve(&contacts, &query) {
+        Ok(contact) => {
+            let mut chain = BufferChain::new();
+            let raw = RecipientDescriptor::resolved(contact.name, contact.address)
+                .to_raw(&mut chain, &decoder);
+            // the calling app frees this with MAPIFreeBuffer
+            let raw = allocation::allocate(raw, chain);
+            if conversion::write_to_raw_ptr(recipient, raw).is_err() {
+                logger::error("mapiresolvename", "could not write recipient");
+                let _ = allocation::free(raw as LpVoid);
+                return MapiStatusCode::Failure;
+            }
+            MapiStatusCode::Success
+        }
+        Err(e) => {
+            logger::error("mapiresolvename", &format!("could not resolve: {:?}", e));
+            e
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::allocation::BufferChain;
+    use crate::environment;
+    use crate::ffi::conversion::StringDecoder;
+    use crate::ffi::{MAPIDetails, MAPISendMailW};
+    use crate::flags::{MapiDetailsFlags, MapiRecipClass, MapiSendMailFlags, MapiStatusCode};
+    use crate::logger;
+    use crate::mailto;
+    use crate::settings;
+    use crate::settings::{Handoff, SendPolicy, Settings};
+    use crate::structs::{RawMapiMessageW, RawMapiRecipDescW, RecipientDescriptor};
+    use crate::transport::RecordingTransport;
+
+    fn wide(s: &str) -> Vec<u16> {
+        s.encode_utf16().chain(Some(0)).collect()
+    }
+
+    fn send(msg: *const RawMapiMessageW, flags: MapiSendMailFlags) -> MapiStatusCode {
+        MAPISendMailW(0, std::ptr::null(), msg, flags, 0)
+    }
+
+    #[test]
+    fn send_mail_delivers_message() {
+        let (to, cc) = (wide("SMTP:a@b.de; <c@d.de>"), wide("mailto:e@f.de"));
+        let (name, subject) = (wide("Jane"), wide("hi\r\nBcc: evil@attacker.com"));
+        let recips = [
+            RawMapiRecipDescW::new(1, name.as_ptr(), to.as_ptr()),
+            RawMapiRecipDescW::new(2, std::ptr::null(), cc.as_ptr()),
+        ];
+        let msg = RawMapiMessageW::new(subject.as_ptr(), std::ptr::null(), &recips);
+
+        logger::take_test_log();
+        let status = send(&msg, MapiSendMailFlags::empty());
+        assert_eq!(status, MapiStatusCode::Success);
+        let deliveries = RecordingTransport::take();
+        assert_eq!(deliveries.len(), 1);
+        assert_eq!(deliveries[0].mode, crate::commands::SendMode::Unattended);
+        let call_id = deliveries[0].call_id.clone().unwrap();
+        let log = logger::take_test_log();
+        assert!(log.len() > 1);
+        assert!(log.iter().all(|line| line.contains(&call_id)));
+        let link = mailto::parse(&deliveries[0].mailto).unwrap();
+        assert_eq!(link.to, vec!["a@b.de", "c@d.de"]);
+        assert_eq!(link.cc, vec!["e@f.de"]);
+        assert_eq!(link.subject, Some("hi Bcc: evil@attacker.com".to_owned()));
+        assert!(link.bcc.is_empty());
+    }
+
+    #[test]
+    fn send_mail_rejects_messages() {
+        let msg = RawMapiMessageW::new(std::ptr::null(), std::ptr::null(), &[]);
+        let status = send(&msg, MapiSendMailFlags::empty());
+        assert_eq!(status, MapiStatusCode::InvalidRecips);
+
+        let x500 = wide("EX:/o=Org/ou=Site/cn=Recipients/cn=jdoe");
+        let recips = [RawMapiRecipDescW::new(1, std::ptr::null(), x500.as_ptr())];
+        let msg = RawMapiMessageW::new(std::ptr::null(), std::ptr::null(), &recips);
+        let status = send(&msg, MapiSendMailFlags::DIALOG);
+        assert_eq!(status, MapiStatusCode::UnknownRecipient);
+
+        let status = send(std::ptr::null(), MapiSendMailFlags::DIALOG);
+        assert_eq!(status, MapiStatusCode::Failure);
+        assert!(RecordingTransport::take().is_empty());
+    }
+
+    #[test]
+    fn send_mail_hands_off_eml() {
+        let dir = std::env::temp_dir()
+            .join("mapirs_test")
+            .join("send_mail_hands_off_eml");
+        let _ = std::fs::remove_dir_all(&dir);
+        environment::set_test_value("TMPPath", dir.to_str());
+        environment::set_test_value("EXEPath", Some("tutanota-desktop"));
+        settings::set_test_settings(Settings {
+            handoff: Handoff::Eml,
+            ..Settings::default()
+        });
+
+        let (to, subject) = (wide("a@b.de"), wide("hi"));
+        let recips = [RawMapiRecipDescW::new(1, std::ptr::null(), to.as_ptr())];
+        let msg = RawMapiMessageW::new(subject.as_ptr(), std::ptr::null(), &recips);
+        assert_eq!(
+            send(&msg, MapiSendMailFlags::DIALOG),
+            MapiStatusCode::Success
+        );
+
+        let spawned = environment::take_test_spawned();
+        assert_eq!(spawned.len(), 1);
+        let (exe, args) = &spawned[0];
+        assert_eq!(exe, "tutanota-desktop");
+        // the call id is handed to the client so its log can be matched up with ours
+        assert_eq!(args[0], "--mapi-call");
+        assert!(!args[1].is_empty());
+        assert_eq!(args[2], "--mapi-eml");
+        let eml = std::path::Path::new(&args[3]);
+        assert!(eml.starts_with(dir.join("outbox")));
+        let eml = String::from_utf8(std::fs::read(eml).unwrap()).unwrap();
+        assert!(eml.contains("To: <a@b.de>\r\n"));
+        assert!(eml.contains("Subject: hi\r\n"));
+        assert!(RecordingTransport::take().is_empty());
+    }
+
+    #[test]
+    fn send_mail_follows_settings() {
+        settings::set_test_settings(Settings {
+            handoff: Handoff::Recording,
+            send_policy: SendPolicy::AlwaysCompose,
+            ..Settings::default()
+        });
+        let to = wide("a@b.de");
+        let recips = [RawMapiRecipDescW::new(1, std::ptr::null(), to.as_ptr())];
+        let msg = RawMapiMessageW::new(std::ptr::null(), std::ptr::null(), &recips);
+        assert_eq!(
+            send(&msg, MapiSendMailFlags::empty()),
+            MapiStatusCode::Success
+        );
+        let deliveries = RecordingTransport::take();
+        assert_eq!(deliveries[0].mode, crate::commands::SendMode::Compose);
+    }
+
+    fn details(recip: &RecipientDescriptor, flags: MapiDetailsFlags) -> MapiStatusCode {
+        let mut chain = BufferChain::new();
+        let raw = recip.to_raw(&mut chain, &StringDecoder::default());
+        MAPIDetails(0, std::ptr::null(), &raw, flags, 0)
+    }
+
+    #[test]
+    fn details_works() {
+        let issued = RecipientDescriptor::issued(
+            MapiRecipClass::To,
+            "Jane".to_owned(),
+            "jane@b.de".to_owned(),
+        );
+        assert_eq!(
+            details(&issued, MapiDetailsFlags::AB_NOMODIFY),
+            MapiStatusCode::Success
+        );
+        // editing needs a dialog
+        assert_eq!(
+            details(&issued, MapiDetailsFlags::empty()),
+            MapiStatusCode::NotSupported
+        );
+        let foreign = RecipientDescriptor::new("jane@b.de");
+        assert_eq!(
+            details(&foreign, MapiDetailsFlags::AB_NOMODIFY),
+            MapiStatusCode::UnknownRecipient
+        );
+        assert_eq!(
+            details(&foreign, MapiDetailsFlags::empty()),
+            MapiStatusCode::UnknownRecipient
+        );
+    }
 }
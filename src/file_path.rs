@@ -37,28 +37,74 @@ impl FilePath {
     }
 }
 
+/// turn a file name we got from somewhere else (like a message we read) into one we
+/// can safely create in a directory of our choice: only the last path component is kept
+/// and chars that windows doesn't allow in file names are replaced.
+///
+/// returns None if nothing usable is left.
+pub fn sanitize_file_name(name: &str) -> Option<String> {
+    let last = name.rsplit(['/', '\\']).next().unwrap_or("");
+    let sanitized: String = last
+        .chars()
+        .map(|c| {
+            if c.is_control() || "<>:\"|?*".contains(c) {
+                '_'
+            } else {
+                c
+            }
+        })
+        .collect();
+    // windows drops trailing dots and spaces
+    let sanitized = sanitized.trim_end_matches(['.', ' ']).trim_start();
+    if sanitized.is_empty() {
+        None
+    } else {
+        Some(sanitized.to_owned())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::convert::TryFrom;
     use std::ffi::OsStr;
     use std::path::PathBuf;
 
-    use crate::file_path::FilePath;
+    use crate::file_path::{sanitize_file_name, FilePath};
 
     #[test]
     fn file_path_construction_works() {
+        let root = std::env::temp_dir().ancestors().last().unwrap().to_owned();
         assert!(FilePath::try_from(PathBuf::from("hello.txt")).is_ok());
-        assert!(FilePath::try_from(PathBuf::from("C:\\")).is_err());
-        assert!(FilePath::try_from(PathBuf::from("C:\\tmp\\")).is_ok());
+        assert!(FilePath::try_from(root.clone()).is_err());
+        // with a trailing separator
+        assert!(FilePath::try_from(root.join("tmp").join("")).is_ok());
     }
 
     #[test]
     fn file_path_name_works() {
+        let path = std::env::temp_dir().join("tmp").join("hello.txt");
         assert_eq!(
             OsStr::new("hello.txt"),
-            FilePath::try_from(PathBuf::from("C:\\tmp\\hello.txt"))
-                .unwrap()
-                .file_name()
+            FilePath::try_from(path).unwrap().file_name()
+        );
+    }
+
+    #[test]
+    fn sanitize_file_name_works() {
+        assert_eq!(sanitize_file_name("a.txt"), Some("a.txt".to_owned()));
+        assert_eq!(
+            sanitize_file_name("..\\..\\Windows\\win.ini"),
+            Some("win.ini".to_owned())
+        );
+        assert_eq!(
+            sanitize_file_name("../../etc/passwd"),
+            Some("passwd".to_owned())
+        );
+        assert_eq!(
+            sanitize_file_name("what?: <now>.pdf"),
+            Some("what__ _now_.pdf".to_owned())
         );
+        assert_eq!(sanitize_file_name(".."), None);
+        assert_eq!(sanitize_file_name("dir/"), None);
     }
 }
@@ -1,3 +1,7 @@
+use std::convert::TryFrom;
+
+use serde::{Deserialize, Serialize};
+
 use crate::types::*;
 
 bitflags! {
@@ -19,15 +23,28 @@ bitflags! {
 
 // ULONG are 32 bit on windows
 #[repr(u32)]
-#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum MessageCodePage {
     Ansi = 0,
     Utf8 = 65001,
 }
 
+impl From<ULong> for MessageCodePage {
+    /// anything that's not CP_UTF8 is treated as the ANSI code page
+    fn from(val: ULong) -> Self {
+        if val == MessageCodePage::Utf8 as ULong {
+            MessageCodePage::Utf8
+        } else {
+            MessageCodePage::Ansi
+        }
+    }
+}
+
 // ULONG
 #[repr(u32)]
 #[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum MapiRecipClass {
     Orig = 0,
     To = 1,
@@ -35,8 +52,23 @@ pub enum MapiRecipClass {
     Bcc = 3,
 }
 
+impl TryFrom<ULong> for MapiRecipClass {
+    type Error = MapiStatusCode;
+
+    fn try_from(val: ULong) -> Result<Self, Self::Error> {
+        match val {
+            0 => Ok(MapiRecipClass::Orig),
+            1 => Ok(MapiRecipClass::To),
+            2 => Ok(MapiRecipClass::Cc),
+            3 => Ok(MapiRecipClass::Bcc),
+            _ => Err(MapiStatusCode::BadRecipType),
+        }
+    }
+}
+
 // ULONG
 #[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum MapiStatusCode {
     Success = 0,
     UserAbort = 1,
@@ -66,6 +98,41 @@ pub enum MapiStatusCode {
     NotSupported = 26,
 }
 
+/// status codes we get from elsewhere (the client). codes we don't know become Failure.
+impl From<ULong> for MapiStatusCode {
+    fn from(val: ULong) -> Self {
+        match val {
+            0 => MapiStatusCode::Success,
+            1 => MapiStatusCode::UserAbort,
+            2 => MapiStatusCode::Failure,
+            3 => MapiStatusCode::LogonFailure,
+            4 => MapiStatusCode::DiskFull,
+            5 => MapiStatusCode::InsufficientMemory,
+            6 => MapiStatusCode::AccessDenied,
+            8 => MapiStatusCode::TooManySessions,
+            9 => MapiStatusCode::TooManyFiles,
+            10 => MapiStatusCode::TooManyRecipients,
+            11 => MapiStatusCode::AttachmentNotFound,
+            12 => MapiStatusCode::AttachmentOpenFailure,
+            13 => MapiStatusCode::AttachmentWriteFailure,
+            14 => MapiStatusCode::UnknownRecipient,
+            15 => MapiStatusCode::BadRecipType,
+            16 => MapiStatusCode::NoMessages,
+            17 => MapiStatusCode::InvalidMessage,
+            18 => MapiStatusCode::TextTooLarge,
+            19 => MapiStatusCode::InvalidSession,
+            20 => MapiStatusCode::TypeNotSupported,
+            21 => MapiStatusCode::AmbiguousRecipient,
+            22 => MapiStatusCode::MessageInUse,
+            23 => MapiStatusCode::NetworkFailure,
+            24 => MapiStatusCode::InvalidEditfields,
+            25 => MapiStatusCode::InvalidRecips,
+            26 => MapiStatusCode::NotSupported,
+            _ => MapiStatusCode::Failure,
+        }
+    }
+}
+
 bitflags! {
     /// MAPILogon() flags
     #[repr(C)]
@@ -0,0 +1,444 @@
+use std::io;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::commands::SendMode;
+use crate::flags::MapiStatusCode;
+use crate::logger;
+use crate::manifest::Manifest;
+use crate::structs::Message;
+use crate::transport::Transport;
+use crate::types::ULong;
+
+#[cfg(windows)]
+mod pipe;
+
+/// the pipe a running client listens on
+const PIPE_NAME: &str = r"\\.\pipe\tutanota-mapi";
+
+/// frames bigger than this are refused. attachments are sent as paths,
+/// so even large messages stay far below it.
+const MAX_FRAME_LENGTH: usize = 16 * 1024 * 1024;
+
+/// how long we wait for the client to answer before giving up on it
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// what we send to the client, as a single frame
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IpcRequest {
+    pub mode: SendMode,
+    pub manifest: Manifest,
+}
+
+/// what the client answers with, as a single frame
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IpcResponse {
+    /// a MAPI status code, 0 (SUCCESS_SUCCESS) if the client took the message
+    pub status: ULong,
+    /// why the client didn't take the message, only for the log
+    #[serde(default)]
+    pub reason: Option<String>,
+}
+
+/// sends the message to an already running client. if nobody is listening,
+/// the fallback (usually one that starts the client) is used instead.
+#[derive(Debug)]
+pub struct IpcTransport {
+    endpoint: PathBuf,
+    fallback: Box<dyn Transport>,
+    timeout: Duration,
+}
+
+impl IpcTransport {
+    pub fn new(fallback: Box<dyn Transport>) -> Self {
+        Self::at(PIPE_NAME.into(), fallback)
+    }
+
+    fn at(endpoint: PathBuf, fallback: Box<dyn Transport>) -> Self {
+        IpcTransport {
+            endpoint,
+            fallback,
+            timeout: RESPONSE_TIMEOUT,
+        }
+    }
+}
+
+impl Transport for IpcTransport {
+    fn deliver(&self, msg: &Message, mode: SendMode) -> Result<(), MapiStatusCode> {
+        let mut connection = match connect(&self.endpoint, self.timeout) {
+            Ok(connection) => connection,
+            Err(e) => {
+                logger::info(
+                    "ipc",
+                    &format!(
+                        "no client listening on {:?} ({:?}), using {:?}",
+                        self.endpoint, e, self.fallback
+                    ),
+                );
+                return self.fallback.deliver(msg, mode);
+            }
+        };
+        let request = IpcRequest {
+            mode,
+            manifest: msg.to_manifest()?,
+        };
+        // once we're connected, the client may already have the message. falling
+        // back now could send it twice, so errors are reported instead.
+        let response = exchange(&mut connection, &request).map_err(|e| {
+            logger::error("ipc", &format!("could not talk to the client: {:?}", e));
+            MapiStatusCode::Failure
+        })?;
+        match MapiStatusCode::from(response.status) {
+            MapiStatusCode::Success => {
+                logger::info("ipc", "client accepted the message");
+                Ok(())
+            }
+            status => {
+                logger::warn(
+                    "ipc",
+                    &format!(
+                        "client rejected the message with {:?}: {:?}",
+                        status, response.reason
+                    ),
+                );
+                Err(status)
+            }
+        }
+    }
+}
+
+/// the client end of the named pipe, which gives up on reads and writes after timeout
+#[cfg(windows)]
+fn connect(endpoint: &Path, timeout: Duration) -> io::Result<pipe::Pipe> {
+    pipe::Pipe::connect(endpoint, timeout)
+}
+
+/// a unix socket, so the transport can be tested against a stand-in client
+#[cfg(unix)]
+fn connect(endpoint: &Path, timeout: Duration) -> io::Result<std::os::unix::net::UnixStream> {
+    let stream = std::os::unix::net::UnixStream::connect(endpoint)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    Ok(stream)
+}
+
+/// send the request and wait for the response
+fn exchange<C: Read + Write>(connection: &mut C, request: &IpcRequest) -> io::Result<IpcResponse> {
+    write_frame(connection, &serde_json::to_vec(request)?)?;
+    let response = read_frame(connection)?;
+    Ok(serde_json::from_slice(&response)?)
+}
+
+/// a frame is the length of the payload as a big endian u32, followed by the payload
+fn write_frame<W: Write>(writer: &mut W, payload: &[u8]) -> io::Result<()> {
+    if payload.len() > MAX_FRAME_LENGTH {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("frame too long: {} bytes", payload.len()),
+        ));
+    }
+    writer.write_all(&(payload.len() as u32).to_be_bytes())?;
+    writer.write_all(payload)?;
+    writer.flush()
+}
+
+fn read_frame<R: Read>(reader: &mut R) -> io::Result<Vec<u8>> {
+    let mut length = [0u8; 4];
+    reader.read_exact(&mut length)?;
+    let length = u32::from_be_bytes(length) as usize;
+    if length > MAX_FRAME_LENGTH {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("frame too long: {} bytes", length),
+        ));
+    }
+    let mut payload = vec![0u8; length];
+    reader.read_exact(&mut payload)?;
+    Ok(payload)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::io::Cursor;
+
+    use crate::ipc::{read_frame, write_frame, MAX_FRAME_LENGTH};
+
+    #[test]
+    fn frames_round_trip() {
+        let mut buf = vec![];
+        write_frame(&mut buf, b"hello").unwrap();
+        write_frame(&mut buf, b"").unwrap();
+        assert_eq!(buf[..4], [0, 0, 0, 5]);
+
+        let mut reader = Cursor::new(buf);
+        assert_eq!(read_frame(&mut reader).unwrap(), b"hello");
+        assert_eq!(read_frame(&mut reader).unwrap(), b"");
+        let eof = read_frame(&mut reader).unwrap_err();
+        assert_eq!(eof.kind(), io::ErrorKind::UnexpectedEof);
+
+        let truncated = read_frame(&mut Cursor::new(vec![0, 0, 0, 5, b'h'])).unwrap_err();
+        assert_eq!(truncated.kind(), io::ErrorKind::UnexpectedEof);
+        let too_long = read_frame(&mut Cursor::new(vec![0xff, 0xff, 0xff, 0xff])).unwrap_err();
+        assert_eq!(too_long.kind(), io::ErrorKind::InvalidData);
+        let too_long = vec![0u8; MAX_FRAME_LENGTH + 1];
+        assert!(write_frame(&mut vec![], &too_long).is_err());
+    }
+
+    /// a stand-in for the client that answers a single request
+    mod client {
+        use std::io::{Read, Write};
+        use std::path::PathBuf;
+        use std::thread::JoinHandle;
+        use std::time::Duration;
+
+        use crate::ipc::{read_frame, write_frame, IpcRequest};
+
+        /// take the request and send response, returns the request
+        fn answer<S: Read + Write>(stream: &mut S, response: &[u8]) -> IpcRequest {
+            let request = read_frame(stream).unwrap();
+            let request = serde_json::from_slice(&request).unwrap();
+            if response.starts_with(b"{") {
+                write_frame(stream, response).unwrap();
+            } else {
+                stream.write_all(response).unwrap();
+            }
+            request
+        }
+
+        /// take the request and never answer
+        fn ignore_request<S: Read + Write>(stream: &mut S) {
+            let _ = read_frame(stream);
+            std::thread::sleep(Duration::from_secs(5));
+        }
+
+        #[cfg(unix)]
+        mod platform {
+            use std::os::unix::net::{UnixListener, UnixStream};
+            use std::path::{Path, PathBuf};
+
+            /// a socket named after the test
+            pub fn endpoint(name: &str) -> PathBuf {
+                let dir = std::env::temp_dir().join("mapirs_test").join("ipc");
+                std::fs::create_dir_all(&dir).unwrap();
+                let endpoint = dir.join(name);
+                let _ = std::fs::remove_file(&endpoint);
+                endpoint
+            }
+
+            pub fn listen(endpoint: &Path) -> UnixListener {
+                UnixListener::bind(endpoint).unwrap()
+            }
+
+            pub fn accept(listener: UnixListener) -> UnixStream {
+                listener.accept().unwrap().0
+            }
+
+            /// nothing to do, what's written to a socket stays readable after it's closed
+            pub fn flush(_stream: &UnixStream) {}
+        }
+
+        /// the server end of a named pipe, like the client creates it
+        #[cfg(windows)]
+        mod platform {
+            use std::fs::File;
+            use std::os::windows::ffi::OsStrExt;
+            use std::os::windows::io::{AsRawHandle, FromRawHandle};
+            use std::path::{Path, PathBuf};
+
+            use winapi::shared::winerror::ERROR_PIPE_CONNECTED;
+            use winapi::um::handleapi::INVALID_HANDLE_VALUE;
+            use winapi::um::namedpipeapi::{
+                ConnectNamedPipe, CreateNamedPipeW, DisconnectNamedPipe,
+            };
+            use winapi::um::winbase::{
+                PIPE_ACCESS_DUPLEX, PIPE_READMODE_BYTE, PIPE_TYPE_BYTE, PIPE_WAIT,
+            };
+
+            /// a pipe named after the test
+            pub fn endpoint(name: &str) -> PathBuf {
+                PathBuf::from(format!(r"\\.\pipe\mapirs-test-{}", name))
+            }
+
+            /// a single instance of the pipe, so a second caller finds it busy
+            pub fn listen(endpoint: &Path) -> File {
+                let wide: Vec<u16> = endpoint.as_os_str().encode_wide().chain(Some(0)).collect();
+                // SAFETY: wide is null terminated and outlives the call, the handle is checked
+                let handle = unsafe {
+                    CreateNamedPipeW(
+                        wide.as_ptr(),
+                        PIPE_ACCESS_DUPLEX,
+                        PIPE_TYPE_BYTE | PIPE_READMODE_BYTE | PIPE_WAIT,
+                        1,
+                        4096,
+                        4096,
+                        0,
+                        std::ptr::null_mut(),
+                    )
+                };
+                assert_ne!(handle, INVALID_HANDLE_VALUE);
+                // SAFETY: the handle is ours, the File closes it
+                unsafe { File::from_raw_handle(handle as _) }
+            }
+
+            pub fn accept(pipe: File) -> File {
+                // SAFETY: the handle is valid as long as pipe is
+                if unsafe { ConnectNamedPipe(pipe.as_raw_handle() as _, std::ptr::null_mut()) } == 0
+                {
+                    // the caller may have connected before we started waiting for it
+                    let e = std::io::Error::last_os_error();
+                    assert_eq!(e.raw_os_error(), Some(ERROR_PIPE_CONNECTED as i32));
+                }
+                pipe
+            }
+
+            /// wait until the caller read everything, closing the pipe before may discard it
+            pub fn flush(pipe: &File) {
+                let _ = pipe.sync_all();
+            }
+
+            /// drop the caller we're connected to, so the instance can take the next one
+            pub fn disconnect(pipe: &File) {
+                // SAFETY: the handle is valid as long as pipe is
+                assert_ne!(unsafe { DisconnectNamedPipe(pipe.as_raw_handle() as _) }, 0);
+            }
+        }
+
+        /// listen on an endpoint named after the test. the thread returns the request it got.
+        pub fn serve(name: &str, response: &'static [u8]) -> (PathBuf, JoinHandle<IpcRequest>) {
+            let endpoint = platform::endpoint(name);
+            let listener = platform::listen(&endpoint);
+            let handle = std::thread::spawn(move || {
+                let mut stream = platform::accept(listener);
+                let request = answer(&mut stream, response);
+                platform::flush(&stream);
+                request
+            });
+            (endpoint, handle)
+        }
+
+        /// listen on an endpoint named after the test, take the request and never answer
+        pub fn ignore(name: &str) -> PathBuf {
+            let endpoint = platform::endpoint(name);
+            let listener = platform::listen(&endpoint);
+            std::thread::spawn(move || ignore_request(&mut platform::accept(listener)));
+            endpoint
+        }
+
+        /// an endpoint nobody listens on
+        pub fn nobody(name: &str) -> PathBuf {
+            platform::endpoint(name)
+        }
+
+        /// like serve, but the only instance of the pipe is taken by another caller
+        /// for a while, like when the client is busy
+        #[cfg(windows)]
+        pub fn serve_busy(
+            name: &str,
+            response: &'static [u8],
+        ) -> (PathBuf, JoinHandle<IpcRequest>) {
+            let endpoint = platform::endpoint(name);
+            let pipe = platform::listen(&endpoint);
+            let other = std::fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(&endpoint)
+                .unwrap();
+            let handle = std::thread::spawn(move || {
+                let pipe = platform::accept(pipe);
+                std::thread::sleep(Duration::from_millis(200));
+                platform::disconnect(&pipe);
+                drop(other);
+                let mut stream = platform::accept(pipe);
+                let request = answer(&mut stream, response);
+                platform::flush(&stream);
+                request
+            });
+            (endpoint, handle)
+        }
+    }
+
+    mod transport {
+        use crate::commands::SendMode;
+        use crate::flags::MapiStatusCode;
+        use crate::ipc::tests::client::{ignore, nobody, serve};
+        use crate::ipc::IpcTransport;
+        use crate::structs::Message;
+        use crate::transport::{RecordingTransport, Transport};
+
+        fn deliver(endpoint: std::path::PathBuf) -> Result<(), MapiStatusCode> {
+            let msg = Message::new(vec!["a@b.de"], Some("hi"), None, vec![]);
+            IpcTransport::at(endpoint, Box::new(RecordingTransport))
+                .deliver(&msg, SendMode::Unattended)
+        }
+
+        #[test]
+        fn accepted_message_is_delivered() {
+            let (endpoint, client) = serve("accepted", br#"{"status":0}"#);
+            assert_eq!(deliver(endpoint), Ok(()));
+            let request = client.join().unwrap();
+            assert_eq!(request.mode, SendMode::Unattended);
+            assert_eq!(request.manifest.body, Some("hi".to_owned()));
+            assert_eq!(
+                request.manifest.recipients[0].address,
+                Some("a@b.de".to_owned())
+            );
+            assert!(RecordingTransport::take().is_empty());
+        }
+
+        #[test]
+        fn rejection_is_reported() {
+            let (endpoint, client) = serve(
+                "rejected",
+                br#"{"status":1,"reason":"user closed the composer"}"#,
+            );
+            assert_eq!(deliver(endpoint), Err(MapiStatusCode::UserAbort));
+            client.join().unwrap();
+
+            let (endpoint, client) = serve("unknown_status", br#"{"status":4711}"#);
+            assert_eq!(deliver(endpoint), Err(MapiStatusCode::Failure));
+            client.join().unwrap();
+
+            let (endpoint, client) = serve("garbage", b"\0\0\0\x05hel");
+            assert_eq!(deliver(endpoint), Err(MapiStatusCode::Failure));
+            client.join().unwrap();
+            assert!(RecordingTransport::take().is_empty());
+        }
+
+        #[test]
+        fn silent_client_times_out() {
+            let msg = Message::new(vec!["a@b.de"], Some("hi"), None, vec![]);
+            let mut transport = IpcTransport::at(ignore("silent"), Box::new(RecordingTransport));
+            transport.timeout = std::time::Duration::from_millis(100);
+            let started = std::time::Instant::now();
+            assert_eq!(
+                transport.deliver(&msg, SendMode::Unattended),
+                Err(MapiStatusCode::Failure)
+            );
+            assert!(started.elapsed() < std::time::Duration::from_secs(5));
+            assert!(RecordingTransport::take().is_empty());
+        }
+
+        #[cfg(windows)]
+        #[test]
+        fn busy_client_is_waited_for() {
+            let (endpoint, client) =
+                crate::ipc::tests::client::serve_busy("busy", br#"{"status":0}"#);
+            assert_eq!(deliver(endpoint), Ok(()));
+            client.join().unwrap();
+            // waited for the client instead of starting another one
+            assert!(RecordingTransport::take().is_empty());
+        }
+
+        #[test]
+        fn falls_back_without_client() {
+            assert_eq!(deliver(nobody("nobody")), Ok(()));
+            let deliveries = RecordingTransport::take();
+            assert_eq!(deliveries.len(), 1);
+            assert_eq!(deliveries[0].mode, SendMode::Unattended);
+        }
+    }
+}
@@ -0,0 +1,164 @@
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{Read, Write};
+use std::mem;
+use std::os::windows::ffi::OsStrExt;
+use std::os::windows::fs::OpenOptionsExt;
+use std::os::windows::io::AsRawHandle;
+use std::path::Path;
+use std::ptr;
+use std::time::{Duration, Instant};
+
+use winapi::shared::minwindef::{BOOL, DWORD, FALSE, LPCVOID, LPVOID, TRUE};
+use winapi::shared::winerror::{
+    ERROR_BROKEN_PIPE, ERROR_IO_PENDING, ERROR_OPERATION_ABORTED, ERROR_PIPE_BUSY,
+};
+use winapi::um::fileapi::{ReadFile, WriteFile};
+use winapi::um::handleapi::CloseHandle;
+use winapi::um::ioapiset::{CancelIoEx, GetOverlappedResult};
+use winapi::um::minwinbase::{LPOVERLAPPED, OVERLAPPED};
+use winapi::um::namedpipeapi::WaitNamedPipeW;
+use winapi::um::synchapi::{CreateEventW, WaitForSingleObject};
+use winapi::um::winbase::{FILE_FLAG_OVERLAPPED, INFINITE, WAIT_OBJECT_0};
+use winapi::um::winnt::HANDLE;
+
+/// how long we wait for a free instance of the pipe while the client is busy with
+/// another caller. falling back after that may start a second client.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// the client end of a named pipe. it's opened for overlapped I/O, so a read or
+/// write that's still going on at the deadline can be cancelled instead of
+/// blocking the calling app.
+pub struct Pipe {
+    file: File,
+    /// signalled when the current read or write is done
+    event: HANDLE,
+    deadline: Instant,
+}
+
+impl Pipe {
+    /// open the pipe at name, see open. reads and writes fail with TimedOut
+    /// once timeout has passed.
+    pub fn connect(name: &Path, timeout: Duration) -> io::Result<Self> {
+        let file = open(name)?;
+        // SAFETY: a manual reset event without a name or security attributes, checked below
+        let event = unsafe { CreateEventW(ptr::null_mut(), TRUE, FALSE, ptr::null()) };
+        if event.is_null() {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Pipe {
+            file,
+            event,
+            deadline: Instant::now() + timeout,
+        })
+    }
+
+    /// start a read or write with start and wait for it until the deadline.
+    /// returns the number of bytes that were transferred.
+    fn overlapped(
+        &mut self,
+        start: impl FnOnce(HANDLE, LPOVERLAPPED) -> BOOL,
+    ) -> io::Result<usize> {
+        let handle = self.file.as_raw_handle() as HANDLE;
+        // SAFETY: OVERLAPPED is plain data that has to start out zeroed
+        let mut overlapped: OVERLAPPED = unsafe { mem::zeroed() };
+        overlapped.hEvent = self.event;
+        if start(handle, &mut overlapped) == FALSE {
+            let e = io::Error::last_os_error();
+            if e.raw_os_error() != Some(ERROR_IO_PENDING as i32) {
+                return Err(e);
+            }
+        }
+        let remaining = self.deadline.saturating_duration_since(Instant::now());
+        // SAFETY: the event is valid until we're dropped
+        if unsafe { WaitForSingleObject(self.event, millis(remaining)) } != WAIT_OBJECT_0 {
+            // SAFETY: overlapped belongs to the operation we started on handle
+            unsafe { CancelIoEx(handle, &mut overlapped) };
+        }
+        let mut transferred: DWORD = 0;
+        // SAFETY: this waits until the operation is over, also if it was cancelled,
+        // so neither overlapped nor the buffer are used once we return
+        if unsafe { GetOverlappedResult(handle, &mut overlapped, &mut transferred, TRUE) } == FALSE
+        {
+            let e = io::Error::last_os_error();
+            if e.raw_os_error() == Some(ERROR_OPERATION_ABORTED as i32) {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "the client didn't answer in time",
+                ));
+            }
+            return Err(e);
+        }
+        Ok(transferred as usize)
+    }
+}
+
+impl Read for Pipe {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = buf.len().min(DWORD::MAX as usize) as DWORD;
+        let buf = buf.as_mut_ptr() as LPVOID;
+        // SAFETY: buf is valid for len bytes until overlapped returns
+        let read = self.overlapped(|handle, overlapped| unsafe {
+            ReadFile(handle, buf, len, ptr::null_mut(), overlapped)
+        });
+        match read {
+            // the client closed its end
+            Err(e) if e.raw_os_error() == Some(ERROR_BROKEN_PIPE as i32) => Ok(0),
+            read => read,
+        }
+    }
+}
+
+impl Write for Pipe {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = buf.len().min(DWORD::MAX as usize) as DWORD;
+        let buf = buf.as_ptr() as LPCVOID;
+        // SAFETY: buf is valid for len bytes until overlapped returns
+        self.overlapped(|handle, overlapped| unsafe {
+            WriteFile(handle, buf, len, ptr::null_mut(), overlapped)
+        })
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for Pipe {
+    fn drop(&mut self) {
+        // SAFETY: we created the event and nothing uses it anymore
+        unsafe { CloseHandle(self.event) };
+    }
+}
+
+/// open the pipe at name, waiting for a free instance if they're all taken
+fn open(name: &Path) -> io::Result<File> {
+    let deadline = Instant::now() + BUSY_TIMEOUT;
+    loop {
+        let opened = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(FILE_FLAG_OVERLAPPED)
+            .open(name);
+        let busy = match opened {
+            Err(e) if e.raw_os_error() == Some(ERROR_PIPE_BUSY as i32) => e,
+            opened => return opened,
+        };
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(busy);
+        }
+        let wide: Vec<u16> = name.as_os_str().encode_wide().chain(Some(0)).collect();
+        // SAFETY: wide is null terminated and outlives the call. 0 would mean the
+        // default timeout of the pipe, so we wait at least a millisecond.
+        if unsafe { WaitNamedPipeW(wide.as_ptr(), millis(remaining).max(1)) } == FALSE {
+            return Err(busy);
+        }
+        // another caller may still take the free instance first, so we try again
+    }
+}
+
+/// a duration as the milliseconds the wait functions take, INFINITE is left out
+fn millis(duration: Duration) -> DWORD {
+    duration.as_millis().min((INFINITE - 1) as u128) as DWORD
+}
@@ -1,12 +1,15 @@
 #[macro_use]
 extern crate bitflags;
 extern crate directories;
+extern crate serde;
+extern crate serde_json;
 extern crate urlencoding;
+#[cfg(windows)]
 extern crate winreg;
 
 pub use crate::ffi::{
     MAPIAddress, MAPIDeleteMail, MAPIDetails, MAPIFindNext, MAPIFreeBuffer, MAPILogoff, MAPILogon,
-    MAPIReadMail, MAPIResolveName, MAPISaveMail, MAPISendDocuments, MAPISendMail,
+    MAPIReadMail, MAPIResolveName, MAPISaveMail, MAPISendDocuments, MAPISendMail, MAPISendMailW,
 };
 
 // type aliases to centrally define C <-> Rust type conversions
@@ -23,3 +26,31 @@ mod environment;
 mod ffi;
 // path with a file_name() method that's guaranteed to return a value
 mod file_path;
+// keeps track of the session handles handed out by MAPILogon
+mod session;
+// reads and searches the contacts exported by the client
+mod contacts;
+// keeps track of the memory we hand to the calling app until it's freed with MAPIFreeBuffer
+mod allocation;
+// rendering messages as RFC 5322 / MIME files
+mod mime;
+// the directories of .eml files we keep messages in
+mod store;
+// normalizing and validating the addresses apps hand us
+mod address;
+// building mailto: links for the client
+mod mailto;
+// keeps control characters and URI delimiters out of what we hand to the client
+mod sanitize;
+// the JSON description of a message the client can be started with instead of a mailto link
+mod manifest;
+// the ways a message can get to the client (starting it with a link or a file, ...)
+mod transport;
+// handing messages to a client that's already running over a named pipe
+mod ipc;
+// the typed settings for the current user, loaded once
+mod settings;
+// removing what we put into TMPPath once it's old enough
+mod retention;
+// the log writer, kept open for the lifetime of the process
+mod logger;
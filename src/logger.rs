@@ -0,0 +1,743 @@
+use std::ffi::OsString;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime};
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde_json::json;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+
+use crate::environment;
+use crate::settings;
+use crate::settings::{LogFormat, LogLevel};
+
+const LOG_FILE_NAME: &str = "mapi.log";
+/// held while rotating, so processes don't rotate the same log at the same time
+const LOCK_FILE_NAME: &str = "mapi.log.lock";
+/// how often a sink looks at mapi.log to notice what other processes did to it
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// where log lines go
+pub trait Sink {
+    fn write_line(&mut self, line: &str) -> io::Result<()>;
+    /// what went wrong in the sink since the last call, like a failed rotation.
+    /// it's logged through the sink, which may well still be able to write.
+    fn take_problems(&mut self) -> Vec<String> {
+        vec![]
+    }
+}
+
+/// when mapi.log is moved aside and what happens to the logs rotated before
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rotation {
+    /// rotate once the log is this big
+    pub max_size: Option<u64>,
+    /// rotate if the log wasn't written to for this long
+    pub max_age: Option<Duration>,
+    /// how many rotated logs are kept, mapi.1.log is the newest
+    pub generations: u32,
+    /// gzip the rotated logs. mapi.1.log stays as it is because other processes
+    /// may still be writing to it until they notice the rotation.
+    pub compress: bool,
+}
+
+impl Rotation {
+    pub fn from_settings(settings: &settings::Settings) -> Self {
+        Rotation {
+            max_size: settings.log_max_size,
+            max_age: settings.log_max_age,
+            generations: settings.log_generations,
+            compress: settings.log_compress,
+        }
+    }
+
+    fn is_due(&self, log: &LogState, now: SystemTime) -> bool {
+        let too_big = self.max_size.is_some_and(|max| log.size >= max);
+        let too_old = self.max_age.is_some_and(|max| {
+            log.modified
+                .and_then(|modified| now.duration_since(modified).ok())
+                .is_some_and(|age| age >= max)
+        });
+        too_big || too_old
+    }
+}
+
+/// what a sink knows about mapi.log without asking the file system. other
+/// processes append as well, so the size is only a lower bound.
+#[derive(Debug, Clone, Copy)]
+struct LogState {
+    size: u64,
+    modified: Option<SystemTime>,
+    /// when we last looked at the file
+    checked: SystemTime,
+}
+
+impl LogState {
+    fn of(log: &fs::Metadata, now: SystemTime) -> Self {
+        LogState {
+            size: log.len(),
+            modified: log.modified().ok(),
+            checked: now,
+        }
+    }
+
+    /// a log we're about to create
+    fn empty(now: SystemTime) -> Self {
+        LogState {
+            size: 0,
+            modified: None,
+            checked: now,
+        }
+    }
+}
+
+/// appends to mapi.log in a directory. the file is kept open between lines.
+/// every process that loaded us has its own, they coordinate through the file system.
+pub struct FileSink {
+    dir: PathBuf,
+    rotation: Rotation,
+    file: Option<File>,
+    /// None if we have to look at mapi.log before the next line
+    state: Option<LogState>,
+    problems: Vec<String>,
+    /// a rotation that fails is tried again with every line, but only reported once
+    rotation_failing: bool,
+}
+
+impl FileSink {
+    pub fn new(dir: PathBuf, rotation: Rotation) -> Self {
+        FileSink {
+            dir,
+            rotation,
+            file: None,
+            state: None,
+            problems: vec![],
+            rotation_failing: false,
+        }
+    }
+
+    fn path(&self) -> PathBuf {
+        self.dir.join(LOG_FILE_NAME)
+    }
+
+    /// mapi.<generation>.log, with .gz if it's compressed
+    fn generation_path(&self, generation: u32, compressed: bool) -> PathBuf {
+        let name = format!(
+            "mapi.{}.log{}",
+            generation,
+            if compressed { ".gz" } else { "" }
+        );
+        self.dir.join(name)
+    }
+
+    /// whether to look at mapi.log before the next line: we don't know it, our own
+    /// lines may have made it due or another process may have rotated it by now
+    fn needs_check(&self, now: SystemTime) -> bool {
+        match &self.state {
+            None => true,
+            Some(state) => {
+                self.rotation.is_due(state, now)
+                    || now
+                        .duration_since(state.checked)
+                        .map_or(true, |since| since >= CHECK_INTERVAL)
+            }
+        }
+    }
+
+    /// make sure the next line goes to a current mapi.log
+    fn rotate_if_due(&mut self) {
+        let now = SystemTime::now();
+        if !self.needs_check(now) {
+            return;
+        }
+        let current = match fs::metadata(self.path()) {
+            Ok(current) => current,
+            Err(_) => {
+                // gone, it's recreated when we open it
+                self.file = None;
+                self.state = Some(LogState::empty(now));
+                return;
+            }
+        };
+        // another process may have rotated the log since we opened it
+        let stale = match &self.file {
+            Some(file) => !same_file(file, &self.path()).unwrap_or(false),
+            None => false,
+        };
+        if stale {
+            self.file = None;
+        }
+        let state = LogState::of(&current, now);
+        self.state = Some(state);
+        if !self.rotation.is_due(&state, now) {
+            return;
+        }
+        match self.rotate() {
+            Ok(()) => {
+                self.rotation_failing = false;
+                self.state = Some(LogState::empty(now));
+            }
+            Err(e) => {
+                self.state = None;
+                if !self.rotation_failing {
+                    self.problems
+                        .push(format!("could not rotate logs: {:?}", e));
+                }
+                self.rotation_failing = true;
+            }
+        }
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        let lock = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(self.dir.join(LOCK_FILE_NAME))?;
+        // released when lock is dropped, also if the process dies
+        lock.lock()?;
+        // while we waited for the lock, another process may have rotated already
+        let now = SystemTime::now();
+        let current = LogState::of(&fs::metadata(self.path())?, now);
+        if !self.rotation.is_due(&current, now) {
+            self.file = None;
+            return Ok(());
+        }
+        self.file = None;
+        if self.rotation.generations == 0 {
+            return fs::remove_file(self.path());
+        }
+        self.shift_generations()?;
+        fs::rename(self.path(), self.generation_path(1, false))
+    }
+
+    /// mapi.1.log becomes mapi.2.log and so on, the oldest one is removed
+    fn shift_generations(&mut self) -> io::Result<()> {
+        let oldest = self.rotation.generations;
+        for compressed in [false, true] {
+            remove_if_exists(&self.generation_path(oldest, compressed))?;
+        }
+        for generation in (1..oldest).rev() {
+            for compressed in [false, true] {
+                let from = self.generation_path(generation, compressed);
+                if !from.exists() {
+                    continue;
+                }
+                if self.rotation.compress && !compressed {
+                    let to = self.generation_path(generation + 1, true);
+                    if let Err(e) = compress(&from, &to) {
+                        self.problems
+                            .push(format!("could not compress {:?}: {:?}", from, e));
+                        let _ = fs::remove_file(&to);
+                    } else {
+                        fs::remove_file(&from)?;
+                        continue;
+                    }
+                }
+                fs::rename(&from, self.generation_path(generation + 1, compressed))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn open(&mut self) -> io::Result<&mut File> {
+        if self.file.is_none() {
+            // this may fail if the path is not writable
+            fs::create_dir_all(&self.dir)?;
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(self.path())?;
+            self.file = Some(file);
+        }
+        // unwrap is OK because we just made sure it's there
+        Ok(self.file.as_mut().unwrap())
+    }
+}
+
+impl Sink for FileSink {
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        self.rotate_if_due();
+        let written = self.open().and_then(|file| writeln!(file, "{}", line));
+        match (&written, &mut self.state) {
+            (Ok(()), Some(state)) => state.size += line.len() as u64 + 1,
+            (Ok(()), None) => {}
+            // try again with a new handle next time
+            (Err(_), _) => self.file = None,
+        }
+        written
+    }
+
+    fn take_problems(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.problems)
+    }
+}
+
+/// whether the file we have open is the one at path
+#[cfg(unix)]
+fn same_file(ours: &File, path: &Path) -> io::Result<bool> {
+    use std::os::unix::fs::MetadataExt;
+    let (ours, theirs) = (ours.metadata()?, fs::metadata(path)?);
+    Ok(ours.dev() == theirs.dev() && ours.ino() == theirs.ino())
+}
+
+/// whether the file we have open is the one at path. std has no stable way to
+/// get the file index on windows, so we ask for it ourselves.
+#[cfg(windows)]
+fn same_file(ours: &File, path: &Path) -> io::Result<bool> {
+    let theirs = File::open(path)?;
+    Ok(file_id(ours)? == file_id(&theirs)?)
+}
+
+/// the volume serial number and file index, which identify a file on windows
+#[cfg(windows)]
+fn file_id(file: &File) -> io::Result<(u32, u32, u32)> {
+    use std::os::windows::io::AsRawHandle;
+    use winapi::um::fileapi::{GetFileInformationByHandle, BY_HANDLE_FILE_INFORMATION};
+
+    // SAFETY: BY_HANDLE_FILE_INFORMATION is plain data, all zeroes is a valid value
+    let mut info: BY_HANDLE_FILE_INFORMATION = unsafe { std::mem::zeroed() };
+    // SAFETY: the handle is valid as long as file is borrowed and info outlives the call
+    if unsafe { GetFileInformationByHandle(file.as_raw_handle() as _, &mut info) } == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok((
+        info.dwVolumeSerialNumber,
+        info.nFileIndexHigh,
+        info.nFileIndexLow,
+    ))
+}
+
+fn remove_if_exists(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// gzip the file at from into a new file at to
+fn compress(from: &Path, to: &Path) -> io::Result<()> {
+    let mut source = File::open(from)?;
+    let mut encoder = GzEncoder::new(File::create(to)?, Compression::default());
+    io::copy(&mut source, &mut encoder)?;
+    encoder.finish()?.sync_all()
+}
+
+/// used if we don't know where the log goes. the lines are dropped, the host
+/// application's stderr isn't ours to write to.
+pub struct NullSink;
+
+impl Sink for NullSink {
+    fn write_line(&mut self, _line: &str) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// the sink for the log directory, if we know it
+fn open_sink(log_path: io::Result<OsString>, rotation: Rotation) -> Box<dyn Sink + Send> {
+    match log_path {
+        Ok(dir) => Box::new(FileSink::new(dir.into(), rotation)),
+        Err(_) => Box::new(NullSink),
+    }
+}
+
+/// keeps the lines in memory, for tests
+#[cfg(test)]
+#[derive(Default)]
+pub struct MemorySink {
+    lines: Vec<String>,
+}
+
+#[cfg(test)]
+impl Sink for MemorySink {
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        self.lines.push(line.to_owned());
+        Ok(())
+    }
+}
+
+thread_local! {
+    /// the id of the entry point call running on this thread, if any
+    static CALL_ID: std::cell::RefCell<Option<String>> = const { std::cell::RefCell::new(None) };
+}
+
+/// marks the lines logged while an entry point runs as belonging to the same call.
+/// the previous id is restored when it's dropped, in case entry points call each other.
+pub struct CallScope {
+    previous: Option<String>,
+}
+
+impl Drop for CallScope {
+    fn drop(&mut self) {
+        let previous = self.previous.take();
+        CALL_ID.with(|id| *id.borrow_mut() = previous);
+    }
+}
+
+/// start a call with a new correlation id. the entry point holds on to the
+/// returned scope until it returns.
+pub fn begin_call(caller: &str) -> CallScope {
+    let previous = CALL_ID.with(|id| id.borrow_mut().replace(new_call_id()));
+    let scope = CallScope { previous };
+    debug(caller, "called");
+    scope
+}
+
+/// the correlation id of the current call. it's passed to the client so its
+/// logs can be matched up with ours.
+pub fn call_id() -> Option<String> {
+    CALL_ID.with(|id| id.borrow().clone())
+}
+
+/// unique enough to tell calls apart: the process, when it loaded us and a counter.
+fn new_call_id() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    static LOADED: std::sync::OnceLock<u64> = std::sync::OnceLock::new();
+    let loaded = *LOADED.get_or_init(|| {
+        SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0)
+    });
+    let count = COUNTER.fetch_add(1, Ordering::Relaxed);
+    format!("{:x}-{:x}-{}", std::process::id(), loaded, count)
+}
+
+pub fn error(caller: &str, msg: &str) {
+    log(LogLevel::Error, caller, msg)
+}
+
+pub fn warn(caller: &str, msg: &str) {
+    log(LogLevel::Warn, caller, msg)
+}
+
+pub fn info(caller: &str, msg: &str) {
+    log(LogLevel::Info, caller, msg)
+}
+
+pub fn debug(caller: &str, msg: &str) {
+    log(LogLevel::Debug, caller, msg)
+}
+
+pub fn trace(caller: &str, msg: &str) {
+    log(LogLevel::Trace, caller, msg)
+}
+
+/// log a message if the configured LOGLevel lets it through
+pub fn log(level: LogLevel, caller: &str, msg: &str) {
+    let settings = settings::get();
+    if level == LogLevel::Off || settings.log_level < level {
+        return;
+    }
+    let line = format_line(
+        settings.log_format,
+        level,
+        call_id().as_deref(),
+        caller,
+        msg,
+    );
+    write_line(&line);
+}
+
+fn format_line(
+    format: LogFormat,
+    level: LogLevel,
+    call_id: Option<&str>,
+    caller: &str,
+    msg: &str,
+) -> String {
+    let level = format!("{:?}", level).to_lowercase();
+    match format {
+        LogFormat::Text => format!(
+            "{} | {:<5} | {} | {}: {}",
+            environment::current_time_formatted(),
+            level,
+            call_id.unwrap_or("-"),
+            caller,
+            msg
+        ),
+        LogFormat::Json => json!({
+            "time": OffsetDateTime::now_utc().format(&Rfc3339).unwrap_or_default(),
+            "level": level,
+            "call": call_id,
+            "caller": caller,
+            "message": msg,
+        })
+        .to_string(),
+    }
+}
+
+/// write a line to the log
+pub fn write_line(line: &str) {
+    let problems = with_sink(|sink| {
+        // if this fails, there's nowhere left to report it
+        let _ = sink.write_line(line);
+        sink.take_problems()
+    });
+    // the sink isn't borrowed anymore, so these can go through it
+    for problem in problems {
+        error("logger", &problem);
+    }
+}
+
+/// the sink is set up on first use and kept for the lifetime of the process
+#[cfg(not(test))]
+fn with_sink<T>(f: impl FnOnce(&mut dyn Sink) -> T) -> T {
+    use std::sync::{Mutex, OnceLock, PoisonError};
+    static SINK: OnceLock<Mutex<Box<dyn Sink + Send>>> = OnceLock::new();
+    let sink = SINK.get_or_init(|| {
+        let rotation = Rotation::from_settings(&settings::get());
+        Mutex::new(open_sink(environment::log_path(), rotation))
+    });
+    let mut sink = sink.lock().unwrap_or_else(PoisonError::into_inner);
+    f(sink.as_mut())
+}
+
+/// tests log into memory, per thread
+#[cfg(test)]
+fn with_sink<T>(f: impl FnOnce(&mut dyn Sink) -> T) -> T {
+    TEST_SINK.with(|sink| f(&mut *sink.borrow_mut()))
+}
+
+#[cfg(test)]
+thread_local! {
+    static TEST_SINK: std::cell::RefCell<MemorySink> = std::cell::RefCell::new(MemorySink::default());
+}
+
+/// the lines the tests on this thread logged since the last call
+#[cfg(test)]
+pub fn take_test_log() -> Vec<String> {
+    TEST_SINK.with(|sink| std::mem::take(&mut sink.borrow_mut().lines))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::fs::File;
+    use std::io::Read;
+    use std::path::PathBuf;
+    use std::time::{Duration, SystemTime};
+
+    use flate2::read::GzDecoder;
+
+    use crate::logger::{
+        begin_call, call_id, debug, format_line, info, open_sink, take_test_log, trace, write_line,
+        FileSink, Rotation, Sink, CHECK_INTERVAL,
+    };
+    use crate::settings;
+    use crate::settings::{LogFormat, LogLevel, Settings};
+
+    fn sink_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join("mapirs_test").join(name);
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn rotation(max_size: Option<u64>, generations: u32, compress: bool) -> Rotation {
+        Rotation {
+            max_size,
+            max_age: Some(Duration::from_secs(24 * 60 * 60)),
+            generations,
+            compress,
+        }
+    }
+
+    fn read(path: PathBuf) -> String {
+        fs::read_to_string(path).unwrap()
+    }
+
+    #[test]
+    fn file_sink_works() {
+        let dir = sink_dir("file_sink_works");
+        let mut sink = FileSink::new(dir.clone(), rotation(None, 1, false));
+        sink.write_line("a").unwrap();
+        sink.write_line("b").unwrap();
+        assert_eq!(read(dir.join("mapi.log")), "a\nb\n");
+
+        // a log that wasn't written to for a day is rotated before the next line,
+        // once the sink looks at it again
+        let two_days_ago = SystemTime::now() - Duration::from_secs(2 * 24 * 60 * 60);
+        File::options()
+            .write(true)
+            .open(dir.join("mapi.log"))
+            .unwrap()
+            .set_modified(two_days_ago)
+            .unwrap();
+        sink.state.as_mut().unwrap().checked -= CHECK_INTERVAL;
+        sink.write_line("c").unwrap();
+        assert_eq!(read(dir.join("mapi.log")), "c\n");
+        assert_eq!(read(dir.join("mapi.1.log")), "a\nb\n");
+    }
+
+    #[test]
+    fn generations_are_kept() {
+        let dir = sink_dir("generations_are_kept");
+        let mut sink = FileSink::new(dir.clone(), rotation(Some(4), 3, true));
+        for line in ["one", "two", "three", "four", "five"] {
+            sink.write_line(line).unwrap();
+        }
+        assert_eq!(read(dir.join("mapi.log")), "five\n");
+        // the newest rotated log isn't compressed, the others are
+        assert_eq!(read(dir.join("mapi.1.log")), "four\n");
+        assert!(!dir.join("mapi.2.log").exists());
+        let mut decoder = GzDecoder::new(File::open(dir.join("mapi.2.log.gz")).unwrap());
+        let mut three = String::new();
+        decoder.read_to_string(&mut three).unwrap();
+        assert_eq!(three, "three\n");
+        assert!(dir.join("mapi.3.log.gz").exists());
+        assert!(!dir.join("mapi.4.log.gz").exists());
+
+        let dir = sink_dir("no_generations_are_kept");
+        let mut sink = FileSink::new(dir.clone(), rotation(Some(4), 0, false));
+        sink.write_line("one").unwrap();
+        sink.write_line("two").unwrap();
+        assert_eq!(read(dir.join("mapi.log")), "two\n");
+        assert!(!dir.join("mapi.1.log").exists());
+    }
+
+    #[test]
+    fn rotation_problems_are_reported_once() {
+        let dir = sink_dir("rotation_problems_are_reported_once");
+        // a directory where the rotated log should go can't be replaced
+        fs::create_dir_all(dir.join("mapi.1.log").join("in_the_way")).unwrap();
+        let mut sink = FileSink::new(dir.clone(), rotation(Some(4), 1, false));
+        sink.write_line("one").unwrap();
+        assert!(sink.take_problems().is_empty());
+        sink.write_line("two").unwrap();
+        sink.write_line("three").unwrap();
+        let problems = sink.take_problems();
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].starts_with("could not rotate logs"));
+        assert_eq!(read(dir.join("mapi.log")), "one\ntwo\nthree\n");
+    }
+
+    #[test]
+    fn open_sink_works() {
+        let rotation = Rotation::from_settings(&Settings {
+            log_max_size: Some(4),
+            log_max_age: None,
+            log_generations: 2,
+            log_compress: true,
+            ..Settings::default()
+        });
+        assert_eq!(
+            rotation,
+            Rotation {
+                max_size: Some(4),
+                max_age: None,
+                generations: 2,
+                compress: true,
+            }
+        );
+
+        let dir = sink_dir("open_sink_works");
+        let mut sink = open_sink(Ok(dir.clone().into_os_string()), rotation);
+        sink.write_line("one").unwrap();
+        assert_eq!(read(dir.join("mapi.log")), "one\n");
+
+        // without a log directory, lines are dropped
+        let mut sink = open_sink(Err(std::io::ErrorKind::NotFound.into()), rotation);
+        sink.write_line("one").unwrap();
+        assert!(sink.take_problems().is_empty());
+    }
+
+    #[test]
+    fn sinks_share_the_log() {
+        // like two processes that loaded us, each with its own handle
+        let dir = sink_dir("sinks_share_the_log");
+        let mut first = FileSink::new(dir.clone(), rotation(Some(8), 2, false));
+        let mut second = FileSink::new(dir.clone(), rotation(Some(8), 2, false));
+        first.write_line("1st").unwrap();
+        second.write_line("2nd").unwrap();
+        // the log is too big now, but the first one only counted its own line
+        // and doesn't look at the file again yet
+        first.write_line("3rd").unwrap();
+        // the second one saw the first line when it opened the log, so it rotates
+        second.write_line("4th").unwrap();
+        // the first one notices, it doesn't rotate again or write to the old log
+        first.write_line("5th").unwrap();
+        assert_eq!(read(dir.join("mapi.log")), "4th\n5th\n");
+        assert_eq!(read(dir.join("mapi.1.log")), "1st\n2nd\n3rd\n");
+        assert!(!dir.join("mapi.2.log").exists());
+    }
+
+    #[test]
+    fn test_log_is_kept_in_memory() {
+        write_line("hello");
+        write_line("world");
+        assert_eq!(take_test_log(), vec!["hello", "world"]);
+        assert!(take_test_log().is_empty());
+    }
+
+    #[test]
+    fn levels_are_filtered() {
+        settings::set_test_settings(Settings {
+            log_level: LogLevel::Debug,
+            ..Settings::default()
+        });
+        take_test_log();
+        debug("caller", "shown");
+        trace("caller", "hidden");
+        let log = take_test_log();
+        assert_eq!(log.len(), 1);
+        assert!(log[0].ends_with(" | debug | - | caller: shown"));
+    }
+
+    #[test]
+    fn calls_are_correlated() {
+        settings::set_test_settings(Settings::default());
+        take_test_log();
+        assert_eq!(call_id(), None);
+        let quiet = begin_call("quiet");
+        drop(quiet);
+        // the start of a call is only logged at debug level
+        assert!(take_test_log().is_empty());
+
+        settings::set_test_settings(Settings {
+            log_level: LogLevel::Debug,
+            ..Settings::default()
+        });
+        let outer = begin_call("outer");
+        let outer_id = call_id().unwrap();
+        {
+            let _inner = begin_call("inner");
+            assert_ne!(call_id().unwrap(), outer_id);
+        }
+        assert_eq!(call_id().unwrap(), outer_id);
+        info("outer", "done");
+        drop(outer);
+        assert_eq!(call_id(), None);
+
+        let log = take_test_log();
+        assert_eq!(log.len(), 3);
+        assert!(log[0].ends_with(&format!(" | debug | {} | outer: called", outer_id)));
+        assert!(log[2].ends_with(&format!(" | info  | {} | outer: done", outer_id)));
+    }
+
+    #[test]
+    fn json_lines_work() {
+        let line = format_line(
+            LogFormat::Json,
+            LogLevel::Warn,
+            Some("1-2-3"),
+            "c",
+            "a \"b\"",
+        );
+        let value: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(value["level"], "warn");
+        assert_eq!(value["call"], "1-2-3");
+        assert_eq!(value["caller"], "c");
+        assert_eq!(value["message"], "a \"b\"");
+        assert!(value["time"].is_string());
+
+        let line = format_line(LogFormat::Json, LogLevel::Info, None, "c", "");
+        assert!(line.contains("\"call\":null"));
+    }
+}
@@ -0,0 +1,162 @@
+use urlencoding::encode;
+
+const SCHEME: &str = "mailto:";
+
+/// characters that can stay as they are in an address besides the unreserved ones.
+/// these are the RFC 6068 some-delims without ",", which separates the addresses.
+const ADDRESS_DELIMS: &str = "!$'()*+;:@";
+
+/// build a mailto: URI from the (not yet encoded) addresses for the path
+/// and the header fields, which are already encoded with the functions below.
+/// the "?" is left out if there are no header fields.
+pub fn build(to: &[String], hfields: &[(&str, String)]) -> String {
+    let mut link = format!("{}{}", SCHEME, encode_addresses(to));
+    if !hfields.is_empty() {
+        let hfields: Vec<String> = hfields
+            .iter()
+            .map(|(name, value)| format!("{}={}", name, value))
+            .collect();
+        link.push('?');
+        link.push_str(&hfields.join("&"));
+    }
+    link
+}
+
+/// percent-encode a list of addresses for the path or a to/cc/bcc header field
+pub fn encode_addresses(addresses: &[String]) -> String {
+    addresses
+        .iter()
+        .map(|a| encode_address(a))
+        .collect::<Vec<String>>()
+        .join(",")
+}
+
+/// percent-encode a single address. quoted local parts may contain
+/// anything, including "," "&" "?" and "%", so these are encoded.
+fn encode_address(address: &str) -> String {
+    let mut encoded = String::with_capacity(address.len());
+    for b in address.bytes() {
+        let c = b as char;
+        if c.is_ascii_alphanumeric() || "-._~".contains(c) || ADDRESS_DELIMS.contains(c) {
+            encoded.push(c);
+        } else {
+            encoded.push_str(&format!("%{:02X}", b));
+        }
+    }
+    encoded
+}
+
+/// percent-encode the value of a header field like subject
+pub fn encode_value(value: &str) -> String {
+    encode(value).into_owned()
+}
+
+/// like encode_value, but line breaks become %0D%0A as RFC 6068 requires for the body.
+pub fn encode_body(body: &str) -> String {
+    let body = body
+        .replace("\r\n", "\n")
+        .replace('\r', "\n")
+        .replace('\n', "\r\n");
+    encode_value(&body)
+}
+
+/// the contents of a mailto: URI, as far as we generate them.
+/// only used to check what we generate.
+#[cfg(test)]
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct MailtoLink {
+    pub to: Vec<String>,
+    pub cc: Vec<String>,
+    pub bcc: Vec<String>,
+    pub subject: Option<String>,
+    pub body: Option<String>,
+    pub attach: Vec<String>,
+}
+
+/// parse a mailto: URI. unknown header fields are ignored,
+/// None if it's not a mailto: URI or not correctly encoded.
+#[cfg(test)]
+pub fn parse(link: &str) -> Option<MailtoLink> {
+    let scheme = link.get(..SCHEME.len())?;
+    if !scheme.eq_ignore_ascii_case(SCHEME) {
+        return None;
+    }
+    let rest = &link[SCHEME.len()..];
+    let (to, query) = rest.split_once('?').unwrap_or((rest, ""));
+
+    let mut parsed = MailtoLink {
+        to: decode_addresses(to)?,
+        ..Default::default()
+    };
+    for hfield in query.split('&').filter(|h| !h.is_empty()) {
+        let (name, value) = hfield.split_once('=')?;
+        match decode(name)?.to_lowercase().as_str() {
+            "to" => parsed.to.extend(decode_addresses(value)?),
+            "cc" => parsed.cc.extend(decode_addresses(value)?),
+            "bcc" => parsed.bcc.extend(decode_addresses(value)?),
+            "subject" => parsed.subject = Some(decode(value)?),
+            "body" => parsed.body = Some(decode(value)?),
+            "attach" => parsed.attach.push(decode(value)?),
+            _ => {}
+        }
+    }
+    Some(parsed)
+}
+
+#[cfg(test)]
+fn decode_addresses(addresses: &str) -> Option<Vec<String>> {
+    addresses
+        .split(',')
+        .filter(|a| !a.is_empty())
+        .map(decode)
+        .collect()
+}
+
+#[cfg(test)]
+fn decode(s: &str) -> Option<String> {
+    urlencoding::decode(s).ok().map(|d| d.into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mailto::{build, encode_body, parse, MailtoLink};
+
+    #[test]
+    fn build_encodes_addresses() {
+        assert_eq!(build(&[], &[]), "mailto:");
+        assert_eq!(
+            build(&["a@b.de".to_owned(), "c+d@e.de".to_owned()], &[]),
+            "mailto:a@b.de,c+d@e.de"
+        );
+        assert_eq!(
+            build(
+                &["\"a&b?c%d,e f\"@x.de".to_owned(), "jörg@b.de".to_owned()],
+                &[("cc", "x".to_owned())]
+            ),
+            "mailto:%22a%26b%3Fc%25d%2Ce%20f%22@x.de,j%C3%B6rg@b.de?cc=x"
+        );
+    }
+
+    #[test]
+    fn encode_body_uses_crlf() {
+        assert_eq!(encode_body("a\nb\r\nc\rd"), "a%0D%0Ab%0D%0Ac%0D%0Ad");
+    }
+
+    #[test]
+    fn parse_works() {
+        assert_eq!(parse("http://a@b.de"), None);
+        assert_eq!(parse("mailto:a@b.de?subject=%FF"), None);
+        assert_eq!(parse("mailto:a@b.de?subject"), None);
+        assert_eq!(
+            parse("MAILTO:%22a%2Cb%22@x.de,c@d.de?To=e@f.de&cc=g@h.de,i@j.de&bcc=k@l.de&subject=a%26b&body=c%0D%0Ad&attach=x&attach=y&in-reply-to=z"),
+            Some(MailtoLink {
+                to: vec!["\"a,b\"@x.de".to_owned(), "c@d.de".to_owned(), "e@f.de".to_owned()],
+                cc: vec!["g@h.de".to_owned(), "i@j.de".to_owned()],
+                bcc: vec!["k@l.de".to_owned()],
+                subject: Some("a&b".to_owned()),
+                body: Some("c\r\nd".to_owned()),
+                attach: vec!["x".to_owned(), "y".to_owned()],
+            })
+        );
+    }
+}
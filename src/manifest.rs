@@ -0,0 +1,228 @@
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::environment;
+use crate::flags::MapiRecipClass;
+use crate::logger;
+use crate::store;
+use crate::types::ULong;
+
+/// only changes if the meaning of existing fields changes. new fields can be added
+/// without changing it, readers ignore the fields they don't know.
+pub const MANIFEST_VERSION: u32 = 1;
+
+/// the client opens the manifest TMPPath\manifests\<id>.json for this url + <id>
+const MANIFEST_URL_PREFIX: &str = "tutanota://mapi/";
+
+/// the directory in TMPPath the manifests are written to
+pub const MANIFEST_DIR: &str = "manifests";
+const MANIFEST_EXTENSION: &str = "json";
+
+/// a structured description of a message for the client, as an alternative to the mailto link
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub version: u32,
+    pub subject: Option<String>,
+    pub body: Option<String>,
+    pub originator: Option<ManifestRecipient>,
+    pub recipients: Vec<ManifestRecipient>,
+    pub conversation_id: Option<String>,
+    /// the MAPI message flags (MAPI_UNREAD, MAPI_RECEIPT_REQUESTED, MAPI_SENT)
+    pub flags: ULong,
+    pub attachments: Vec<ManifestAttachment>,
+    pub caller: Caller,
+    /// the correlation id of the MAPI call in our log, for matching it up with the client's
+    #[serde(default)]
+    pub call_id: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestRecipient {
+    pub class: MapiRecipClass,
+    pub name: String,
+    pub address: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestAttachment {
+    /// the name the attachment should have, which isn't necessarily the name of the file
+    pub name: String,
+    /// the file to attach. this is our copy in TMPPath, not the caller's file.
+    pub path: String,
+    pub size: u64,
+    /// hex encoded SHA-256 of the contents
+    pub sha256: String,
+}
+
+impl ManifestAttachment {
+    /// describe an attachment, reading the size and hash from source
+    pub fn new(name: String, path: String, source: &Path) -> io::Result<Self> {
+        let mut file = File::open(source)?;
+        let mut sha256 = Sha256::new();
+        let size = io::copy(&mut file, &mut sha256)?;
+        let sha256 = sha256
+            .finalize()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        Ok(ManifestAttachment {
+            name,
+            path,
+            size,
+            sha256,
+        })
+    }
+}
+
+/// the process that called us. the dll is loaded into it, so that's us.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Caller {
+    pub pid: u32,
+    pub exe: Option<String>,
+}
+
+impl Caller {
+    pub fn current() -> Self {
+        Caller {
+            pid: std::process::id(),
+            exe: std::env::current_exe()
+                .ok()
+                .map(|exe| exe.to_string_lossy().into_owned()),
+        }
+    }
+}
+
+/// write a manifest into the manifest directory in TMPPath and return its id
+pub fn write(manifest: &Manifest) -> io::Result<String> {
+    let mut dir: PathBuf = environment::tmp_path()?.into();
+    dir.push(MANIFEST_DIR);
+    write_to(&dir, manifest)
+}
+
+fn write_to(dir: &Path, manifest: &Manifest) -> io::Result<String> {
+    std::fs::create_dir_all(dir)?;
+    let id = store::new_id();
+    let path = dir.join(&id).with_extension(MANIFEST_EXTENSION);
+    let contents = serde_json::to_vec_pretty(manifest)?;
+    create_private(&path)?.write_all(&contents)?;
+    logger::debug("manifest::write", &format!("wrote manifest {}", id));
+    Ok(id)
+}
+
+/// the url the client is started with to open the manifest with this id
+pub fn url(id: &str) -> String {
+    format!("{}{}", MANIFEST_URL_PREFIX, id)
+}
+
+/// create a new file only the current user can read. TMPPath is in the user's profile on
+/// windows, the file inherits its ACL, which already keeps other users out.
+fn create_private(path: &Path) -> io::Result<File> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use crate::flags::MapiRecipClass;
+    use crate::manifest::{
+        url, write_to, Caller, Manifest, ManifestAttachment, ManifestRecipient, MANIFEST_VERSION,
+    };
+
+    fn manifest() -> Manifest {
+        Manifest {
+            version: MANIFEST_VERSION,
+            subject: Some("hi".to_owned()),
+            body: None,
+            originator: None,
+            recipients: vec![ManifestRecipient {
+                class: MapiRecipClass::Cc,
+                name: "Jane".to_owned(),
+                address: Some("jane@b.de".to_owned()),
+            }],
+            conversation_id: Some("42".to_owned()),
+            flags: 0,
+            attachments: vec![],
+            caller: Caller {
+                pid: 1234,
+                exe: Some("C:\\app.exe".to_owned()),
+            },
+            call_id: Some("1-2-3".to_owned()),
+        }
+    }
+
+    #[test]
+    fn manifest_round_trips() {
+        let json = serde_json::to_string(&manifest()).unwrap();
+        assert!(json.contains("\"class\":\"cc\""));
+        assert_eq!(serde_json::from_str::<Manifest>(&json).unwrap(), manifest());
+    }
+
+    #[test]
+    fn unknown_fields_are_ignored() {
+        let mut json = serde_json::to_value(manifest()).unwrap();
+        json["priority"] = serde_json::json!("high");
+        json["recipients"][0]["type"] = serde_json::json!("smtp");
+        assert_eq!(
+            serde_json::from_value::<Manifest>(json).unwrap(),
+            manifest()
+        );
+    }
+
+    #[test]
+    fn attachment_is_described() {
+        let dir = std::env::temp_dir()
+            .join("mapirs_test")
+            .join("attachment_is_described");
+        fs::create_dir_all(&dir).unwrap();
+        let source = dir.join("hello.txt");
+        fs::write(&source, b"hello").unwrap();
+
+        let attachment =
+            ManifestAttachment::new("a.txt".to_owned(), "C:\\a.txt".to_owned(), &source).unwrap();
+        assert_eq!(attachment.size, 5);
+        assert_eq!(
+            attachment.sha256,
+            "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824"
+        );
+        assert!(
+            ManifestAttachment::new("a".to_owned(), "a".to_owned(), &dir.join("nope")).is_err()
+        );
+    }
+
+    #[test]
+    fn write_to_works() {
+        let dir = std::env::temp_dir()
+            .join("mapirs_test")
+            .join("write_to_works");
+        let _ = fs::remove_dir_all(&dir);
+        let id = write_to(&dir, &manifest()).unwrap();
+        let written = fs::read(dir.join(format!("{}.json", id))).unwrap();
+        assert_eq!(
+            serde_json::from_slice::<Manifest>(&written).unwrap(),
+            manifest()
+        );
+        assert_eq!(url(&id), format!("tutanota://mapi/{}", id));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(dir.join(format!("{}.json", id)))
+                .unwrap()
+                .permissions()
+                .mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+    }
+}
@@ -0,0 +1,877 @@
+use std::io;
+use std::io::Write;
+
+use sha2::{Digest, Sha256};
+use time::{macros::format_description, OffsetDateTime};
+
+use crate::ffi::code_page;
+
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// RFC 2045: encoded lines must not be longer than 76 chars
+const BASE64_LINE_LENGTH: usize = 76;
+
+/// RFC 2047: an encoded word must not be longer than 75 chars. "=?UTF-8?B?" and "?="
+/// take 12 of those, 45 bytes of text turn into 60 chars of base64.
+const ENCODED_WORD_BYTES: usize = 45;
+
+/// RFC 5322: lines must not be longer than 998 chars
+const MAX_LINE_LENGTH: usize = 998;
+
+pub fn base64_encode(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let b = [
+            chunk[0],
+            chunk.get(1).copied().unwrap_or(0),
+            chunk.get(2).copied().unwrap_or(0),
+        ];
+        let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(BASE64_ALPHABET[(n >> (18 - 6 * i) & 0x3F) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+/// base64 with CRLF line breaks, for MIME bodies
+pub fn base64_encode_lines(bytes: &[u8]) -> String {
+    let encoded = base64_encode(bytes);
+    let mut out = String::with_capacity(encoded.len() + encoded.len() / BASE64_LINE_LENGTH * 2);
+    // base64 is ASCII, so splitting at byte offsets is fine.
+    for line in encoded.as_bytes().chunks(BASE64_LINE_LENGTH) {
+        out.push_str(std::str::from_utf8(line).unwrap_or(""));
+        out.push_str("\r\n");
+    }
+    out
+}
+
+fn is_plain_header_text(s: &str) -> bool {
+    s.chars().all(|c| c == ' ' || c.is_ascii_graphic())
+}
+
+/// encode a header value as RFC 2047 encoded words if it's not plain ASCII.
+/// long values are split into multiple encoded words on folded lines.
+pub fn encode_header_value(s: &str) -> String {
+    if is_plain_header_text(s) && !s.contains("=?") {
+        return s.to_owned();
+    }
+
+    let mut words = vec![];
+    let mut start = 0;
+    while start < s.len() {
+        let mut end = (start + ENCODED_WORD_BYTES).min(s.len());
+        // don't split multi-byte chars across encoded words
+        while !s.is_char_boundary(end) {
+            end -= 1;
+        }
+        words.push(format!(
+            "=?UTF-8?B?{}?=",
+            base64_encode(&s.as_bytes()[start..end])
+        ));
+        start = end;
+    }
+    words.join("\r\n ")
+}
+
+/// format a mailbox for an address header. display names that need it are
+/// quoted or encoded.
+pub fn encode_mailbox(name: &str, address: &str) -> String {
+    if name.is_empty() || name == address {
+        format!("<{}>", address)
+    } else if is_plain_header_text(name) {
+        let quoted = name.replace('\\', "\\\\").replace('"', "\\\"");
+        format!("\"{}\" <{}>", quoted, address)
+    } else {
+        format!("{} <{}>", encode_header_value(name), address)
+    }
+}
+
+/// the filename parameter of a Content-Disposition header. non-ASCII names are
+/// encoded as described in RFC 2231.
+pub fn filename_param(name: &str) -> String {
+    encode_param("filename", name)
+}
+
+/// a header parameter, encoded as described in RFC 2231 if value isn't plain ASCII.
+fn encode_param(attribute: &str, value: &str) -> String {
+    if is_plain_header_text(value) {
+        let quoted = value.replace('\\', "\\\\").replace('"', "\\\"");
+        format!("{}=\"{}\"", attribute, quoted)
+    } else {
+        let mut encoded = String::new();
+        for b in value.as_bytes() {
+            if b.is_ascii_alphanumeric() || b"!#$&+-.^_`|~".contains(b) {
+                encoded.push(*b as char);
+            } else {
+                encoded.push_str(&format!("%{:02X}", b));
+            }
+        }
+        format!("{}*=UTF-8''{}", attribute, encoded)
+    }
+}
+
+/// the value of a Date header
+pub fn format_date(date: OffsetDateTime) -> String {
+    let desc = format_description!(
+        "[weekday repr:short], [day] [month repr:short] [year] [hour]:[minute]:[second] [offset_hour sign:mandatory][offset_minute]"
+    );
+    date.format(desc)
+        .unwrap_or_else(|_| "Thu, 01 Jan 1970 00:00:00 +0000".to_owned())
+}
+
+/// a multipart boundary that's very unlikely to appear in the parts it separates
+pub fn make_boundary(seed: &[u8]) -> String {
+    let mut sha256 = Sha256::new();
+    sha256.update(seed);
+    sha256.update(
+        OffsetDateTime::now_utc()
+            .unix_timestamp_nanos()
+            .to_le_bytes(),
+    );
+    let hash = sha256.finalize();
+    let hex: String = hash[..12].iter().map(|b| format!("{:02x}", b)).collect();
+    format!("----=_mapirs_{}", hex)
+}
+
+/// write a text/plain body part including its headers. text that can't be
+/// sent as-is is base64 encoded.
+pub fn write_text_part<W: Write>(out: &mut W, text: &str) -> io::Result<()> {
+    let needs_encoding = !text.is_ascii() || text.lines().any(|l| l.len() > MAX_LINE_LENGTH);
+    write!(out, "Content-Type: text/plain; charset=utf-8\r\n")?;
+    if needs_encoding {
+        write!(out, "Content-Transfer-Encoding: base64\r\n\r\n")?;
+        write!(out, "{}", base64_encode_lines(text.as_bytes()))
+    } else {
+        write!(out, "Content-Transfer-Encoding: 7bit\r\n\r\n")?;
+        // normalize line endings to CRLF
+        for line in text.lines() {
+            write!(out, "{}\r\n", line)?;
+        }
+        Ok(())
+    }
+}
+
+/// write an attachment part including its headers
+pub fn write_attachment_part<W: Write>(out: &mut W, name: &str, contents: &[u8]) -> io::Result<()> {
+    write!(
+        out,
+        "Content-Type: application/octet-stream\r\n\
+         Content-Disposition: attachment; {}\r\n\
+         Content-Transfer-Encoding: base64\r\n\r\n",
+        filename_param(name)
+    )?;
+    write!(out, "{}", base64_encode_lines(contents))
+}
+
+/// write an attachment part that only refers to a local file (RFC 2046 message/external-body)
+/// instead of containing it.
+pub fn write_external_attachment_part<W: Write>(
+    out: &mut W,
+    name: &str,
+    path: &str,
+) -> io::Result<()> {
+    write!(
+        out,
+        "Content-Type: message/external-body; access-type=local-file; {}\r\n\
+         Content-Disposition: attachment; {}\r\n\r\n\
+         Content-Type: application/octet-stream\r\n\r\n",
+        encode_param("name", path),
+        filename_param(name)
+    )
+}
+
+/// a mailbox from an address header
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mailbox {
+    /// the display name, empty if there is none
+    pub name: String,
+    pub address: String,
+}
+
+/// a parsed message or body part. headers are unfolded, the body is
+/// still transfer-encoded.
+#[derive(Debug)]
+pub struct Part {
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl Part {
+    /// split raw bytes into headers and body. this never fails, anything
+    /// that's not a header ends up in the body.
+    pub fn parse(bytes: &[u8]) -> Self {
+        let (head, body) = split_head(bytes);
+        Part {
+            headers: parse_headers(head),
+            body: body.to_vec(),
+        }
+    }
+
+    /// the raw value of the first header with this name
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// the value of the first header with this name, with encoded words decoded
+    pub fn decoded_header(&self, name: &str) -> Option<String> {
+        self.header(name).map(decode_header_value)
+    }
+
+    /// the mailboxes of all headers with this name
+    pub fn mailboxes(&self, name: &str) -> Vec<Mailbox> {
+        self.headers
+            .iter()
+            .filter(|(n, _)| n.eq_ignore_ascii_case(name))
+            .flat_map(|(_, v)| parse_address_list(v))
+            .collect()
+    }
+
+    /// the lowercase mime type and the parameters of the Content-Type header.
+    /// defaults to text/plain as described in RFC 2045
+    pub fn content_type(&self) -> (String, Vec<(String, String)>) {
+        match self.header("Content-Type") {
+            Some(v) => parse_parameterized(v),
+            None => ("text/plain".to_owned(), vec![]),
+        }
+    }
+
+    /// the file name from Content-Disposition or Content-Type, if there is one
+    pub fn file_name(&self) -> Option<String> {
+        let from_disposition = self
+            .header("Content-Disposition")
+            .map(parse_parameterized)
+            .and_then(|(_, params)| param(&params, "filename"));
+        from_disposition
+            .or_else(|| param(&self.content_type().1, "name"))
+            .map(|n| decode_header_value(&n))
+    }
+
+    /// true if the sender wants this part to be treated as an attachment
+    pub fn is_attachment(&self) -> bool {
+        let disposition = self
+            .header("Content-Disposition")
+            .map(parse_parameterized)
+            .map(|(d, _)| d);
+        disposition.as_deref() == Some("attachment") || self.file_name().is_some()
+    }
+
+    /// the body with the transfer encoding removed
+    pub fn decoded_body(&self) -> Vec<u8> {
+        let encoding = self
+            .header("Content-Transfer-Encoding")
+            .map(|e| e.trim().to_ascii_lowercase());
+        match encoding.as_deref() {
+            Some("base64") => base64_decode(&self.body),
+            Some("quoted-printable") => quoted_printable_decode(&self.body),
+            _ => self.body.clone(),
+        }
+    }
+
+    /// the body as text, decoded according to the charset parameter
+    pub fn text(&self) -> String {
+        let charset = param(&self.content_type().1, "charset");
+        decode_charset(
+            charset.as_deref().unwrap_or("us-ascii"),
+            &self.decoded_body(),
+        )
+    }
+
+    /// the parts of a multipart body. empty if this is not a multipart part.
+    pub fn subparts(&self) -> Vec<Part> {
+        let (mime_type, params) = self.content_type();
+        let boundary = match param(&params, "boundary") {
+            Some(b) if mime_type.starts_with("multipart/") => format!("--{}", b),
+            _ => return vec![],
+        };
+
+        let mut parts = vec![];
+        let mut current: Option<Vec<u8>> = None;
+        for line in self.body.split_inclusive(|b| *b == b'\n') {
+            let trimmed = trim_line_end(line);
+            if trimmed.starts_with(boundary.as_bytes()) {
+                if let Some(part) = current.take() {
+                    parts.push(Part::parse(trim_line_end(&part)));
+                }
+                if trimmed[boundary.len()..].starts_with(b"--") {
+                    break;
+                }
+                current = Some(vec![]);
+            } else if let Some(part) = current.as_mut() {
+                part.extend_from_slice(line);
+            }
+        }
+        // tolerate a missing close delimiter
+        if let Some(part) = current {
+            parts.push(Part::parse(&part));
+        }
+        parts
+    }
+}
+
+fn trim_line_end(line: &[u8]) -> &[u8] {
+    let line = line.strip_suffix(b"\n").unwrap_or(line);
+    line.strip_suffix(b"\r").unwrap_or(line)
+}
+
+/// split at the first empty line. both CRLF and LF line endings are accepted.
+fn split_head(bytes: &[u8]) -> (&[u8], &[u8]) {
+    let mut start = 0;
+    for line in bytes.split_inclusive(|b| *b == b'\n') {
+        if trim_line_end(line).is_empty() {
+            return (&bytes[..start], &bytes[start + line.len()..]);
+        }
+        start += line.len();
+    }
+    (bytes, &[])
+}
+
+fn parse_headers(head: &[u8]) -> Vec<(String, String)> {
+    let mut headers: Vec<(String, String)> = vec![];
+    for line in head.split_inclusive(|b| *b == b'\n') {
+        let line = header_text(trim_line_end(line));
+        if line.starts_with(' ') || line.starts_with('\t') {
+            // unfold continuation lines
+            if let Some((_, value)) = headers.last_mut() {
+                value.push_str(&line);
+            }
+        } else if let Some((name, value)) = line.split_once(':') {
+            headers.push((name.trim().to_owned(), value.trim_start().to_owned()));
+        }
+    }
+    for (_, value) in headers.iter_mut() {
+        *value = value.trim_end().to_owned();
+    }
+    headers
+}
+
+/// header lines should be ASCII, but raw 8-bit headers are common.
+/// those are most often UTF-8 or windows-1252.
+fn header_text(line: &[u8]) -> String {
+    match std::str::from_utf8(line) {
+        Ok(s) => s.to_owned(),
+        Err(_) => decode_charset("windows-1252", line),
+    }
+}
+
+/// parse a header value like 'text/plain; charset="utf-8"' into the lowercase
+/// value and its parameters. parameters in the simple RFC 2231 form
+/// (filename*=UTF-8''...) are decoded.
+fn parse_parameterized(value: &str) -> (String, Vec<(String, String)>) {
+    let mut pieces = split_outside_quotes(value, ';').into_iter();
+    let main = pieces
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    let params = pieces
+        .filter_map(|piece| {
+            let (name, value) = piece.split_once('=')?;
+            let name = name.trim().to_ascii_lowercase();
+            let value = value.trim();
+            if let Some(name) = name.strip_suffix('*') {
+                let mut pieces = value.splitn(3, '\'');
+                let charset = pieces.next()?;
+                let encoded = pieces.nth(1)?;
+                let bytes = percent_decode(encoded.as_bytes());
+                Some((name.to_owned(), decode_charset(charset, &bytes)))
+            } else {
+                Some((name, unquote(value)))
+            }
+        })
+        .collect();
+    (main, params)
+}
+
+fn param(params: &[(String, String)], name: &str) -> Option<String> {
+    params
+        .iter()
+        .find(|(n, _)| n == name)
+        .map(|(_, v)| v.to_owned())
+}
+
+/// split a string at a separator, ignoring separators inside quoted strings
+fn split_outside_quotes(s: &str, sep: char) -> Vec<String> {
+    let mut pieces = vec![];
+    let mut current = String::new();
+    let mut in_quotes = false;
+    let mut escaped = false;
+    for c in s.chars() {
+        if escaped {
+            escaped = false;
+        } else if in_quotes && c == '\\' {
+            escaped = true;
+        } else if c == '"' {
+            in_quotes = !in_quotes;
+        } else if c == sep && !in_quotes {
+            pieces.push(std::mem::take(&mut current));
+            continue;
+        }
+        current.push(c);
+    }
+    pieces.push(current);
+    pieces
+}
+
+/// remove the quotes and escapes from a quoted string. other strings are
+/// returned as they are.
+fn unquote(s: &str) -> String {
+    let inner = match s.strip_prefix('"').and_then(|s| s.strip_suffix('"')) {
+        Some(inner) => inner,
+        None => return s.to_owned(),
+    };
+    let mut out = String::with_capacity(inner.len());
+    let mut escaped = false;
+    for c in inner.chars() {
+        if c == '\\' && !escaped {
+            escaped = true;
+        } else {
+            out.push(c);
+            escaped = false;
+        }
+    }
+    out
+}
+
+/// parse an address list header value (To, Cc, From, ...) into mailboxes.
+/// group names and comments are dropped, entries without an address are skipped.
+pub fn parse_address_list(value: &str) -> Vec<Mailbox> {
+    parse_list(value, true)
+}
+
+/// like parse_address_list, but for address lists that don't come from a header
+/// and may contain things like "SMTP:" prefixes, so there are no groups.
+pub fn split_address_list(value: &str) -> Vec<Mailbox> {
+    parse_list(value, false)
+}
+
+fn parse_list(value: &str, groups: bool) -> Vec<Mailbox> {
+    let mut entries = vec![];
+    let mut current = String::new();
+    let mut in_quotes = false;
+    let mut in_angle = false;
+    let mut comment_depth = 0;
+    let mut escaped = false;
+    for c in value.chars() {
+        if escaped {
+            escaped = false;
+            if comment_depth == 0 {
+                current.push(c);
+            }
+            continue;
+        }
+        match c {
+            '\\' if in_quotes || comment_depth > 0 => escaped = true,
+            '"' if comment_depth == 0 => in_quotes = !in_quotes,
+            '(' if !in_quotes && !in_angle => {
+                comment_depth += 1;
+                continue;
+            }
+            ')' if comment_depth > 0 => {
+                comment_depth -= 1;
+                continue;
+            }
+            '<' if !in_quotes && comment_depth == 0 => in_angle = true,
+            '>' if !in_quotes && comment_depth == 0 => in_angle = false,
+            // the name of a group, the members follow
+            ':' if groups
+                && !in_quotes
+                && !in_angle
+                && comment_depth == 0
+                && !current.contains('@') =>
+            {
+                current.clear();
+                continue;
+            }
+            ',' | ';' if !in_quotes && !in_angle && comment_depth == 0 => {
+                entries.push(std::mem::take(&mut current));
+                continue;
+            }
+            _ => {}
+        }
+        if comment_depth == 0 {
+            current.push(c);
+        }
+    }
+    entries.push(current);
+    entries.iter().filter_map(|e| parse_mailbox(e)).collect()
+}
+
+fn parse_mailbox(entry: &str) -> Option<Mailbox> {
+    let entry = entry.trim();
+    let (name, address) = match (entry.rfind('<'), entry.rfind('>')) {
+        (Some(start), Some(end)) if start < end => (&entry[..start], &entry[start + 1..end]),
+        _ => ("", entry),
+    };
+    let address = address.trim();
+    if address.is_empty() {
+        return None;
+    }
+    Some(Mailbox {
+        name: decode_header_value(&unquote(name.trim())),
+        address: address.to_owned(),
+    })
+}
+
+/// decode the RFC 2047 encoded words in a header value.
+/// whitespace between two encoded words is dropped.
+pub fn decode_header_value(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    let mut rest = value;
+    let mut pending_space = String::new();
+    let mut last_was_encoded = false;
+    while !rest.is_empty() {
+        if let Some((decoded, len)) = decode_encoded_word(rest) {
+            if !last_was_encoded {
+                out.push_str(&pending_space);
+            }
+            pending_space.clear();
+            out.push_str(&decoded);
+            rest = &rest[len..];
+            last_was_encoded = true;
+            continue;
+        }
+        // we only look at char boundaries, so this always finds a char.
+        let c = rest.chars().next().unwrap_or(' ');
+        if c.is_whitespace() {
+            pending_space.push(c);
+        } else {
+            out.push_str(&pending_space);
+            pending_space.clear();
+            out.push(c);
+            last_was_encoded = false;
+        }
+        rest = &rest[c.len_utf8()..];
+    }
+    out.push_str(&pending_space);
+    out
+}
+
+/// decode an encoded word at the start of s. returns the decoded text and
+/// the length of the encoded word in s.
+fn decode_encoded_word(s: &str) -> Option<(String, usize)> {
+    let inner = s.strip_prefix("=?")?;
+    let (charset, inner) = inner.split_once('?')?;
+    let (encoding, inner) = inner.split_once('?')?;
+    let end = inner.find("?=")?;
+    let text = &inner[..end];
+    if text.contains(char::is_whitespace) {
+        return None;
+    }
+    let bytes = match encoding {
+        "B" | "b" => base64_decode(text.as_bytes()),
+        "Q" | "q" => {
+            let text = text.replace('_', " ");
+            quoted_printable_decode(text.as_bytes())
+        }
+        _ => return None,
+    };
+    let len = "=?".len() + charset.len() + 1 + encoding.len() + 1 + end + "?=".len();
+    // RFC 2231 allows a language after the charset: charset*lang
+    let charset = charset.split('*').next().unwrap_or(charset);
+    Some((decode_charset(charset, &bytes), len))
+}
+
+/// decode text in one of the charsets we know. unknown charsets are
+/// treated as UTF-8, invalid sequences are replaced.
+pub fn decode_charset(charset: &str, bytes: &[u8]) -> String {
+    let charset = charset.trim().to_ascii_lowercase();
+    let code_page = match charset.as_str() {
+        "iso-8859-1" | "latin1" => Some(28591),
+        "windows-874" => Some(874),
+        other => other
+            .strip_prefix("windows-")
+            .or_else(|| other.strip_prefix("cp"))
+            .and_then(|cp| cp.parse().ok()),
+    };
+    code_page
+        .and_then(|cp| code_page::decode_single_byte(cp, bytes))
+        .unwrap_or_else(|| String::from_utf8_lossy(bytes).into_owned())
+}
+
+fn base64_value(c: u8) -> Option<u32> {
+    BASE64_ALPHABET
+        .iter()
+        .position(|&a| a == c)
+        .map(|p| p as u32)
+}
+
+/// decode base64, skipping line breaks and anything else that's not
+/// part of the alphabet.
+pub fn base64_decode(encoded: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(encoded.len() / 4 * 3);
+    let mut acc: u32 = 0;
+    let mut bits = 0;
+    for value in encoded.iter().filter_map(|&c| base64_value(c)) {
+        acc = acc << 6 | value;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((acc >> bits & 0xFF) as u8);
+        }
+    }
+    out
+}
+
+fn hex_value(c: u8) -> Option<u8> {
+    (c as char).to_digit(16).map(|d| d as u8)
+}
+
+/// decode quoted-printable, including soft line breaks
+pub fn quoted_printable_decode(encoded: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(encoded.len());
+    let mut i = 0;
+    while i < encoded.len() {
+        let c = encoded[i];
+        if c != b'=' {
+            out.push(c);
+            i += 1;
+            continue;
+        }
+        let rest = &encoded[i + 1..];
+        if rest.starts_with(b"\r\n") {
+            i += 3;
+        } else if rest.starts_with(b"\n") {
+            i += 2;
+        } else if let (Some(h), Some(l)) = (
+            rest.first().copied().and_then(hex_value),
+            rest.get(1).copied().and_then(hex_value),
+        ) {
+            out.push(h << 4 | l);
+            i += 3;
+        } else {
+            // not a valid escape, keep it as it is
+            out.push(c);
+            i += 1;
+        }
+    }
+    out
+}
+
+fn percent_decode(encoded: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(encoded.len());
+    let mut i = 0;
+    while i < encoded.len() {
+        match (
+            encoded[i],
+            encoded.get(i + 1).copied().and_then(hex_value),
+            encoded.get(i + 2).copied().and_then(hex_value),
+        ) {
+            (b'%', Some(h), Some(l)) => {
+                out.push(h << 4 | l);
+                i += 3;
+            }
+            (c, _, _) => {
+                out.push(c);
+                i += 1;
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use time::macros::datetime;
+
+    use crate::mime::{
+        base64_decode, base64_encode, base64_encode_lines, decode_header_value,
+        encode_header_value, encode_mailbox, filename_param, format_date, parse_address_list,
+        quoted_printable_decode, write_attachment_part, write_external_attachment_part,
+        write_text_part, Mailbox, Part,
+    };
+
+    #[test]
+    fn base64_encode_works() {
+        assert_eq!(base64_encode(b""), "");
+        assert_eq!(base64_encode(b"f"), "Zg==");
+        assert_eq!(base64_encode(b"fo"), "Zm8=");
+        assert_eq!(base64_encode(b"foo"), "Zm9v");
+        assert_eq!(base64_encode(b"foobar"), "Zm9vYmFy");
+        assert_eq!(base64_encode(&[0xFF, 0xFE, 0x00]), "//4A");
+
+        let lines = base64_encode_lines(&[0u8; 60]);
+        assert_eq!(
+            lines,
+            format!("{}\r\n{}\r\n", "A".repeat(76), "A".repeat(4))
+        );
+    }
+
+    #[test]
+    fn encode_header_value_works() {
+        assert_eq!(encode_header_value("Hello World"), "Hello World");
+        assert_eq!(encode_header_value("Grüße"), "=?UTF-8?B?R3LDvMOfZQ==?=");
+        // not plain text anymore if it contains line breaks
+        assert_eq!(encode_header_value("a\r\nb"), "=?UTF-8?B?YQ0KYg==?=");
+
+        let long = "ä".repeat(40);
+        let encoded = encode_header_value(&long);
+        let words: Vec<&str> = encoded.split("\r\n ").collect();
+        assert_eq!(words.len(), 2);
+        assert!(words.iter().all(|w| w.len() <= 75));
+    }
+
+    #[test]
+    fn encode_mailbox_works() {
+        assert_eq!(encode_mailbox("", "a@b.de"), "<a@b.de>");
+        assert_eq!(encode_mailbox("a@b.de", "a@b.de"), "<a@b.de>");
+        assert_eq!(
+            encode_mailbox("Doe, \"Jane\"", "a@b.de"),
+            "\"Doe, \\\"Jane\\\"\" <a@b.de>"
+        );
+        assert_eq!(
+            encode_mailbox("Jürgen", "j@b.de"),
+            "=?UTF-8?B?SsO8cmdlbg==?= <j@b.de>"
+        );
+    }
+
+    #[test]
+    fn filename_param_works() {
+        assert_eq!(filename_param("a b.txt"), "filename=\"a b.txt\"");
+        assert_eq!(
+            filename_param("Überweisung 1.pdf"),
+            "filename*=UTF-8''%C3%9Cberweisung%201.pdf"
+        );
+    }
+
+    #[test]
+    fn write_external_attachment_part_works() {
+        let mut out = vec![];
+        write_external_attachment_part(&mut out, "Büro.pdf", "C:\\tmp\\ab12\\Büro.pdf").unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "Content-Type: message/external-body; access-type=local-file; \
+             name*=UTF-8''C%3A%5Ctmp%5Cab12%5CB%C3%BCro.pdf\r\n\
+             Content-Disposition: attachment; filename*=UTF-8''B%C3%BCro.pdf\r\n\r\n\
+             Content-Type: application/octet-stream\r\n\r\n"
+        );
+
+        let mut out = vec![];
+        write_external_attachment_part(&mut out, "a.txt", "C:\\tmp\\a.txt").unwrap();
+        assert!(String::from_utf8(out)
+            .unwrap()
+            .starts_with("Content-Type: message/external-body; access-type=local-file; name=\"C:\\\\tmp\\\\a.txt\"\r\n"));
+    }
+
+    #[test]
+    fn format_date_works() {
+        assert_eq!(
+            format_date(datetime!(2022-08-01 13:05:09 UTC)),
+            "Mon, 01 Aug 2022 13:05:09 +0000"
+        );
+    }
+
+    #[test]
+    fn write_text_part_works() {
+        let mut out = vec![];
+        write_text_part(&mut out, "hello\nworld").unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "Content-Type: text/plain; charset=utf-8\r\n\
+             Content-Transfer-Encoding: 7bit\r\n\r\n\
+             hello\r\nworld\r\n"
+        );
+    }
+
+    fn mailbox(name: &str, address: &str) -> Mailbox {
+        Mailbox {
+            name: name.to_owned(),
+            address: address.to_owned(),
+        }
+    }
+
+    #[test]
+    fn decoding_works() {
+        assert_eq!(base64_decode(b"Zm9v\r\nYmFy"), b"foobar");
+        assert_eq!(base64_decode(b"Zm8="), b"fo");
+        assert_eq!(
+            base64_decode(base64_encode(&[0xFF, 0xFE, 0x00]).as_bytes()),
+            [0xFF, 0xFE, 0x00]
+        );
+        assert_eq!(
+            quoted_printable_decode(b"Gr=C3=BC=\r\n=C3=9Fe"),
+            "Grüße".as_bytes()
+        );
+        assert_eq!(quoted_printable_decode(b"100% =XY"), b"100% =XY");
+    }
+
+    #[test]
+    fn decode_header_value_works() {
+        assert_eq!(decode_header_value("plain text"), "plain text");
+        assert_eq!(decode_header_value("=?UTF-8?B?R3LDvMOfZQ==?="), "Grüße");
+        assert_eq!(
+            decode_header_value(&encode_header_value(&"ä".repeat(40))),
+            "ä".repeat(40)
+        );
+        assert_eq!(
+            decode_header_value("=?iso-8859-1?Q?J=FCrgen_W?= =?utf-8?q?=C3=B6lk?= sagt"),
+            "Jürgen Wölk sagt"
+        );
+        assert_eq!(decode_header_value("=?utf-8?x?nope?="), "=?utf-8?x?nope?=");
+    }
+
+    #[test]
+    fn parse_address_list_works() {
+        assert_eq!(
+            parse_address_list("a@b.de, \"Doe, Jane\" <j@d.de>; c@d.de (work)"),
+            vec![
+                mailbox("", "a@b.de"),
+                mailbox("Doe, Jane", "j@d.de"),
+                mailbox("", "c@d.de"),
+            ]
+        );
+        assert_eq!(
+            parse_address_list("=?UTF-8?B?SsO8cmdlbg==?= <j@b.de>"),
+            vec![mailbox("Jürgen", "j@b.de")]
+        );
+        assert_eq!(parse_address_list("undisclosed-recipients:;"), vec![]);
+        assert_eq!(
+            parse_address_list("team: a@b.de, b@b.de;"),
+            vec![mailbox("", "a@b.de"), mailbox("", "b@b.de")]
+        );
+        assert_eq!(
+            parse_address_list("\"a,b\"@c.de"),
+            vec![mailbox("", "\"a,b\"@c.de")]
+        );
+    }
+
+    #[test]
+    fn parse_part_works() {
+        let mut eml = b"Subject: =?UTF-8?B?R3LDvMOfZQ==?=\r\n\
+            To: a@b.de,\r\n \"B\" <b@b.de>\r\n\
+            Content-Type: multipart/mixed; boundary=\"xyz\"\r\n\r\n\
+            preamble\r\n\
+            --xyz\r\n\
+            Content-Type: text/plain; charset=iso-8859-1\r\n\
+            Content-Transfer-Encoding: quoted-printable\r\n\r\n\
+            Gr=FC=DFe\r\n\
+            --xyz\r\n"
+            .to_vec();
+        write_attachment_part(&mut eml, "Überweisung.pdf", b"%PDF").unwrap();
+        eml.extend_from_slice(b"--xyz--\r\n");
+
+        let part = Part::parse(&eml);
+        assert_eq!(part.decoded_header("subject"), Some("Grüße".to_owned()));
+        assert_eq!(
+            part.mailboxes("To"),
+            vec![mailbox("", "a@b.de"), mailbox("B", "b@b.de")]
+        );
+        assert_eq!(part.content_type().0, "multipart/mixed");
+
+        let subparts = part.subparts();
+        assert_eq!(subparts.len(), 2);
+        assert!(!subparts[0].is_attachment());
+        assert_eq!(subparts[0].text(), "Grüße");
+        assert!(subparts[1].is_attachment());
+        assert_eq!(subparts[1].file_name(), Some("Überweisung.pdf".to_owned()));
+        assert_eq!(subparts[1].decoded_body(), b"%PDF");
+    }
+}
@@ -0,0 +1,246 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use crate::environment;
+use crate::logger;
+use crate::manifest::MANIFEST_DIR;
+use crate::store::OUTBOX_DIR;
+
+/// an empty file in the directories we copy attachments to. directories without
+/// it are left alone, TMPPath may hold things that aren't ours.
+const ATTACHMENT_DIR_MARKER: &str = ".mapirs";
+
+/// remove the files we put into TMPPath for the client (.eml files, manifests and copies
+/// of attachments) once they're older than max_age. drafts and everything we don't
+/// know are kept, even if they're in TMPPath.
+///
+/// returns the number of files that were removed.
+pub fn clean_tmp(max_age: Duration) -> io::Result<usize> {
+    let tmp_path: PathBuf = environment::tmp_path()?.into();
+    clean(&tmp_path, max_age, SystemTime::now(), &|path| {
+        fs::remove_file(path)
+    })
+}
+
+/// files that can't be removed (the client may still have an attachment open)
+/// are logged and skipped, the rest of TMPPath is cleaned anyway.
+fn clean(
+    tmp_path: &Path,
+    max_age: Duration,
+    now: SystemTime,
+    remove: &dyn Fn(&Path) -> io::Result<()>,
+) -> io::Result<usize> {
+    let cutoff = match now.checked_sub(max_age) {
+        Some(cutoff) => cutoff,
+        None => return Ok(0),
+    };
+    let mut removed = 0;
+    for entry in fs::read_dir(tmp_path)? {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                skip(tmp_path, &e);
+                continue;
+            }
+        };
+        match entry.file_type() {
+            Ok(file_type) if file_type.is_dir() => {}
+            Ok(_) => continue,
+            Err(e) => {
+                skip(&entry.path(), &e);
+                continue;
+            }
+        }
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        if name == OUTBOX_DIR || name == MANIFEST_DIR {
+            removed += remove_older(&entry.path(), cutoff, remove);
+        } else if is_attachment_dir(&entry.path()) {
+            removed += remove_older(&entry.path(), cutoff, remove);
+            remove_attachment_dir_if_empty(&entry.path());
+        }
+    }
+    Ok(removed)
+}
+
+/// create a directory in TMPPath to copy attachments to. if it didn't exist
+/// yet, it's marked as ours so clean_tmp removes it once it's old enough.
+pub fn create_attachment_dir(dir: &Path) -> io::Result<()> {
+    if dir.is_dir() {
+        return Ok(());
+    }
+    fs::create_dir_all(dir)?;
+    fs::File::create(dir.join(ATTACHMENT_DIR_MARKER)).map(|_| ())
+}
+
+/// only the directories created with create_attachment_dir
+fn is_attachment_dir(dir: &Path) -> bool {
+    dir.join(ATTACHMENT_DIR_MARKER).is_file()
+}
+
+/// the marker goes last, so a directory that still has files stays ours
+fn remove_attachment_dir_if_empty(dir: &Path) {
+    let only_marker = fs::read_dir(dir).is_ok_and(|mut entries| {
+        entries.all(|entry| entry.is_ok_and(|entry| entry.file_name() == ATTACHMENT_DIR_MARKER))
+    });
+    if only_marker {
+        let _ = fs::remove_file(dir.join(ATTACHMENT_DIR_MARKER));
+        let _ = fs::remove_dir(dir);
+    }
+}
+
+/// remove the files in dir and its subdirectories that weren't touched since cutoff.
+/// entries that can't be read or removed are logged and skipped.
+fn remove_older(dir: &Path, cutoff: SystemTime, remove: &dyn Fn(&Path) -> io::Result<()>) -> usize {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            skip(dir, &e);
+            return 0;
+        }
+    };
+    let mut removed = 0;
+    for entry in entries {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                skip(dir, &e);
+                continue;
+            }
+        };
+        let path = entry.path();
+        if entry.file_name() == ATTACHMENT_DIR_MARKER {
+            continue;
+        }
+        let metadata = match entry.metadata() {
+            Ok(metadata) => metadata,
+            Err(e) => {
+                skip(&path, &e);
+                continue;
+            }
+        };
+        if metadata.is_dir() {
+            removed += remove_older(&path, cutoff, remove);
+        } else if last_touched(&metadata).map(|t| t < cutoff) == Some(true) {
+            match remove(&path) {
+                Ok(()) => removed += 1,
+                Err(e) => skip(&path, &e),
+            }
+        }
+    }
+    removed
+}
+
+fn skip(path: &Path, e: &io::Error) {
+    logger::warn("retention", &format!("skipping {:?}: {}", path, e));
+}
+
+/// copies keep the modification time of the original on windows,
+/// so the creation time counts as well.
+fn last_touched(metadata: &fs::Metadata) -> Option<SystemTime> {
+    let modified = metadata.modified().ok();
+    let created = metadata.created().ok();
+    modified.max(created)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io;
+    use std::path::{Path, PathBuf};
+    use std::time::{Duration, SystemTime};
+
+    use crate::retention::{clean, create_attachment_dir, is_attachment_dir};
+
+    const DAY: Duration = Duration::from_secs(60 * 60 * 24);
+
+    fn remove_file(path: &Path) -> io::Result<()> {
+        fs::remove_file(path)
+    }
+
+    /// a fresh TMPPath in the temp dir with the attachment directories we created and files
+    fn populate(name: &str, attachment_dirs: &[&str], files: &[&str]) -> PathBuf {
+        let tmp = std::env::temp_dir().join("mapirs_test").join(name);
+        let _ = fs::remove_dir_all(&tmp);
+        for dir in attachment_dirs {
+            create_attachment_dir(&tmp.join(dir)).unwrap();
+        }
+        for file in files {
+            let path = tmp.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, b"x").unwrap();
+        }
+        tmp
+    }
+
+    #[test]
+    fn attachment_dirs_are_marked() {
+        let tmp = populate("attachment_dirs_are_marked", &["e3b0"], &["cafe/a.txt"]);
+        assert!(is_attachment_dir(&tmp.join("e3b0")));
+        // not created by us, even if the name looks like it
+        assert!(!is_attachment_dir(&tmp.join("cafe")));
+        create_attachment_dir(&tmp.join("cafe")).unwrap();
+        assert!(!is_attachment_dir(&tmp.join("cafe")));
+        assert!(!is_attachment_dir(&tmp.join("missing")));
+    }
+
+    #[test]
+    fn clean_works() {
+        let tmp = populate(
+            "clean_works",
+            &["e3b0"],
+            &[
+                "outbox/a.eml",
+                "manifests/b.json",
+                "e3b0/c.pdf",
+                "drafts/d.eml",
+                "other/e.txt",
+                "cafe/f.txt",
+            ],
+        );
+
+        // nothing is old enough yet
+        assert_eq!(
+            clean(&tmp, 7 * DAY, SystemTime::now(), &remove_file).unwrap(),
+            0
+        );
+
+        let later = SystemTime::now() + 8 * DAY;
+        assert_eq!(clean(&tmp, 7 * DAY, later, &remove_file).unwrap(), 3);
+        assert!(tmp.join("outbox").exists());
+        assert!(!tmp.join("outbox/a.eml").exists());
+        assert!(!tmp.join("manifests/b.json").exists());
+        assert!(!tmp.join("e3b0").exists());
+        assert!(tmp.join("drafts/d.eml").exists());
+        assert!(tmp.join("other/e.txt").exists());
+        assert!(tmp.join("cafe/f.txt").exists());
+    }
+
+    #[test]
+    fn clean_skips_files_it_cant_remove() {
+        let tmp = populate(
+            "clean_skips",
+            &["e3b0", "5e3f"],
+            &["outbox/a.eml", "outbox/b.eml", "e3b0/c.pdf", "5e3f/d.pdf"],
+        );
+
+        // the client still has these open
+        let in_use = |path: &Path| {
+            if path.ends_with("a.eml") || path.ends_with("c.pdf") {
+                Err(io::Error::new(io::ErrorKind::PermissionDenied, "in use"))
+            } else {
+                fs::remove_file(path)
+            }
+        };
+        let later = SystemTime::now() + 8 * DAY;
+        assert_eq!(clean(&tmp, 7 * DAY, later, &in_use).unwrap(), 2);
+        assert!(tmp.join("outbox/a.eml").exists());
+        assert!(!tmp.join("outbox/b.eml").exists());
+        assert!(tmp.join("e3b0/c.pdf").exists());
+        // still ours, so it's cleaned once the file can be removed
+        assert!(is_attachment_dir(&tmp.join("e3b0")));
+        assert!(!tmp.join("5e3f").exists());
+    }
+}
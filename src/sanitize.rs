@@ -0,0 +1,83 @@
+use crate::logger;
+
+/// line breaks (and tabs) in header-like fields are folded into a single space
+fn is_line_break(c: char) -> bool {
+    matches!(c, '\r' | '\n' | '\t' | '\u{2028}' | '\u{2029}')
+}
+
+/// fold the line breaks in a header-like field (subject, recipient names) into
+/// spaces and drop the other control characters. field is only used for logging.
+pub fn header_value(field: &str, value: &str) -> String {
+    let mut sanitized = String::with_capacity(value.len());
+    let mut folding = false;
+    for c in value.chars() {
+        if is_line_break(c) {
+            if !folding {
+                sanitized.push(' ');
+            }
+            folding = true;
+        } else if !c.is_control() {
+            sanitized.push(c);
+            folding = false;
+        }
+    }
+    if sanitized != value {
+        logger::warn(
+            "sanitize",
+            &format!("removed control characters from {}: {:?}", field, value),
+        );
+    }
+    sanitized
+}
+
+/// false if the address contains line breaks or other control characters. URI
+/// delimiters and spaces are legal in quoted local parts, mailto::encode_addresses
+/// takes care of them.
+pub fn is_safe_address(address: &str) -> bool {
+    let unsafe_char = address
+        .chars()
+        .find(|&c| c.is_control() || is_line_break(c));
+    if let Some(c) = unsafe_char {
+        logger::warn(
+            "sanitize",
+            &format!(
+                "rejecting address {:?} because it contains {:?}",
+                address, c
+            ),
+        );
+    }
+    unsafe_char.is_none()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sanitize::{header_value, is_safe_address};
+
+    #[test]
+    fn header_value_works() {
+        assert_eq!(header_value("subject", "Invoice 42"), "Invoice 42");
+        assert_eq!(
+            header_value("subject", "hi\r\nBcc: evil@attacker.com"),
+            "hi Bcc: evil@attacker.com"
+        );
+        assert_eq!(header_value("subject", "a\n\n\tb\rc\u{2028}d"), "a b c d");
+        assert_eq!(
+            header_value("subject", "a\0b\u{7}c\u{7f}d\u{85}e\u{1b}[0m"),
+            "abcde[0m"
+        );
+        assert_eq!(header_value("subject", "Grüße ☕"), "Grüße ☕");
+    }
+
+    #[test]
+    fn is_safe_address_works() {
+        assert!(is_safe_address("a@b.de"));
+        assert!(is_safe_address("a+b=c@b.de"));
+        assert!(is_safe_address("\"a b\"@b.de"));
+        assert!(is_safe_address("\"a?b&c#d%e\"@b.de"));
+        assert!(!is_safe_address("a@b.de\r\nBcc: evil@attacker.com"));
+        assert!(!is_safe_address("a@b.de\nBcc: evil@attacker.com"));
+        assert!(!is_safe_address("a@b.de\u{2028}x"));
+        assert!(!is_safe_address("a\tb@b.de"));
+        assert!(!is_safe_address("a\0@b.de"));
+    }
+}
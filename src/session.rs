@@ -0,0 +1,154 @@
+use std::sync::Mutex;
+
+use crate::flags::MapiStatusCode;
+use crate::types::LHandle;
+
+/// the implicit session that's used when a caller doesn't log on before calling
+/// any of the other functions.
+pub const IMPLICIT_SESSION: LHandle = 0;
+
+/// there is no real reason to have more than a couple of sessions open at once,
+/// a caller that does probably leaks them.
+const MAX_SESSIONS: usize = 64;
+
+static SESSIONS: Mutex<SessionTable> = Mutex::new(SessionTable::new());
+
+/// keeps track of the session handles we handed out via MAPILogon
+/// that weren't invalidated via MAPILogoff yet.
+#[derive(Debug)]
+struct SessionTable {
+    /// the last handle that was handed out
+    last: LHandle,
+    /// the session that's returned if MAPILogon is called without NEW_SESSION
+    shared: Option<LHandle>,
+    open: Vec<LHandle>,
+}
+
+impl SessionTable {
+    const fn new() -> Self {
+        SessionTable {
+            last: IMPLICIT_SESSION,
+            shared: None,
+            open: Vec::new(),
+        }
+    }
+
+    fn logon(&mut self, new_session: bool) -> Result<LHandle, MapiStatusCode> {
+        if !new_session {
+            if let Some(shared) = self.shared {
+                return Ok(shared);
+            }
+        }
+
+        if self.open.len() >= MAX_SESSIONS {
+            return Err(MapiStatusCode::TooManySessions);
+        }
+
+        // skip the implicit session handle when wrapping around.
+        let mut handle = self.last.wrapping_add(1);
+        while handle == IMPLICIT_SESSION || self.open.contains(&handle) {
+            handle = handle.wrapping_add(1);
+        }
+        self.last = handle;
+        self.open.push(handle);
+        if self.shared.is_none() {
+            self.shared = Some(handle);
+        }
+        Ok(handle)
+    }
+
+    fn logoff(&mut self, handle: LHandle) -> Result<(), MapiStatusCode> {
+        if handle == IMPLICIT_SESSION {
+            // nothing to do, the implicit session is always there.
+            return Ok(());
+        }
+        let idx = self
+            .open
+            .iter()
+            .position(|h| *h == handle)
+            .ok_or(MapiStatusCode::InvalidSession)?;
+        self.open.swap_remove(idx);
+        if self.shared == Some(handle) {
+            self.shared = None;
+        }
+        Ok(())
+    }
+
+    fn check(&self, handle: LHandle) -> Result<(), MapiStatusCode> {
+        if handle == IMPLICIT_SESSION || self.open.contains(&handle) {
+            Ok(())
+        } else {
+            Err(MapiStatusCode::InvalidSession)
+        }
+    }
+}
+
+/// hand out a session handle. if new_session is false and there is
+/// already a session open, that one is returned instead.
+pub fn logon(new_session: bool) -> Result<LHandle, MapiStatusCode> {
+    let mut table = SESSIONS.lock().map_err(|_| MapiStatusCode::Failure)?;
+    table.logon(new_session)
+}
+
+/// invalidate a session handle we handed out earlier
+pub fn logoff(handle: LHandle) -> Result<(), MapiStatusCode> {
+    let mut table = SESSIONS.lock().map_err(|_| MapiStatusCode::Failure)?;
+    table.logoff(handle)
+}
+
+/// make sure a session handle is either the implicit session or
+/// one we handed out and that wasn't logged off yet.
+pub fn check(handle: LHandle) -> Result<(), MapiStatusCode> {
+    let table = SESSIONS.lock().map_err(|_| MapiStatusCode::Failure)?;
+    table.check(handle)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::flags::MapiStatusCode;
+    use crate::session::{SessionTable, IMPLICIT_SESSION, MAX_SESSIONS};
+
+    #[test]
+    fn logon_and_logoff_work() {
+        let mut table = SessionTable::new();
+        assert_eq!(table.check(IMPLICIT_SESSION), Ok(()));
+        assert_eq!(table.check(1), Err(MapiStatusCode::InvalidSession));
+
+        let first = table.logon(false).unwrap();
+        assert_ne!(first, IMPLICIT_SESSION);
+        assert_eq!(table.check(first), Ok(()));
+        // without NEW_SESSION, the shared session is reused
+        assert_eq!(table.logon(false), Ok(first));
+
+        let second = table.logon(true).unwrap();
+        assert_ne!(first, second);
+
+        assert_eq!(table.logoff(first), Ok(()));
+        assert_eq!(table.check(first), Err(MapiStatusCode::InvalidSession));
+        assert_eq!(table.logoff(first), Err(MapiStatusCode::InvalidSession));
+        assert_eq!(table.check(second), Ok(()));
+
+        // logged off handles are not handed out again right away
+        let third = table.logon(false).unwrap();
+        assert_ne!(third, first);
+        assert_eq!(table.logoff(IMPLICIT_SESSION), Ok(()));
+    }
+
+    #[test]
+    fn too_many_sessions_are_rejected() {
+        let mut table = SessionTable::new();
+        for _ in 0..MAX_SESSIONS {
+            table.logon(true).unwrap();
+        }
+        assert_eq!(table.logon(true), Err(MapiStatusCode::TooManySessions));
+        // the shared session can still be used
+        assert!(table.logon(false).is_ok());
+    }
+
+    #[test]
+    fn handles_skip_the_implicit_session_on_wraparound() {
+        let mut table = SessionTable::new();
+        table.last = usize::MAX;
+        assert_eq!(table.logon(true), Ok(1));
+    }
+}
@@ -0,0 +1,554 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use serde_json::Value;
+
+use crate::environment;
+use crate::logger;
+
+const LOG_LEVEL: &str = "LOGLevel";
+const LOG_FORMAT: &str = "LOGFormat";
+const LOG_MAX_SIZE: &str = "LOGMaxSize";
+const LOG_MAX_AGE_DAYS: &str = "LOGMaxAgeDays";
+const LOG_GENERATIONS: &str = "LOGGenerations";
+const LOG_COMPRESS: &str = "LOGCompress";
+const HANDOFF: &str = "HANDOFF";
+const MAX_ATTACHMENT_SIZE: &str = "MAXAttachmentSize";
+const MAX_TOTAL_ATTACHMENT_SIZE: &str = "MAXTotalAttachmentSize";
+const TMP_RETENTION_DAYS: &str = "TMPRetentionDays";
+const SEND_POLICY: &str = "SENDPolicy";
+
+/// something about loading the settings that should go into the log
+type Note = (LogLevel, String);
+
+const DAY: Duration = Duration::from_secs(60 * 60 * 24);
+
+/// more rotated logs than this are refused, rotating renames every one of them
+const MAX_LOG_GENERATIONS: u32 = 100;
+
+/// how much ends up in the log
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    Off,
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+/// how the lines in the log look
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    /// "<time> | <level> | <call id> | <caller>: <message>"
+    Text,
+    /// one JSON object per line
+    Json,
+}
+
+/// how messages get to the client, see transport
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Handoff {
+    Mailto,
+    Eml,
+    EmlReference,
+    Manifest,
+    Ipc,
+    /// the tests' environment uses this, they never start the client
+    #[cfg(test)]
+    Recording,
+}
+
+/// whether apps may send messages without showing the composer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SendPolicy {
+    /// do what the app asks for with MAPI_DIALOG
+    AsRequested,
+    /// always show the composer, even if the app wants the message sent right away
+    AlwaysCompose,
+}
+
+/// the settings for the current user. they're read from the same place as EXEPath
+/// and TMPPath, values in the JSON file at SETTINGSPath win over those. numbers can be
+/// strings or, in the registry, REG_DWORD or REG_QWORD values.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Settings {
+    /// LOGLevel: "off", "error", "warn", "info", "debug" or "trace"
+    pub log_level: LogLevel,
+    /// LOGFormat: "text" or "json"
+    pub log_format: LogFormat,
+    /// LOGMaxSize: mapi.log is rotated once it's this big in bytes, 0 for no limit
+    pub log_max_size: Option<u64>,
+    /// LOGMaxAgeDays: mapi.log is rotated if it wasn't written to for this long, 0 to never do that
+    pub log_max_age: Option<Duration>,
+    /// LOGGenerations: how many rotated logs (mapi.1.log, mapi.2.log, ...) are kept
+    pub log_generations: u32,
+    /// LOGCompress: "true" to gzip the rotated logs except the newest one
+    pub log_compress: bool,
+    /// HANDOFF: "mailto", "eml", "eml-reference", "manifest" or "ipc"
+    pub handoff: Handoff,
+    /// MAXAttachmentSize: the largest attachment we hand to the client in bytes, 0 for no limit
+    pub max_attachment_size: Option<u64>,
+    /// MAXTotalAttachmentSize: the largest size of all attachments of a message together
+    pub max_total_attachment_size: Option<u64>,
+    /// TMPRetentionDays: how long the files we put into TMPPath are kept
+    pub tmp_retention: Duration,
+    /// SENDPolicy: "as-requested" or "always-compose"
+    pub send_policy: SendPolicy,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            log_level: LogLevel::Info,
+            log_format: LogFormat::Text,
+            log_max_size: Some(5 * 1024 * 1024),
+            log_max_age: Some(DAY),
+            log_generations: 5,
+            log_compress: false,
+            handoff: Handoff::Mailto,
+            max_attachment_size: None,
+            max_total_attachment_size: None,
+            tmp_retention: 7 * DAY,
+            send_policy: SendPolicy::AsRequested,
+        }
+    }
+}
+
+/// the settings, loaded on first use. what was defaulted or invalid is logged then.
+#[cfg(not(test))]
+pub fn get() -> Settings {
+    static SETTINGS: std::sync::OnceLock<Settings> = std::sync::OnceLock::new();
+    let mut notes = vec![];
+    let settings = *SETTINGS.get_or_init(|| load(&mut notes));
+    // logging needs the settings, so this has to wait until they're there.
+    for (level, note) in notes {
+        logger::log(level, "settings", &note);
+    }
+    settings
+}
+
+/// tests load the settings per thread, from the per thread environment.
+#[cfg(test)]
+pub fn get() -> Settings {
+    if let Some(settings) = TEST_SETTINGS.with(|s| *s.borrow()) {
+        return settings;
+    }
+    let mut notes = vec![];
+    let settings = load(&mut notes);
+    set_test_settings(settings);
+    for (level, note) in notes {
+        logger::log(level, "settings", &note);
+    }
+    settings
+}
+
+#[cfg(test)]
+thread_local! {
+    static TEST_SETTINGS: std::cell::RefCell<Option<Settings>> = const { std::cell::RefCell::new(None) };
+}
+
+/// replace the settings for the tests running on this thread
+#[cfg(test)]
+pub fn set_test_settings(settings: Settings) {
+    TEST_SETTINGS.with(|s| *s.borrow_mut() = Some(settings));
+}
+
+fn load(notes: &mut Vec<Note>) -> Settings {
+    notes.extend(
+        environment::problems()
+            .into_iter()
+            .map(|problem| (LogLevel::Warn, problem)),
+    );
+    let overrides = read_overrides(notes);
+    let unreadable = RefCell::new(vec![]);
+    let settings = from_values(
+        |name| {
+            overrides
+                .get(name)
+                .cloned()
+                .or_else(|| environment_value(name, environment::setting(name), &unreadable))
+        },
+        notes,
+    );
+    notes.extend(unreadable.into_inner());
+    settings
+}
+
+/// a value that's there but can't be read (like a registry value of the wrong type)
+/// is noted, it would look like it's not set otherwise.
+fn environment_value(
+    name: &str,
+    value: io::Result<String>,
+    notes: &RefCell<Vec<Note>>,
+) -> Option<String> {
+    match value {
+        Ok(value) => Some(value),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+        Err(e) => {
+            notes
+                .borrow_mut()
+                .push((LogLevel::Warn, format!("could not read {}: {:?}", name, e)));
+            None
+        }
+    }
+}
+
+/// read the values from the settings file, if there is one
+fn read_overrides(notes: &mut Vec<Note>) -> HashMap<String, String> {
+    let path: PathBuf = match environment::settings_path() {
+        Ok(path) => path.into(),
+        Err(_) => return HashMap::new(),
+    };
+    let values = fs::read(&path)
+        .and_then(|contents| Ok(serde_json::from_slice::<HashMap<String, Value>>(&contents)?));
+    match values {
+        Ok(values) => values
+            .into_iter()
+            .map(|(name, value)| match value {
+                Value::String(s) => (name, s),
+                other => (name, other.to_string()),
+            })
+            .collect(),
+        Err(e) => {
+            notes.push((
+                LogLevel::Warn,
+                format!("could not read settings file {:?}: {:?}", path, e),
+            ));
+            HashMap::new()
+        }
+    }
+}
+
+fn from_values(value: impl Fn(&str) -> Option<String>, notes: &mut Vec<Note>) -> Settings {
+    let defaults = Settings::default();
+    let log_level = parse(
+        &value,
+        notes,
+        LOG_LEVEL,
+        defaults.log_level,
+        parse_log_level,
+    );
+    let log_format = parse(
+        &value,
+        notes,
+        LOG_FORMAT,
+        defaults.log_format,
+        parse_log_format,
+    );
+    let log_max_size = parse(
+        &value,
+        notes,
+        LOG_MAX_SIZE,
+        defaults.log_max_size,
+        parse_size,
+    );
+    let log_max_age_days = parse(
+        &value,
+        notes,
+        LOG_MAX_AGE_DAYS,
+        defaults
+            .log_max_age
+            .map(|age| age.as_secs() / DAY.as_secs()),
+        |v| match v.parse() {
+            Ok(0) => Some(None),
+            Ok(days) => Some(Some(days)),
+            Err(_) => None,
+        },
+    );
+    let log_generations = parse(
+        &value,
+        notes,
+        LOG_GENERATIONS,
+        defaults.log_generations,
+        |v| v.parse().ok().filter(|g| *g <= MAX_LOG_GENERATIONS),
+    );
+    let log_compress = parse(
+        &value,
+        notes,
+        LOG_COMPRESS,
+        defaults.log_compress,
+        parse_bool,
+    );
+    let handoff = parse(&value, notes, HANDOFF, defaults.handoff, parse_handoff);
+    let max_attachment_size = parse(
+        &value,
+        notes,
+        MAX_ATTACHMENT_SIZE,
+        defaults.max_attachment_size,
+        parse_size,
+    );
+    let max_total_attachment_size = parse(
+        &value,
+        notes,
+        MAX_TOTAL_ATTACHMENT_SIZE,
+        defaults.max_total_attachment_size,
+        parse_size,
+    );
+    let retention_days = parse(
+        &value,
+        notes,
+        TMP_RETENTION_DAYS,
+        defaults.tmp_retention.as_secs() / DAY.as_secs(),
+        |v| v.parse().ok(),
+    );
+    let send_policy = parse(
+        &value,
+        notes,
+        SEND_POLICY,
+        defaults.send_policy,
+        parse_send_policy,
+    );
+    Settings {
+        log_level,
+        log_format,
+        log_max_size,
+        log_max_age: log_max_age_days
+            .map(|days: u64| Duration::from_secs(days.saturating_mul(DAY.as_secs()))),
+        log_generations,
+        log_compress,
+        handoff,
+        max_attachment_size,
+        max_total_attachment_size,
+        tmp_retention: Duration::from_secs(retention_days.saturating_mul(DAY.as_secs())),
+        send_policy,
+    }
+}
+
+/// parse the value with this name, noting if the default is used instead
+fn parse<T: Debug>(
+    value: &impl Fn(&str) -> Option<String>,
+    notes: &mut Vec<Note>,
+    name: &str,
+    default: T,
+    parse: impl Fn(&str) -> Option<T>,
+) -> T {
+    match value(name) {
+        None => {
+            notes.push((
+                LogLevel::Debug,
+                format!("{} is not set, using {:?}", name, default),
+            ));
+            default
+        }
+        Some(v) => parse(v.trim().to_lowercase().as_str()).unwrap_or_else(|| {
+            notes.push((
+                LogLevel::Warn,
+                format!("invalid {} {:?}, using {:?}", name, v, default),
+            ));
+            default
+        }),
+    }
+}
+
+fn parse_log_level(value: &str) -> Option<LogLevel> {
+    match value {
+        "off" => Some(LogLevel::Off),
+        "error" => Some(LogLevel::Error),
+        "warn" => Some(LogLevel::Warn),
+        "info" => Some(LogLevel::Info),
+        "debug" => Some(LogLevel::Debug),
+        "trace" => Some(LogLevel::Trace),
+        _ => None,
+    }
+}
+
+fn parse_log_format(value: &str) -> Option<LogFormat> {
+    match value {
+        "text" => Some(LogFormat::Text),
+        "json" => Some(LogFormat::Json),
+        _ => None,
+    }
+}
+
+fn parse_handoff(value: &str) -> Option<Handoff> {
+    match value {
+        "mailto" => Some(Handoff::Mailto),
+        "eml" => Some(Handoff::Eml),
+        "eml-reference" => Some(Handoff::EmlReference),
+        "manifest" => Some(Handoff::Manifest),
+        "ipc" => Some(Handoff::Ipc),
+        #[cfg(test)]
+        "recording" => Some(Handoff::Recording),
+        _ => None,
+    }
+}
+
+fn parse_send_policy(value: &str) -> Option<SendPolicy> {
+    match value {
+        "as-requested" => Some(SendPolicy::AsRequested),
+        "always-compose" => Some(SendPolicy::AlwaysCompose),
+        _ => None,
+    }
+}
+
+fn parse_bool(value: &str) -> Option<bool> {
+    match value {
+        "true" | "yes" | "1" => Some(true),
+        "false" | "no" | "0" => Some(false),
+        _ => None,
+    }
+}
+
+/// a size in bytes, 0 means there's no limit
+fn parse_size(value: &str) -> Option<Option<u64>> {
+    match value.parse() {
+        Ok(0) => Some(None),
+        Ok(size) => Some(Some(size)),
+        Err(_) => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+    use std::io;
+    use std::time::Duration;
+
+    use crate::environment;
+    use crate::settings::{
+        environment_value, from_values, load, Handoff, LogFormat, LogLevel, Note, SendPolicy,
+        Settings,
+    };
+
+    fn settings(values: &[(&str, &str)]) -> (Settings, Vec<Note>) {
+        let values: HashMap<&str, &str> = values.iter().cloned().collect();
+        let mut notes = vec![];
+        let settings = from_values(|name| values.get(name).map(|v| v.to_string()), &mut notes);
+        (settings, notes)
+    }
+
+    #[test]
+    fn defaults_are_used() {
+        let (defaults, notes) = settings(&[]);
+        assert_eq!(defaults, Settings::default());
+        assert_eq!(notes.len(), 11);
+        assert_eq!(
+            notes[0],
+            (
+                LogLevel::Debug,
+                "LOGLevel is not set, using Info".to_owned()
+            )
+        );
+    }
+
+    #[test]
+    fn values_are_parsed() {
+        let (parsed, notes) = settings(&[
+            ("LOGLevel", "Trace"),
+            ("LOGFormat", "json"),
+            ("LOGMaxSize", "0"),
+            ("LOGMaxAgeDays", "3"),
+            ("LOGGenerations", "0"),
+            ("LOGCompress", "yes"),
+            ("HANDOFF", "eml-reference"),
+            ("MAXAttachmentSize", "1024"),
+            ("MAXTotalAttachmentSize", "0"),
+            ("TMPRetentionDays", " 2 "),
+            ("SENDPolicy", "always-compose"),
+        ]);
+        assert!(notes.is_empty());
+        assert_eq!(
+            parsed,
+            Settings {
+                log_level: LogLevel::Trace,
+                log_format: LogFormat::Json,
+                log_max_size: None,
+                log_max_age: Some(Duration::from_secs(3 * 24 * 60 * 60)),
+                log_generations: 0,
+                log_compress: true,
+                handoff: Handoff::EmlReference,
+                max_attachment_size: Some(1024),
+                max_total_attachment_size: None,
+                tmp_retention: Duration::from_secs(2 * 24 * 60 * 60),
+                send_policy: SendPolicy::AlwaysCompose,
+            }
+        );
+    }
+
+    #[test]
+    fn invalid_values_are_reported() {
+        let (parsed, notes) = settings(&[
+            ("LOGLevel", "loud"),
+            ("LOGFormat", "xml"),
+            ("LOGMaxSize", "big"),
+            ("LOGMaxAgeDays", "-1"),
+            ("LOGGenerations", "1000"),
+            ("LOGCompress", "maybe"),
+            ("HANDOFF", "carrier-pigeon"),
+            ("MAXAttachmentSize", "-1"),
+            ("MAXTotalAttachmentSize", "1k"),
+            ("TMPRetentionDays", "forever"),
+            ("SENDPolicy", "never"),
+        ]);
+        assert_eq!(parsed, Settings::default());
+        assert_eq!(notes.len(), 11);
+        assert_eq!(
+            notes[6],
+            (
+                LogLevel::Warn,
+                "invalid HANDOFF \"carrier-pigeon\", using Mailto".to_owned()
+            )
+        );
+    }
+
+    #[test]
+    fn unreadable_values_are_reported() {
+        let notes = RefCell::new(vec![]);
+        assert_eq!(
+            environment_value("LOGMaxSize", Ok("10".to_owned()), &notes),
+            Some("10".to_owned())
+        );
+        let missing = io::Error::from(io::ErrorKind::NotFound);
+        assert_eq!(environment_value("LOGMaxSize", Err(missing), &notes), None);
+        assert!(notes.borrow().is_empty());
+
+        let wrong_type = io::Error::new(io::ErrorKind::InvalidData, "a binary value");
+        assert_eq!(
+            environment_value("LOGMaxSize", Err(wrong_type), &notes),
+            None
+        );
+        let notes = notes.into_inner();
+        assert_eq!(notes.len(), 1);
+        assert_eq!(notes[0].0, LogLevel::Warn);
+        assert!(notes[0].1.starts_with("could not read LOGMaxSize"));
+    }
+
+    #[test]
+    fn settings_file_overrides() {
+        let dir = std::env::temp_dir()
+            .join("mapirs_test")
+            .join("settings_file_overrides");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("settings.json");
+        std::fs::write(
+            &path,
+            r#"{"LOGLevel": "warn", "TMPRetentionDays": 1, "SENDPolicy": "bogus"}"#,
+        )
+        .unwrap();
+        environment::set_test_value("SETTINGSPath", path.to_str());
+        environment::set_test_value("LOGLevel", Some("debug"));
+        environment::set_test_value("HANDOFF", Some("ipc"));
+
+        let mut notes = vec![];
+        let loaded = load(&mut notes);
+        assert_eq!(loaded.log_level, LogLevel::Warn);
+        assert_eq!(loaded.handoff, Handoff::Ipc);
+        assert_eq!(loaded.tmp_retention, Duration::from_secs(24 * 60 * 60));
+        assert!(notes.contains(&(
+            LogLevel::Warn,
+            "invalid SENDPolicy \"bogus\", using AsRequested".to_owned()
+        )));
+
+        std::fs::write(&path, "{").unwrap();
+        let mut notes = vec![];
+        assert_eq!(load(&mut notes).log_level, LogLevel::Debug);
+        assert!(notes[0].1.starts_with("could not read settings file"));
+    }
+}
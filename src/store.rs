@@ -0,0 +1,456 @@
+use std::ffi::OsStr;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::SystemTime;
+
+use sha2::{Digest, Sha256};
+use time::{macros::format_description, OffsetDateTime};
+
+use crate::environment;
+use crate::flags::MapiStatusCode;
+use crate::logger;
+
+/// the longest id we accept from callers. the longest we hand out is much shorter.
+const MAX_ID_LENGTH: usize = 64;
+
+/// the size of the message id buffers callers give us, including the terminator.
+/// with LONG_MSGID, they're bigger.
+const MESSAGE_ID_BUFFER_SIZE: usize = 64;
+const LONG_MESSAGE_ID_BUFFER_SIZE: usize = 512;
+
+const MESSAGE_EXTENSION: &str = "eml";
+
+/// makes ids generated in the same instant different
+static ID_COUNTER: AtomicU32 = AtomicU32::new(0);
+
+/// messages in these subdirectories of a store are unread and read. this is how
+/// a maildir tells them apart, messages in the store directory itself count as read.
+const UNREAD_DIR: &str = "new";
+const READ_DIR: &str = "cur";
+
+/// deleted messages are moved here instead of being removed
+const TRASH_DIR: &str = "trash";
+
+/// the directory in TMPPath the outbox is in
+pub const OUTBOX_DIR: &str = "outbox";
+
+/// a directory of .eml files, named after their message id.
+#[derive(Debug)]
+pub struct MessageStore {
+    dir: PathBuf,
+}
+
+/// a message in a store, as returned by MessageStore::list
+#[derive(Debug)]
+pub struct StoredMessage {
+    pub id: String,
+    pub unread: bool,
+    /// when the file was last modified, which is when it got into the store
+    /// unless someone edited it.
+    pub received: SystemTime,
+}
+
+impl MessageStore {
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Self {
+        MessageStore { dir: dir.into() }
+    }
+
+    /// the store MAPISaveMail writes its drafts to
+    pub fn drafts() -> io::Result<Self> {
+        let dir: PathBuf = environment::drafts_path()?.into();
+        Ok(Self::new(dir))
+    }
+
+    /// the store the client exports received messages to, which is what
+    /// MAPIFindNext and MAPIReadMail work on.
+    pub fn inbox() -> io::Result<Self> {
+        let dir: PathBuf = environment::inbox_path()?.into();
+        Ok(Self::new(dir))
+    }
+
+    /// the store for the .eml files we hand messages to the client with
+    pub fn outbox() -> io::Result<Self> {
+        let mut dir: PathBuf = environment::tmp_path()?.into();
+        dir.push(OUTBOX_DIR);
+        Ok(Self::new(dir))
+    }
+
+    /// write a message to the store. if id is given, the message with that id is
+    /// replaced, otherwise a new id is generated.
+    ///
+    /// returns the id of the message.
+    pub fn save(&self, id: Option<&str>, contents: &[u8]) -> Result<String, MapiStatusCode> {
+        let (id, path) = match id {
+            Some(id) => match self.find(id) {
+                Some(path) => (id.to_owned(), path),
+                None => {
+                    logger::warn("MessageStore::save", &format!("no message with id {}", id));
+                    return Err(MapiStatusCode::InvalidMessage);
+                }
+            },
+            None => {
+                let id = new_id();
+                // we made the id ourselves, so this can't fail.
+                let path = self.path_for(&id).ok_or(MapiStatusCode::InvalidMessage)?;
+                (id, path)
+            }
+        };
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(|e| {
+                logger::error(
+                    "MessageStore::save",
+                    &format!("could not create dir: {:?}", e),
+                );
+                MapiStatusCode::Failure
+            })?;
+        }
+
+        // write to a temporary file first so the old version of a message
+        // survives if something goes wrong.
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, contents)
+            .and_then(|_| fs::rename(&tmp, &path))
+            .map_err(|e| {
+                logger::error("MessageStore::save", &format!("could not write: {:?}", e));
+                let _ = fs::remove_file(&tmp);
+                MapiStatusCode::Failure
+            })?;
+
+        Ok(id)
+    }
+
+    /// the path of the file for a new message id. returns None if the id
+    /// is not something we could have handed out, so callers can't make us
+    /// touch files outside of the store.
+    pub fn path_for(&self, id: &str) -> Option<PathBuf> {
+        if is_valid_id(id) {
+            Some(self.dir.join(file_name(id)))
+        } else {
+            None
+        }
+    }
+
+    /// the path of an existing message, wherever in the store it is.
+    pub fn find(&self, id: &str) -> Option<PathBuf> {
+        if !is_valid_id(id) {
+            return None;
+        }
+        self.dirs()
+            .into_iter()
+            .map(|(dir, _)| dir.join(file_name(id)))
+            .find(|p| p.is_file())
+    }
+
+    /// all messages in the store that have an id we can hand out, ordered by id.
+    /// other files are ignored.
+    pub fn list(&self) -> Vec<StoredMessage> {
+        let mut messages = vec![];
+        for (dir, unread) in self.dirs() {
+            let entries = match fs::read_dir(&dir) {
+                Ok(entries) => entries,
+                // the subdirectories are optional
+                Err(_) => continue,
+            };
+            for entry in entries.flatten() {
+                let id = match message_id_of(&entry.path()) {
+                    Some(id) => id,
+                    None => continue,
+                };
+                let received = match entry.metadata() {
+                    Ok(md) if md.is_file() => md.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+                    _ => continue,
+                };
+                messages.push(StoredMessage {
+                    id,
+                    unread,
+                    received,
+                });
+            }
+        }
+        messages.sort_by(|a, b| a.id.cmp(&b.id));
+        messages
+    }
+
+    /// move an unread message to the read ones. does nothing if the message
+    /// is read already.
+    pub fn mark_read(&self, id: &str) -> Result<(), MapiStatusCode> {
+        let path = self.find(id).ok_or(MapiStatusCode::InvalidMessage)?;
+        if !is_unread(&path) {
+            return Ok(());
+        }
+        let read_dir = self.dir.join(READ_DIR);
+        fs::create_dir_all(&read_dir)
+            .and_then(|_| fs::rename(&path, read_dir.join(file_name(id))))
+            .map_err(|e| {
+                logger::error(
+                    "MessageStore::mark_read",
+                    &format!("could not move: {:?}", e),
+                );
+                MapiStatusCode::Failure
+            })
+    }
+
+    /// move a message to the trash subdirectory of the store, where it's not
+    /// listed anymore. an older message with the same id in the trash is kept.
+    pub fn delete(&self, id: &str) -> Result<(), MapiStatusCode> {
+        let path = self.find(id).ok_or(MapiStatusCode::InvalidMessage)?;
+        let trash_dir = self.dir.join(TRASH_DIR);
+        let mut target = trash_dir.join(file_name(id));
+        let mut n = 0;
+        while target.exists() {
+            n += 1;
+            target = trash_dir.join(format!("{}-{}.{}", id, n, MESSAGE_EXTENSION));
+        }
+        fs::create_dir_all(&trash_dir)
+            .and_then(|_| fs::rename(&path, &target))
+            .map_err(|e| {
+                logger::error("MessageStore::delete", &format!("could not move: {:?}", e));
+                MapiStatusCode::Failure
+            })
+    }
+
+    /// the directories messages can be in and whether the messages in them are unread
+    fn dirs(&self) -> [(PathBuf, bool); 3] {
+        [
+            (self.dir.clone(), false),
+            (self.dir.join(UNREAD_DIR), true),
+            (self.dir.join(READ_DIR), false),
+        ]
+    }
+}
+
+/// look for a message in the stores we manage, the inbox first and then the drafts.
+/// returns the store the message is in and its path.
+pub fn find_message(id: &str) -> Option<(MessageStore, PathBuf)> {
+    [MessageStore::inbox(), MessageStore::drafts()]
+        .into_iter()
+        .flatten()
+        .find_map(|store| store.find(id).map(|path| (store, path)))
+}
+
+/// whether a message file is in the unread part of a store
+pub fn is_unread(path: &Path) -> bool {
+    path.parent().and_then(|p| p.file_name()) == Some(OsStr::new(UNREAD_DIR))
+}
+
+fn file_name(id: &str) -> String {
+    format!("{}.{}", id, MESSAGE_EXTENSION)
+}
+
+/// the id of a message file, None if it's not an .eml file with a valid id
+fn message_id_of(path: &Path) -> Option<String> {
+    if path.extension() != Some(OsStr::new(MESSAGE_EXTENSION)) {
+        return None;
+    }
+    let id = path.file_stem()?.to_str()?;
+    if is_valid_id(id) {
+        Some(id.to_owned())
+    } else {
+        None
+    }
+}
+
+/// the size of the message id buffer the caller gave us, depending on
+/// whether it passed LONG_MSGID
+pub fn id_buffer_size(long_msgid: bool) -> usize {
+    if long_msgid {
+        LONG_MESSAGE_ID_BUFFER_SIZE
+    } else {
+        MESSAGE_ID_BUFFER_SIZE
+    }
+}
+
+/// the message that comes after the seed in a list of messages, like MAPIFindNext
+/// needs it. the first message if there's no seed, None if there are no more.
+/// the messages are filtered after looking for the seed, so the seed can be a
+/// message that doesn't match anymore (like one that was just read).
+///
+/// returns Err if the seed is not in the list.
+pub fn next_after<'a>(
+    messages: &'a [StoredMessage],
+    seed: Option<&str>,
+    unread_only: bool,
+) -> Result<Option<&'a StoredMessage>, MapiStatusCode> {
+    let start = match seed {
+        None => 0,
+        Some(seed) => {
+            messages
+                .iter()
+                .position(|m| m.id == seed)
+                .ok_or(MapiStatusCode::InvalidMessage)?
+                + 1
+        }
+    };
+    Ok(messages[start..].iter().find(|m| !unread_only || m.unread))
+}
+
+/// ids only contain ASCII alphanumerics and dashes
+pub fn is_valid_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_ID_LENGTH
+        && id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+}
+
+/// make a new message id from the current time and some
+/// hashed entropy, like 20220801T130509-1a2b3c4d
+pub fn new_id() -> String {
+    let now = OffsetDateTime::now_utc();
+    let desc = format_description!("[year][month][day]T[hour][minute][second]");
+    let timestamp = now
+        .format(desc)
+        .unwrap_or_else(|_| "00000000T000000".to_owned());
+
+    let mut sha256 = Sha256::new();
+    sha256.update(now.unix_timestamp_nanos().to_le_bytes());
+    sha256.update(std::process::id().to_le_bytes());
+    sha256.update(ID_COUNTER.fetch_add(1, Ordering::Relaxed).to_le_bytes());
+    let hash = sha256.finalize();
+    let suffix: String = hash[..4].iter().map(|b| format!("{:02x}", b)).collect();
+    format!("{}-{}", timestamp, suffix)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::PathBuf;
+
+    use crate::flags::MapiStatusCode;
+    use crate::store::{is_valid_id, new_id, next_after, MessageStore};
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join("mapirs_test").join(name);
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn is_valid_id_works() {
+        assert!(is_valid_id("20220801T130509-1a2b3c4d"));
+        assert!(is_valid_id(&new_id()));
+        assert!(!is_valid_id(""));
+        assert!(!is_valid_id("..\\..\\Windows\\win"));
+        assert!(!is_valid_id("../etc/passwd"));
+        assert!(!is_valid_id("a.eml"));
+        assert!(!is_valid_id(&"a".repeat(65)));
+    }
+
+    #[test]
+    fn new_ids_differ() {
+        assert_ne!(new_id(), new_id());
+    }
+
+    #[test]
+    fn save_works() {
+        let dir = test_dir("save_works");
+        let store = MessageStore::new(&dir);
+
+        let id = store.save(None, b"first").unwrap();
+        assert!(store.find(&id).is_some());
+        assert_eq!(fs::read(store.path_for(&id).unwrap()).unwrap(), b"first");
+
+        // saving with an existing id updates in place
+        assert_eq!(store.save(Some(&id), b"second"), Ok(id.clone()));
+        assert_eq!(fs::read(store.path_for(&id).unwrap()).unwrap(), b"second");
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        assert_eq!(
+            store.save(Some("20000101T000000-00000000"), b"third"),
+            Err(MapiStatusCode::InvalidMessage)
+        );
+        assert_eq!(
+            store.save(Some("../escape"), b"third"),
+            Err(MapiStatusCode::InvalidMessage)
+        );
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn list_and_mark_read_work() {
+        let dir = test_dir("list_and_mark_read_work");
+        fs::create_dir_all(dir.join("new")).unwrap();
+        fs::create_dir_all(dir.join("cur")).unwrap();
+        fs::write(dir.join("new").join("b.eml"), b"b").unwrap();
+        fs::write(dir.join("cur").join("a.eml"), b"a").unwrap();
+        fs::write(dir.join("c.eml"), b"c").unwrap();
+        // not messages we can hand out ids for
+        fs::write(dir.join("d.txt"), b"d").unwrap();
+        fs::write(dir.join("e f.eml"), b"e").unwrap();
+        let store = MessageStore::new(&dir);
+
+        let listed: Vec<(String, bool)> =
+            store.list().into_iter().map(|m| (m.id, m.unread)).collect();
+        assert_eq!(
+            listed,
+            vec![
+                ("a".to_owned(), false),
+                ("b".to_owned(), true),
+                ("c".to_owned(), false)
+            ]
+        );
+
+        assert_eq!(store.mark_read("b"), Ok(()));
+        assert!(dir.join("cur").join("b.eml").is_file());
+        assert!(store.list().iter().all(|m| !m.unread));
+        assert_eq!(store.mark_read("b"), Ok(()));
+        assert_eq!(store.mark_read("x"), Err(MapiStatusCode::InvalidMessage));
+
+        // updating a message leaves it where it is
+        assert_eq!(store.save(Some("a"), b"a2"), Ok("a".to_owned()));
+        assert_eq!(fs::read(dir.join("cur").join("a.eml")).unwrap(), b"a2");
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn next_after_works() {
+        let dir = test_dir("next_after_works");
+        fs::create_dir_all(dir.join("new")).unwrap();
+        fs::write(dir.join("a.eml"), b"a").unwrap();
+        fs::write(dir.join("new").join("b.eml"), b"b").unwrap();
+        fs::write(dir.join("c.eml"), b"c").unwrap();
+        let messages = MessageStore::new(&dir).list();
+        let next = |seed, unread_only| {
+            next_after(&messages, seed, unread_only).map(|m| m.map(|m| m.id.as_str()))
+        };
+
+        assert_eq!(next(None, false), Ok(Some("a")));
+        assert_eq!(next(Some("a"), false), Ok(Some("b")));
+        assert_eq!(next(Some("c"), false), Ok(None));
+        assert_eq!(next(None, true), Ok(Some("b")));
+        assert_eq!(next(Some("b"), true), Ok(None));
+        assert_eq!(next(Some("x"), false), Err(MapiStatusCode::InvalidMessage));
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn delete_works() {
+        let dir = test_dir("delete_works");
+        fs::create_dir_all(dir.join("new")).unwrap();
+        fs::write(dir.join("new").join("a.eml"), b"first").unwrap();
+        let store = MessageStore::new(&dir);
+
+        assert_eq!(store.delete("a"), Ok(()));
+        assert!(store.find("a").is_none());
+        assert!(store.list().is_empty());
+        assert_eq!(fs::read(dir.join("trash").join("a.eml")).unwrap(), b"first");
+
+        // deleting the same id again doesn't clobber the trash
+        fs::write(dir.join("a.eml"), b"second").unwrap();
+        assert_eq!(store.delete("a"), Ok(()));
+        assert_eq!(fs::read(dir.join("trash").join("a.eml")).unwrap(), b"first");
+        assert_eq!(
+            fs::read(dir.join("trash").join("a-1.eml")).unwrap(),
+            b"second"
+        );
+
+        assert_eq!(store.delete("a"), Err(MapiStatusCode::InvalidMessage));
+        assert_eq!(store.delete("../a"), Err(MapiStatusCode::InvalidMessage));
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -1,17 +1,22 @@
 use std::convert::{From, TryFrom};
-#[cfg(not(test))]
 use std::fs;
 use std::path::{Path, PathBuf};
 
-use crate::commands::log_to_file;
+use crate::allocation::BufferChain;
 use crate::environment::make_subfolder_name_from_content;
 use crate::ffi::conversion;
+use crate::ffi::conversion::StringDecoder;
 use crate::file_path::FilePath;
 use crate::flags::MapiFileFlags;
+use crate::logger;
+use crate::retention;
 use crate::types::*;
 
 const FALLBACK_TMP_SUBDIR_PATH: &str = "xxxxxxxx";
 
+/// nPosition for attachments that don't replace a char in the message text
+const NO_POSITION: ULong = 0xFFFF_FFFF;
+
 #[repr(C)]
 #[derive(Debug)]
 pub struct RawMapiFileTagExt {
@@ -83,6 +88,24 @@ pub struct RawMapiFileDesc {
     file_type: *const RawMapiFileTagExt,
 }
 
+/// the same as RawMapiFileDesc, but with UTF-16 strings
+#[repr(C)]
+#[derive(Debug)]
+pub struct RawMapiFileDescW {
+    // ULONG  ulReserved - must be zero
+    reserved: ULong,
+    // ULONG  flFlags - flags
+    flags: MapiFileFlags,
+    // ULONG  nPosition - character in text to be replaced by attachment
+    position: ULong,
+    // PWSTR  lpszPathName - full path name of attachment file
+    pub path_name: LpWStr,
+    // PWSTR  lpszFileName - original file name (optional)
+    file_name: LpWStr,
+    // LPVOID lpFileType - attachment file type (can be lpMapiFileTagExt)
+    file_type: *const RawMapiFileTagExt,
+}
+
 #[derive(Debug)]
 pub struct FileDescriptor {
     _flags: MapiFileFlags,
@@ -98,24 +121,60 @@ impl TryFrom<&RawMapiFileDesc> for FileDescriptor {
     type Error = ();
 
     fn try_from(raw: &RawMapiFileDesc) -> Result<Self, Self::Error> {
-        if let Some(file_path) =
-            conversion::maybe_string_from_raw_ptr(raw.path_name).map(PathBuf::from)
-        {
+        Self::try_from_raw(raw, &StringDecoder::default())
+    }
+}
+
+impl TryFrom<&RawMapiFileDescW> for FileDescriptor {
+    type Error = ();
+
+    fn try_from(raw: &RawMapiFileDescW) -> Result<Self, Self::Error> {
+        Self::from_parts(
+            raw.flags,
+            raw.position,
+            conversion::maybe_string_from_raw_wide_ptr(raw.path_name),
+            conversion::maybe_string_from_raw_wide_ptr(raw.file_name),
+            raw.file_type,
+        )
+    }
+}
+
+impl FileDescriptor {
+    /// convert a RawMapiFileDesc, decoding its strings with the
+    /// code page of the message it belongs to
+    pub fn try_from_raw(raw: &RawMapiFileDesc, decoder: &StringDecoder) -> Result<Self, ()> {
+        Self::from_parts(
+            raw.flags,
+            raw.position,
+            decoder.decode(raw.path_name, "file path_name"),
+            decoder.decode(raw.file_name, "file file_name"),
+            raw.file_type,
+        )
+    }
+
+    /// build a FileDescriptor from the already converted fields
+    /// of a RawMapiFileDesc or RawMapiFileDescW
+    fn from_parts(
+        flags: MapiFileFlags,
+        position: ULong,
+        path_name: Option<String>,
+        file_name: Option<String>,
+        file_type: *const RawMapiFileTagExt,
+    ) -> Result<Self, ()> {
+        if let Some(file_path) = path_name.map(PathBuf::from) {
             let file_path: FilePath = FilePath::try_from(file_path)?;
             Ok(FileDescriptor {
-                _flags: raw.flags,
-                _position: raw.position,
+                _flags: flags,
+                _position: position,
                 path_name: file_path,
-                file_name: conversion::maybe_string_from_raw_ptr(raw.file_name).map(PathBuf::from),
-                _file_type: FileTagExtension::try_from(raw.file_type).ok(),
+                file_name: file_name.map(PathBuf::from),
+                _file_type: FileTagExtension::try_from(file_type).ok(),
             })
         } else {
             Err(())
         }
     }
-}
 
-impl FileDescriptor {
     pub fn new(file_path: &str, file_name: Option<&str>) -> Self {
         Self {
             _flags: MapiFileFlags::empty(),
@@ -126,6 +185,35 @@ impl FileDescriptor {
         }
     }
 
+    /// a descriptor for a file we wrote ourselves, like an attachment
+    /// extracted from a message we read.
+    pub fn extracted(path_name: FilePath, file_name: String) -> Self {
+        Self {
+            _flags: MapiFileFlags::empty(),
+            _position: NO_POSITION,
+            path_name,
+            file_name: Some(PathBuf::from(file_name)),
+            _file_type: None,
+        }
+    }
+
+    /// build a RawMapiFileDesc that can be handed to the calling app, see
+    /// RecipientDescriptor::to_raw
+    pub fn to_raw(&self, chain: &mut BufferChain, encoder: &StringDecoder) -> RawMapiFileDesc {
+        let file_name = self
+            .file_name
+            .as_deref()
+            .unwrap_or_else(|| self.path_name.file_name().as_ref());
+        RawMapiFileDesc {
+            reserved: 0,
+            flags: self._flags,
+            position: self._position,
+            path_name: chain.add_string(encoder.encode(&self.path_name.as_ref().to_string_lossy())),
+            file_name: chain.add_string(encoder.encode(&file_name.to_string_lossy())),
+            file_type: std::ptr::null(),
+        }
+    }
+
     /// check if the last component of the file descriptor's path is different from its file_name.
     /// returns false if file_name is None
     fn needs_new_name(&self) -> bool {
@@ -164,14 +252,13 @@ impl FileDescriptor {
         self.path_name.clone().into()
     }
 
-    #[cfg(not(test))]
     fn copy_file_to_tmp_subdir(&self, tmp_path: &Path, tmp_name: &Path) -> Option<PathBuf> {
         let sub_name = make_subfolder_name_from_content(&self.path_name)
             .unwrap_or_else(|_| FALLBACK_TMP_SUBDIR_PATH.to_owned());
         let tmp_subdir_path = tmp_path.join(sub_name);
 
-        if fs::create_dir_all(&tmp_subdir_path).is_err() {
-            log_to_file(
+        if retention::create_attachment_dir(&tmp_subdir_path).is_err() {
+            logger::error(
                 "FileDescriptor::copy_file_to_tmp_subdir",
                 "failed to create temporary directory for attachment",
             );
@@ -181,7 +268,7 @@ impl FileDescriptor {
         let dest = tmp_subdir_path.join(tmp_name);
 
         if fs::copy(&self.path_name, &dest).is_err() {
-            log_to_file(
+            logger::error(
                 "FileDescriptor::copy_file_to_tmp_subdir",
                 "failed to copy file",
             );
@@ -190,59 +277,94 @@ impl FileDescriptor {
 
         Some(dest)
     }
-
-    #[cfg(test)]
-    fn copy_file_to_tmp_subdir(&self, tmp_path: &PathBuf, tmp_name: &PathBuf) -> Option<PathBuf> {
-        Some(tmp_path.join(FALLBACK_TMP_SUBDIR_PATH).join(tmp_name))
-    }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::path::PathBuf;
+    use std::convert::TryFrom;
+    use std::fs;
+    use std::path::{Path, PathBuf};
 
-    use crate::structs::file_descriptor::FALLBACK_TMP_SUBDIR_PATH;
-    use crate::structs::FileDescriptor;
+    use crate::environment;
+    use crate::environment::make_subfolder_name_from_content;
+    use crate::flags::MapiFileFlags;
+    use crate::structs::{FileDescriptor, RawMapiFileDescW};
 
     #[test]
     fn needs_new_name_works() {
-        assert!(FileDescriptor::new(&"C:\\hello.txt", Some("ciao.txt")).needs_new_name());
+        let path = Path::new("some").join("hello.txt");
+        let path = path.to_str().unwrap();
+        assert!(FileDescriptor::new(path, Some("ciao.txt")).needs_new_name());
 
-        assert!(!FileDescriptor::new(&"C:\\hello.txt", Some("hello.txt")).needs_new_name());
-        assert!(!FileDescriptor::new(&"C:\\hello.txt", None).needs_new_name());
+        assert!(!FileDescriptor::new(path, Some("hello.txt")).needs_new_name());
+        assert!(!FileDescriptor::new(path, None).needs_new_name());
     }
 
     #[test]
-    // TODO test the case where copying the file fails (would require some kind of refactoring, or just a static variable hack)
     fn consolidate_into_works() {
+        let dir = std::env::temp_dir()
+            .join("mapirs_test")
+            .join("consolidate_into_works");
+        fs::create_dir_all(&dir).unwrap();
+        let source = dir.join("hello.txt");
+        fs::write(&source, b"hello").unwrap();
+        let source = source.to_str().unwrap();
+        let tmp_path: PathBuf = environment::tmp_path().unwrap().into();
+        let subdir = tmp_path.join(make_subfolder_name_from_content(source).unwrap());
+
+        let consolidated = FileDescriptor::new(source, Some("hello.txt"))
+            .consolidate_into(&Some(tmp_path.clone()));
         assert_eq!(
-            FileDescriptor::new(&"C:\\User\\Doccies\\hello.txt", Some("hello.txt"))
-                .consolidate_into(&Some("C:\\User\\TmpDir".into())),
-            PathBuf::from(format!(
-                "C:\\User\\TmpDir\\{}\\hello.txt",
-                FALLBACK_TMP_SUBDIR_PATH
-            )),
+            consolidated,
+            subdir.join("hello.txt"),
             "If the same file name is given, then it is copied with the same filename"
         );
+        assert_eq!(fs::read(consolidated).unwrap(), b"hello");
 
         assert_eq!(
-            FileDescriptor::new(&"C:\\User\\Doccies\\hello.txt", Some("ciao.txt"))
-                .consolidate_into(&Some("C:\\User\\TmpDir".into())),
-            PathBuf::from(format!(
-                "C:\\User\\TmpDir\\{}\\ciao.txt",
-                FALLBACK_TMP_SUBDIR_PATH
-            )),
+            FileDescriptor::new(source, Some("ciao.txt")).consolidate_into(&Some(tmp_path.clone())),
+            subdir.join("ciao.txt"),
             "If a different file name is given, then it copies with the new filename",
         );
 
         assert_eq!(
-            FileDescriptor::new(&"C:\\User\\Doccies\\hello.txt", None)
-                .consolidate_into(&Some("C:\\User\\TmpDir".into())),
-            PathBuf::from(format!(
-                "C:\\User\\TmpDir\\{}\\hello.txt",
-                FALLBACK_TMP_SUBDIR_PATH
-            )),
+            FileDescriptor::new(source, None).consolidate_into(&Some(tmp_path.clone())),
+            subdir.join("hello.txt"),
             "If no file name is given, then it copies with the original filename"
         );
+
+        let missing = dir.join("missing.txt");
+        let missing = missing.to_str().unwrap();
+        assert_eq!(
+            FileDescriptor::new(missing, None).consolidate_into(&Some(tmp_path)),
+            PathBuf::from(missing),
+            "If the file can't be copied, then the original path is used"
+        );
+    }
+
+    #[test]
+    fn wide_file_descriptor_is_converted() {
+        let path: Vec<u16> = "C:\\Users\\Jürgen\\Überweisung.pdf\0"
+            .encode_utf16()
+            .collect();
+        let name: Vec<u16> = "Überweisung März.pdf\0".encode_utf16().collect();
+        let raw = |file_name| RawMapiFileDescW {
+            reserved: 0,
+            flags: MapiFileFlags::empty(),
+            position: 0,
+            path_name: path.as_ptr(),
+            file_name,
+            file_type: std::ptr::null(),
+        };
+
+        let desc = FileDescriptor::try_from(&raw(name.as_ptr())).unwrap();
+        assert_eq!(
+            desc.path_name.as_ref(),
+            PathBuf::from("C:\\Users\\Jürgen\\Überweisung.pdf")
+        );
+        assert_eq!(desc.file_name, Some(PathBuf::from("Überweisung März.pdf")));
+
+        let desc = FileDescriptor::try_from(&raw(std::ptr::null())).unwrap();
+        assert_eq!(desc.file_name, None);
     }
 }
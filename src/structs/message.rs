@@ -1,13 +1,30 @@
 use std::convert::TryFrom;
-use std::path::PathBuf;
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
 
-use urlencoding::encode;
+use sha2::{Digest, Sha256};
+use time::OffsetDateTime;
 
-use crate::commands::log_to_file;
+use crate::allocation::BufferChain;
 use crate::environment;
 use crate::ffi::conversion;
-use crate::flags::MapiMessageFlags;
-use crate::structs::{FileDescriptor, RawMapiFileDesc, RawMapiRecipDesc, RecipientDescriptor};
+use crate::ffi::conversion::StringDecoder;
+use crate::file_path::{sanitize_file_name, FilePath};
+use crate::flags::{
+    MapiMessageFlags, MapiReadMailFlags, MapiRecipClass, MapiStatusCode, MessageCodePage,
+};
+use crate::logger;
+use crate::mailto;
+use crate::manifest::{Caller, Manifest, ManifestAttachment, ManifestRecipient, MANIFEST_VERSION};
+use crate::mime;
+use crate::retention;
+use crate::sanitize;
+use crate::structs::{
+    FileDescriptor, RawMapiFileDesc, RawMapiFileDescW, RawMapiRecipDesc, RawMapiRecipDescW,
+    RecipientDescriptor,
+};
 use crate::types::*;
 
 /// https://docs.microsoft.com/en-us/windows/win32/api/mapi/ns-mapi-mapimessage
@@ -40,24 +57,78 @@ pub struct RawMapiMessage {
     files: *const RawMapiFileDesc,
 }
 
+/// https://docs.microsoft.com/en-us/windows/win32/api/mapi/ns-mapi-mapimessagew
+/// the same as RawMapiMessage, but with UTF-16 strings
+#[repr(C)]
+#[derive(Debug)]
+pub struct RawMapiMessageW {
+    // ULONG ulReserved - reserved, must be 0
+    reserved: ULong,
+    // PWSTR lpszSubject - message subject
+    subject: LpWStr,
+    // PWSTR lpszNoteText - message text
+    note_text: LpWStr,
+    // PWSTR lpszMessageType - message class
+    message_type: LpWStr,
+    // PWSTR lpszDateReceived - in YYYY/MM/DD HH:MM format
+    date_received: LpWStr,
+    // PWSTR lpszConversationID - conversation thread id
+    conversation_id: LpWStr,
+    // FLAGS flFlags
+    flags: MapiMessageFlags,
+    // lpMapiRecipDescW lpOriginator
+    originator: *const RawMapiRecipDescW,
+    // ULONG nRecipCount - number of recipients
+    recip_count: ULong,
+    // lpMapiRecipDescW lpRecips - recipient descriptors
+    recips: *const RawMapiRecipDescW,
+    // ULONG nFileCount - # of file attachments
+    file_count: ULong,
+    // lpMapiFileDescW lpFiles - attachment descriptors
+    files: *const RawMapiFileDescW,
+}
+
+impl RawMapiMessageW {
+    /// the strings and recipients have to outlive the message
+    #[cfg(test)]
+    pub fn new(subject: LpWStr, note_text: LpWStr, recips: &[RawMapiRecipDescW]) -> Self {
+        Self {
+            reserved: 0,
+            subject,
+            note_text,
+            message_type: std::ptr::null(),
+            date_received: std::ptr::null(),
+            conversation_id: std::ptr::null(),
+            flags: MapiMessageFlags::empty(),
+            originator: std::ptr::null(),
+            recip_count: recips.len() as ULong,
+            recips: recips.as_ptr(),
+            file_count: 0,
+            files: std::ptr::null(),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Message {
     subject: Option<String>,
     note_text: Option<String>,
     _message_type: Option<String>,
     _date_received: Option<String>,
-    _conversation_id: Option<String>,
-    _flags: MapiMessageFlags,
-    _originator: Option<RecipientDescriptor>,
+    conversation_id: Option<String>,
+    flags: MapiMessageFlags,
+    originator: Option<RecipientDescriptor>,
     recips: Vec<RecipientDescriptor>,
     files: Vec<FileDescriptor>,
 }
 
+/// fails with Failure for a null pointer and with the error of
+/// the first recipient whose address can't be normalized.
 impl TryFrom<*const RawMapiMessage> for Message {
-    type Error = ();
+    type Error = MapiStatusCode;
     fn try_from(raw_ptr: *const RawMapiMessage) -> Result<Self, Self::Error> {
         if raw_ptr.is_null() {
-            Err(())
+            Err(MapiStatusCode::Failure)
         } else {
             /*
             SAFETY: https://doc.rust-lang.org/book/ch19-01-unsafe-rust.html#dereferencing-a-raw-pointer
@@ -75,46 +146,240 @@ impl TryFrom<*const RawMapiMessage> for Message {
                 -> we got the ptr over ffi, so the calling app needs to clean this up
             */
             let raw = unsafe { &*raw_ptr };
-            let originator_result = RecipientDescriptor::try_from(raw.originator);
-            let recips: Vec<RecipientDescriptor> =
-                conversion::raw_to_vec(raw.recips, raw.recip_count as usize)
-                    .into_iter()
-                    .flatten()
-                    .collect();
-            if recips.len() < raw.recip_count as usize {
-                log_to_file(
-                    "Message::from::<RawMapiMessage>",
-                    "could not parse one or more RecipientDescriptors",
-                );
-            }
-            let files: Vec<FileDescriptor> = conversion::raw_to_vec::<
-                FileDescriptor,
-                RawMapiFileDesc,
-            >(raw.files, raw.file_count as usize)
-            .into_iter()
-            .flatten()
-            .collect();
-            if files.len() < raw.file_count as usize {
-                log_to_file(
+            let decoder = StringDecoder::new(MessageCodePage::from(raw.reserved));
+            Ok(Message {
+                subject: decoder.decode(raw.subject, "subject"),
+                note_text: decoder.decode(raw.note_text, "note_text"),
+                _message_type: decoder.decode(raw.message_type, "message_type"),
+                _date_received: decoder.decode(raw.date_received, "date_received"),
+                conversation_id: decoder.decode(raw.conversation_id, "conversation_id"),
+                flags: raw.flags,
+                originator: RecipientDescriptor::try_from_raw_ptr(raw.originator, &decoder)
+                    .ok()
+                    .and_then(|o| o.into_iter().next()),
+                recips: convert_recipients(raw.recips, raw.recip_count, |r| {
+                    RecipientDescriptor::from_raw(r, &decoder)
+                })?,
+                files: convert_descriptors(
                     "Message::from::<RawMapiMessage>",
-                    "could not parse one or more FileDescriptors",
-                );
+                    raw.files,
+                    raw.file_count,
+                    "FileDescriptors",
+                    |f| FileDescriptor::try_from_raw(f, &decoder).ok(),
+                ),
             }
+            .take_orig_recips())
+        }
+    }
+}
+
+/// see TryFrom<*const RawMapiMessage>
+impl TryFrom<*const RawMapiMessageW> for Message {
+    type Error = MapiStatusCode;
+    fn try_from(raw_ptr: *const RawMapiMessageW) -> Result<Self, Self::Error> {
+        if raw_ptr.is_null() {
+            Err(MapiStatusCode::Failure)
+        } else {
+            // SAFETY: see TryFrom<*const RawMapiMessage>
+            let raw = unsafe { &*raw_ptr };
             Ok(Message {
-                subject: conversion::maybe_string_from_raw_ptr(raw.subject),
-                note_text: conversion::maybe_string_from_raw_ptr(raw.note_text),
-                _message_type: conversion::maybe_string_from_raw_ptr(raw.message_type),
-                _date_received: conversion::maybe_string_from_raw_ptr(raw.date_received),
-                _conversation_id: conversion::maybe_string_from_raw_ptr(raw.conversation_id),
-                _flags: raw.flags,
-                _originator: originator_result.ok(),
-                recips,
-                files,
-            })
+                subject: conversion::maybe_string_from_raw_wide_ptr(raw.subject),
+                note_text: conversion::maybe_string_from_raw_wide_ptr(raw.note_text),
+                _message_type: conversion::maybe_string_from_raw_wide_ptr(raw.message_type),
+                _date_received: conversion::maybe_string_from_raw_wide_ptr(raw.date_received),
+                conversation_id: conversion::maybe_string_from_raw_wide_ptr(raw.conversation_id),
+                flags: raw.flags,
+                originator: RecipientDescriptor::try_from_raw_wide_ptr(raw.originator)
+                    .ok()
+                    .and_then(|o| o.into_iter().next()),
+                recips: convert_recipients(raw.recips, raw.recip_count, |r| Vec::try_from(r))?,
+                files: convert_descriptors(
+                    "Message::from::<RawMapiMessageW>",
+                    raw.files,
+                    raw.file_count,
+                    "FileDescriptors",
+                    |f| FileDescriptor::try_from(f).ok(),
+                ),
+            }
+            .take_orig_recips())
         }
     }
 }
 
+/// convert the recipient or file descriptor array of a raw message,
+/// dropping (and logging) the ones that can't be converted
+fn convert_descriptors<K, T>(
+    caller: &str,
+    ptr: *const T,
+    count: ULong,
+    what: &str,
+    convert: impl Fn(&T) -> Option<K>,
+) -> Vec<K> {
+    let converted: Vec<K> = conversion::raw_to_vec(ptr, count as usize, convert)
+        .into_iter()
+        .flatten()
+        .collect();
+    if converted.len() < count as usize {
+        logger::error(caller, &format!("could not parse one or more {}", what));
+    }
+    converted
+}
+
+/// how the attachments of a message end up in an .eml file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AttachmentMode {
+    /// the contents of the files are part of the .eml
+    Embed,
+    /// the .eml only contains the paths of (copies of) the files
+    Reference,
+}
+
+/// the name an attachment should have, which is file_name if it's set
+fn attachment_name(desc: &FileDescriptor) -> String {
+    desc.file_name
+        .as_ref()
+        .and_then(|n| n.file_name())
+        .unwrap_or_else(|| desc.path_name.file_name())
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// an attachment as it's written to an .eml file
+enum EmlAttachment {
+    Contents(Vec<u8>),
+    Path(String),
+}
+
+/// convert the recipient descriptor array of a raw message. unlike files,
+/// recipients we can't convert are an error instead of being dropped.
+fn convert_recipients<T>(
+    ptr: *const T,
+    count: ULong,
+    convert: impl Fn(&T) -> Result<Vec<RecipientDescriptor>, MapiStatusCode>,
+) -> Result<Vec<RecipientDescriptor>, MapiStatusCode> {
+    let converted = conversion::raw_to_vec(ptr, count as usize, |r| Some(convert(r)))
+        .into_iter()
+        .flatten()
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(converted.into_iter().flatten().collect())
+}
+
+/// sanitize the name of a recipient, InvalidRecips if its address isn't safe to hand off
+fn sanitize_recipient(
+    mut recip: RecipientDescriptor,
+) -> Result<RecipientDescriptor, MapiStatusCode> {
+    recip.name = sanitize::header_value("recipient name", &recip.name);
+    match &recip.address {
+        Some(address) if !sanitize::is_safe_address(address) => Err(MapiStatusCode::InvalidRecips),
+        _ => Ok(recip),
+    }
+}
+
+/// the mailbox for an address header, None if the recipient has no address
+fn mailbox(recip: &RecipientDescriptor) -> Option<String> {
+    recip
+        .address
+        .as_ref()
+        .map(|address| mime::encode_mailbox(&recip.name, address))
+}
+
+/// the parts of a message we read that end up in a Message
+#[derive(Default)]
+struct EmlContents {
+    /// the first text/plain part
+    text: Option<String>,
+    /// the first text/html part, used if there is no plain text
+    html: Option<String>,
+    /// file name (if there is one) and decoded contents
+    attachments: Vec<(Option<String>, Vec<u8>)>,
+}
+
+impl EmlContents {
+    fn collect(&mut self, part: &mime::Part) {
+        let subparts = part.subparts();
+        if !subparts.is_empty() {
+            for sub in &subparts {
+                self.collect(sub);
+            }
+            return;
+        }
+
+        let (mime_type, _) = part.content_type();
+        if !part.is_attachment() {
+            if mime_type == "text/plain" && self.text.is_none() {
+                self.text = Some(part.text());
+                return;
+            }
+            if mime_type == "text/html" && self.html.is_none() {
+                self.html = Some(part.text());
+                return;
+            }
+            if mime_type.starts_with("multipart/") {
+                // a multipart without a boundary, nothing we can do
+                return;
+            }
+        }
+        let name = part.file_name().or_else(|| {
+            if mime_type == "message/rfc822" {
+                Some("message.eml".to_owned())
+            } else {
+                None
+            }
+        });
+        self.attachments.push((name, part.decoded_body()));
+    }
+}
+
+/// write a file that belongs to a message we read into its own subdirectory of
+/// tmp_path, named after a hash of the contents like the attachments we send.
+fn extract_file(
+    tmp_path: &Option<PathBuf>,
+    name: &str,
+    contents: &[u8],
+) -> Result<FileDescriptor, MapiStatusCode> {
+    let tmp_path = tmp_path
+        .as_ref()
+        .ok_or(MapiStatusCode::AttachmentWriteFailure)?;
+    let mut sha256 = Sha256::new();
+    sha256.update(contents);
+    let dir = tmp_path.join(environment::sha_head(sha256.finalize()));
+    let path = dir.join(name);
+    retention::create_attachment_dir(&dir)
+        .and_then(|_| fs::write(&path, contents))
+        .map_err(|e| {
+            logger::error(
+                "Message::from_eml",
+                &format!("could not extract {}: {:?}", name, e),
+            );
+            MapiStatusCode::AttachmentWriteFailure
+        })?;
+    let path = FilePath::try_from(path).map_err(|_| MapiStatusCode::AttachmentWriteFailure)?;
+    Ok(FileDescriptor::extracted(path, name.to_owned()))
+}
+
+/// convert the value of a Date header (like "Mon, 1 Aug 2022 13:05:09 +0200")
+/// to the YYYY/MM/DD HH:MM format of lpszDateReceived. the time stays in the
+/// time zone of the header.
+fn mapi_date(date: &str) -> Option<String> {
+    const MONTHS: [&str; 12] = [
+        "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+    ];
+    // the day of the week is optional
+    let date = date.split_once(',').map(|(_, d)| d).unwrap_or(date);
+    let mut tokens = date.split_whitespace();
+    let day: u8 = tokens.next()?.parse().ok()?;
+    let month = tokens.next()?.to_ascii_lowercase();
+    let month = MONTHS.iter().position(|m| month.starts_with(m))? + 1;
+    let year: u16 = tokens.next()?.parse().ok()?;
+    let mut time = tokens.next()?.split(':');
+    let hour: u8 = time.next()?.parse().ok()?;
+    let minute: u8 = time.next()?.parse().ok()?;
+    Some(format!(
+        "{:04}/{:02}/{:02} {:02}:{:02}",
+        year, month, day, hour, minute
+    ))
+}
+
 impl Message {
     /// Copy the files to be attached to a temp directory that's accessible by tutanota.
     /// it copies the file from the file path to the temp directory and renames it so the
@@ -141,47 +406,388 @@ impl Message {
     }
 
     pub fn make_mailto_link(&self) -> String {
-        // MAPI message only has a recipient array, so we use the first one for the
-        // address and put the rest (comma-separated) into cc.
-        let to = self
-            .recips
-            .get(0)
-            .map(|r| r.address.clone())
-            .unwrap_or_else(|| Some("".to_owned()))
-            .unwrap();
-        let cc = self
-            .recips
-            .iter()
-            .skip(1)
-            .filter_map(|r| r.address.clone())
-            .collect::<Vec<String>>();
-        let subject = self.subject.as_ref().cloned();
-        let body = self.note_text.as_ref().cloned();
-
-        let mut url_parts = vec![];
+        let addresses = |class| {
+            self.recips_of_class(class)
+                .into_iter()
+                .filter_map(|r| r.address.clone())
+                .collect::<Vec<String>>()
+        };
+        let to = addresses(MapiRecipClass::To);
+        let cc = addresses(MapiRecipClass::Cc);
+        let bcc = addresses(MapiRecipClass::Bcc);
+        let mut hfields = vec![];
 
         if !cc.is_empty() {
-            url_parts.push(format!("cc={}", cc.join(",")));
+            hfields.push(("cc", mailto::encode_addresses(&cc)));
+        }
+
+        if !bcc.is_empty() {
+            hfields.push(("bcc", mailto::encode_addresses(&bcc)));
         }
 
-        if let Some(subject_text) = subject {
-            url_parts.push(format!("subject={}", encode(&subject_text)));
+        if let Some(subject_text) = &self.subject {
+            hfields.push(("subject", mailto::encode_value(subject_text)));
         }
 
-        if let Some(body_text) = body {
-            url_parts.push(format!("body={}", encode(&body_text)));
+        if let Some(body_text) = &self.note_text {
+            hfields.push(("body", mailto::encode_body(body_text)));
         }
 
         for attachment in self.ensure_attachments() {
             if let Some(fp) = attachment.to_str() {
-                url_parts.push(format!("attach={}", encode(fp)));
+                hfields.push(("attach", mailto::encode_value(fp)));
             }
         }
-        let lnk = format!("mailto:{}?{}", to, url_parts.join("&"));
-        log_to_file("make_mailto", "finished");
+        let lnk = mailto::build(&to, &hfields);
+        logger::debug("make_mailto", "finished");
         lnk
     }
 
+    /// some apps put the sender into the recipient array as MAPI_ORIG instead of
+    /// (or in addition to) lpOriginator. those entries are not recipients, the first
+    /// one becomes the originator if there is none.
+    fn take_orig_recips(mut self) -> Self {
+        let (orig, recips): (Vec<RecipientDescriptor>, Vec<RecipientDescriptor>) = self
+            .recips
+            .into_iter()
+            .partition(|r| r.recip_class == MapiRecipClass::Orig as ULong);
+        self.recips = recips;
+        if self.originator.is_none() {
+            self.originator = orig.into_iter().next();
+        }
+        self
+    }
+
+    /// fold control characters out of the header-like fields. fails with InvalidRecips
+    /// if any address could add fields to what we hand to the client, the message
+    /// isn't sent to the remaining recipients then.
+    /// everything we get from the calling app goes through this before it's handed off.
+    pub fn sanitize(mut self) -> Result<Self, MapiStatusCode> {
+        self.subject = self
+            .subject
+            .map(|subject| sanitize::header_value("subject", &subject));
+        self.originator = self.originator.map(sanitize_recipient).transpose()?;
+        self.recips = self
+            .recips
+            .into_iter()
+            .map(sanitize_recipient)
+            .collect::<Result<_, _>>()?;
+        Ok(self)
+    }
+
+    /// true if there's at least one recipient (not the originator) with an address
+    pub fn has_valid_recipients(&self) -> bool {
+        self.recips.iter().any(|r| {
+            r.recip_class != MapiRecipClass::Orig as ULong
+                && r.address.as_deref().map(|a| !a.trim().is_empty()) == Some(true)
+        })
+    }
+
+    /// fails with Failure if an attachment is bigger than max_size or all of them together
+    /// are bigger than max_total. attachments we can't read are left to the handoff.
+    pub fn check_attachment_sizes(
+        &self,
+        max_size: Option<u64>,
+        max_total: Option<u64>,
+    ) -> Result<(), MapiStatusCode> {
+        let mut total: u64 = 0;
+        for desc in &self.files {
+            let path: &Path = desc.path_name.as_ref();
+            let size = match fs::metadata(path) {
+                Ok(metadata) => metadata.len(),
+                Err(_) => continue,
+            };
+            if max_size.map(|max| size > max) == Some(true) {
+                logger::warn(
+                    "Message::check_attachment_sizes",
+                    &format!("{:?} is too big ({} bytes)", path, size),
+                );
+                return Err(MapiStatusCode::Failure);
+            }
+            total = total.saturating_add(size);
+        }
+        if max_total.map(|max| total > max) == Some(true) {
+            logger::warn(
+                "Message::check_attachment_sizes",
+                &format!("the attachments are too big ({} bytes)", total),
+            );
+            return Err(MapiStatusCode::Failure);
+        }
+        Ok(())
+    }
+
+    /// the recipients that go into the header for a recipient class. recipients
+    /// with a class we don't know are treated as MAPI_TO.
+    fn recips_of_class(&self, class: MapiRecipClass) -> Vec<&RecipientDescriptor> {
+        let known = [
+            MapiRecipClass::Orig as ULong,
+            MapiRecipClass::Cc as ULong,
+            MapiRecipClass::Bcc as ULong,
+        ];
+        let class = class as ULong;
+        self.recips
+            .iter()
+            .filter(|r| {
+                r.recip_class == class
+                    || (class == MapiRecipClass::To as ULong && !known.contains(&r.recip_class))
+            })
+            .collect()
+    }
+
+    /// render the message as an RFC 5322 .eml file with the attachments embedded or
+    /// referenced, depending on mode.
+    /// the message is marked as unsent, so mail clients open it as a draft.
+    pub fn to_eml(
+        &self,
+        date: OffsetDateTime,
+        mode: AttachmentMode,
+    ) -> Result<Vec<u8>, MapiStatusCode> {
+        let mut attachments = vec![];
+        match mode {
+            AttachmentMode::Embed => {
+                for desc in &self.files {
+                    let contents = fs::read(&desc.path_name).map_err(|e| {
+                        logger::error(
+                            "Message::to_eml",
+                            &format!("could not read attachment: {:?}", e),
+                        );
+                        MapiStatusCode::AttachmentOpenFailure
+                    })?;
+                    attachments.push((attachment_name(desc), EmlAttachment::Contents(contents)));
+                }
+            }
+            AttachmentMode::Reference => {
+                // the copies, because the calling app may delete its files as soon as we return.
+                for path in self.ensure_attachments() {
+                    let name = path
+                        .file_name()
+                        .map(|n| n.to_string_lossy().into_owned())
+                        .unwrap_or_default();
+                    let path = path.to_string_lossy().into_owned();
+                    attachments.push((name, EmlAttachment::Path(path)));
+                }
+            }
+        }
+
+        let mut out: Vec<u8> = vec![];
+        self.write_eml(&mut out, date, &attachments)
+            .map_err(|_| MapiStatusCode::Failure)?;
+        Ok(out)
+    }
+
+    fn write_eml<W: Write>(
+        &self,
+        out: &mut W,
+        date: OffsetDateTime,
+        attachments: &[(String, EmlAttachment)],
+    ) -> io::Result<()> {
+        write!(out, "Date: {}\r\n", mime::format_date(date))?;
+        if let Some(from) = self.originator.as_ref().and_then(mailbox) {
+            write!(out, "From: {}\r\n", from)?;
+        }
+        for (header, class) in [
+            ("To", MapiRecipClass::To),
+            ("Cc", MapiRecipClass::Cc),
+            ("Bcc", MapiRecipClass::Bcc),
+        ] {
+            let mailboxes: Vec<String> = self
+                .recips_of_class(class)
+                .into_iter()
+                .filter_map(mailbox)
+                .collect();
+            if !mailboxes.is_empty() {
+                write!(out, "{}: {}\r\n", header, mailboxes.join(",\r\n "))?;
+            }
+        }
+        if let Some(subject) = &self.subject {
+            write!(out, "Subject: {}\r\n", mime::encode_header_value(subject))?;
+        }
+        write!(out, "X-Unsent: 1\r\nMIME-Version: 1.0\r\n")?;
+
+        let body = self.note_text.as_deref().unwrap_or("");
+        if attachments.is_empty() {
+            return mime::write_text_part(out, body);
+        }
+
+        let boundary = mime::make_boundary(body.as_bytes());
+        write!(
+            out,
+            "Content-Type: multipart/mixed; boundary=\"{}\"\r\n\r\n",
+            boundary
+        )?;
+        write!(out, "--{}\r\n", boundary)?;
+        mime::write_text_part(out, body)?;
+        for (name, attachment) in attachments {
+            write!(out, "--{}\r\n", boundary)?;
+            match attachment {
+                EmlAttachment::Contents(contents) => {
+                    mime::write_attachment_part(out, name, contents)?
+                }
+                EmlAttachment::Path(path) => mime::write_external_attachment_part(out, name, path)?,
+            }
+        }
+        write!(out, "--{}--\r\n", boundary)
+    }
+
+    /// describe the message for the manifest handoff. the attachments are copied
+    /// to TMPPath like for the mailto link and the manifest points to the copies.
+    pub fn to_manifest(&self) -> Result<Manifest, MapiStatusCode> {
+        let recipient = |r: &RecipientDescriptor| ManifestRecipient {
+            // unknown classes end up in To, like in recips_of_class
+            class: MapiRecipClass::try_from(r.recip_class).unwrap_or(MapiRecipClass::To),
+            name: r.name.clone(),
+            address: r.address.clone(),
+        };
+        let attachments = self
+            .files
+            .iter()
+            .zip(self.ensure_attachments())
+            .map(|(desc, copy)| {
+                ManifestAttachment::new(
+                    attachment_name(desc),
+                    copy.to_string_lossy().into_owned(),
+                    desc.path_name.as_ref(),
+                )
+                .map_err(|e| {
+                    logger::error(
+                        "Message::to_manifest",
+                        &format!("could not read attachment: {:?}", e),
+                    );
+                    MapiStatusCode::AttachmentOpenFailure
+                })
+            })
+            .collect::<Result<Vec<ManifestAttachment>, MapiStatusCode>>()?;
+
+        Ok(Manifest {
+            version: MANIFEST_VERSION,
+            subject: self.subject.clone(),
+            body: self.note_text.clone(),
+            originator: self.originator.as_ref().map(recipient),
+            recipients: self.recips.iter().map(recipient).collect(),
+            conversation_id: self.conversation_id.clone(),
+            flags: self.flags.bits(),
+            attachments,
+            caller: Caller::current(),
+            call_id: logger::call_id(),
+        })
+    }
+
+    /// read a message from an .eml file, as MAPIReadMail returns it. attachments are
+    /// extracted to subdirectories of tmp_path, depending on flags.
+    pub fn from_eml(
+        bytes: &[u8],
+        flags: MapiReadMailFlags,
+        tmp_path: &Option<PathBuf>,
+    ) -> Result<Self, MapiStatusCode> {
+        let part = mime::Part::parse(bytes);
+        let mut recips = vec![];
+        for (header, class) in [
+            ("To", MapiRecipClass::To),
+            ("Cc", MapiRecipClass::Cc),
+            ("Bcc", MapiRecipClass::Bcc),
+        ] {
+            for mailbox in part.mailboxes(header) {
+                recips.push(RecipientDescriptor::issued(
+                    class,
+                    mailbox.name,
+                    mailbox.address,
+                ));
+            }
+        }
+        let originator = part.mailboxes("From").into_iter().next().map(|mailbox| {
+            RecipientDescriptor::issued(MapiRecipClass::Orig, mailbox.name, mailbox.address)
+        });
+
+        let mut msg = Message {
+            subject: part.decoded_header("Subject"),
+            note_text: None,
+            _message_type: None,
+            _date_received: part.header("Date").and_then(mapi_date),
+            conversation_id: None,
+            flags: MapiMessageFlags::empty(),
+            originator,
+            recips,
+            files: vec![],
+        };
+        if flags.contains(MapiReadMailFlags::ENVELOPE_ONLY) {
+            return Ok(msg);
+        }
+
+        let mut contents = EmlContents::default();
+        contents.collect(&part);
+        let text = contents.text.or(contents.html).unwrap_or_default();
+        if flags.contains(MapiReadMailFlags::BODY_AS_FILE) {
+            let desc = extract_file(tmp_path, "body.txt", text.as_bytes())?;
+            msg.files.push(desc);
+        } else {
+            msg.note_text = Some(text);
+        }
+        if flags.contains(MapiReadMailFlags::SUPPRESS_ATTACH) {
+            return Ok(msg);
+        }
+
+        for (idx, (name, contents)) in contents.attachments.iter().enumerate() {
+            let name = name
+                .as_deref()
+                .and_then(sanitize_file_name)
+                .unwrap_or_else(|| format!("attachment-{}", idx + 1));
+            msg.files.push(extract_file(tmp_path, &name, contents)?);
+        }
+        Ok(msg)
+    }
+
+    /// mark the message as unread for the calling app
+    pub fn set_unread(&mut self, unread: bool) {
+        self.flags.set(MapiMessageFlags::UNREAD, unread);
+    }
+
+    /// build a RawMapiMessage that can be handed to the calling app, see
+    /// RecipientDescriptor::to_raw
+    pub fn to_raw(&self, chain: &mut BufferChain, encoder: &StringDecoder) -> RawMapiMessage {
+        let mut add_optional = |s: &Option<String>| match s {
+            Some(s) => chain.add_string(encoder.encode(s)),
+            None => std::ptr::null(),
+        };
+        let subject = add_optional(&self.subject);
+        let note_text = add_optional(&self.note_text);
+        let message_type = add_optional(&self._message_type);
+        let date_received = add_optional(&self._date_received);
+        let conversation_id = add_optional(&self.conversation_id);
+
+        let originator = match &self.originator {
+            Some(o) => {
+                let raw = o.to_raw(chain, encoder);
+                chain.add_vec(vec![raw])
+            }
+            None => std::ptr::null(),
+        };
+        let recips: Vec<RawMapiRecipDesc> = self
+            .recips
+            .iter()
+            .map(|r| r.to_raw(chain, encoder))
+            .collect();
+        let files: Vec<RawMapiFileDesc> = self
+            .files
+            .iter()
+            .map(|f| f.to_raw(chain, encoder))
+            .collect();
+
+        RawMapiMessage {
+            reserved: 0,
+            subject,
+            note_text,
+            message_type,
+            date_received,
+            conversation_id,
+            flags: self.flags,
+            originator,
+            recip_count: recips.len() as ULong,
+            recips: chain.add_vec(recips),
+            file_count: files.len() as ULong,
+            files: chain.add_vec(files),
+        }
+    }
+
     #[cfg(test)]
     pub fn new(
         to: Vec<&str>,
@@ -194,9 +800,9 @@ impl Message {
             note_text: body.map(|b| b.to_owned()),
             _message_type: None,
             _date_received: None,
-            _conversation_id: None,
-            _flags: MapiMessageFlags::empty(),
-            _originator: None,
+            conversation_id: None,
+            flags: MapiMessageFlags::empty(),
+            originator: None,
             recips: to
                 .into_iter()
                 .map(|t| RecipientDescriptor::new(t))
@@ -225,9 +831,9 @@ impl Message {
             note_text: None,
             _message_type: None,
             _date_received: None,
-            _conversation_id: None,
-            _flags: MapiMessageFlags::empty(),
-            _originator: None,
+            conversation_id: None,
+            flags: MapiMessageFlags::empty(),
+            originator: None,
             recips: vec![],
             files,
         }
@@ -236,32 +842,60 @@ impl Message {
 
 #[cfg(test)]
 mod tests {
-    use crate::structs::{FileDescriptor, Message};
+    use std::convert::TryFrom;
+    use std::fs;
+    use std::path::PathBuf;
+
+    use time::macros::datetime;
+
+    use crate::environment;
+    use crate::flags::{MapiMessageFlags, MapiReadMailFlags, MapiRecipClass, MapiStatusCode};
+    use crate::mailto;
+    use crate::manifest::MANIFEST_VERSION;
+    use crate::structs::message::mapi_date;
+    use crate::structs::{
+        AttachmentMode, FileDescriptor, Message, RawMapiMessageW, RecipientDescriptor,
+    };
+    use crate::types::ULong;
 
     #[test]
     fn message_make_mailto_works() {
         assert_eq!(
             Message::new(vec![], None, None, vec![]).make_mailto_link(),
-            "mailto:?"
+            "mailto:"
         );
 
         assert_eq!(
             Message::new(vec!["a@b.de", "b@c.de", "d@g.de"], None, None, vec![]).make_mailto_link(),
-            "mailto:a@b.de?cc=b@c.de,d@g.de"
+            "mailto:a@b.de,b@c.de,d@g.de"
         );
 
+        // attachments are copied into a subdirectory of TMPPath named after their contents
+        let dir = std::env::temp_dir()
+            .join("mapirs_test")
+            .join("message_make_mailto_works");
+        std::fs::create_dir_all(&dir).unwrap();
+        let source = dir.join("path file.jpg");
+        std::fs::write(&source, b"jpg").unwrap();
+        let subdir = PathBuf::from(environment::tmp_path().unwrap())
+            .join(environment::make_subfolder_name_from_content(&source).unwrap());
+        let attach = |name: &str| {
+            format!(
+                "mailto:a@b.de?attach={}",
+                urlencoding::encode(&subdir.join(name).to_string_lossy())
+            )
+        };
+        let source = source.to_str().unwrap();
+
         assert_eq!(
             Message::new(
                 vec!["a@b.de"],
                 None,
                 None,
-                vec![FileDescriptor::new(
-                    "C:\\some\\path file.jpg",
-                    "file.txt".into(),
-                )],
+                vec![FileDescriptor::new(source, "file.txt".into())],
             )
             .make_mailto_link(),
-            "mailto:a@b.de?attach=C%3A%5Ctmp%5Cxxxxxxxx%5Cfile.txt"
+            attach("file.txt")
         );
 
         assert_eq!(
@@ -269,10 +903,10 @@ mod tests {
                 vec!["a@b.de"],
                 None,
                 None,
-                vec![FileDescriptor::new("C:\\some\\path file.jpg", None)],
+                vec![FileDescriptor::new(source, None)],
             )
             .make_mailto_link(),
-            "mailto:a@b.de?attach=C%3A%5Ctmp%5Cxxxxxxxx%5Cpath%20file.jpg"
+            attach("path file.jpg")
         );
 
         assert_eq!(Message::new(
@@ -282,4 +916,406 @@ mod tests {
             vec![],
         ).make_mailto_link(), "mailto:a@b.de?subject=b%C3%B6rk%20%26%20%3F%20%5C%20%2520%20&body=b%C3%B6rk%20%26%20%3F%20%3D%20%2F%20%5C");
     }
+
+    #[test]
+    fn recipient_classes_are_respected() {
+        let mut msg = Message::new(
+            vec![
+                "to1@b.de",
+                "cc@b.de",
+                "to2@b.de",
+                "bcc1@b.de",
+                "bcc2@b.de",
+                "orig@b.de",
+            ],
+            None,
+            None,
+            vec![],
+        );
+        let classes = [
+            MapiRecipClass::To,
+            MapiRecipClass::Cc,
+            MapiRecipClass::To,
+            MapiRecipClass::Bcc,
+            MapiRecipClass::Bcc,
+            MapiRecipClass::Orig,
+        ];
+        for (recip, class) in msg.recips.iter_mut().zip(classes) {
+            recip.recip_class = class as ULong;
+        }
+        let msg = msg.take_orig_recips();
+        assert_eq!(
+            msg.originator.as_ref().and_then(|o| o.address.as_deref()),
+            Some("orig@b.de")
+        );
+        assert_eq!(msg.recips.len(), 5);
+        assert_eq!(
+            msg.make_mailto_link(),
+            "mailto:to1@b.de,to2@b.de?cc=cc@b.de&bcc=bcc1@b.de,bcc2@b.de"
+        );
+    }
+
+    #[test]
+    fn mailto_link_round_trips() {
+        let mut msg = Message::new(
+            vec![
+                "\"a&b?c%d,e f\"@x.de",
+                "jörg@xn--bcher-kva.de",
+                "cc@b.de",
+                "bcc+1@b.de",
+                "\"bcc=2#\"@b.de",
+            ],
+            "Grüße & so?\nline 2\r\n%20 =".into(),
+            "börk & ? = / \\ #".into(),
+            vec![FileDescriptor::new("C:\\some\\path & file.jpg", None)],
+        );
+        let classes = [
+            MapiRecipClass::To,
+            MapiRecipClass::To,
+            MapiRecipClass::Cc,
+            MapiRecipClass::Bcc,
+            MapiRecipClass::Bcc,
+        ];
+        for (recip, class) in msg.recips.iter_mut().zip(classes) {
+            recip.recip_class = class as ULong;
+        }
+
+        let parsed = mailto::parse(&msg.make_mailto_link()).unwrap();
+        assert_eq!(
+            parsed.to,
+            vec!["\"a&b?c%d,e f\"@x.de", "jörg@xn--bcher-kva.de"]
+        );
+        assert_eq!(parsed.cc, vec!["cc@b.de"]);
+        assert_eq!(parsed.bcc, vec!["bcc+1@b.de", "\"bcc=2#\"@b.de"]);
+        assert_eq!(parsed.subject, msg.subject);
+        assert_eq!(
+            parsed.body.as_deref(),
+            Some("Grüße & so?\r\nline 2\r\n%20 =")
+        );
+        let attachments: Vec<String> = msg
+            .ensure_attachments()
+            .iter()
+            .map(|a| a.to_str().unwrap().to_owned())
+            .collect();
+        assert_eq!(parsed.attach, attachments);
+    }
+
+    #[test]
+    fn sanitize_removes_injections() {
+        let mut msg = Message::new(
+            vec!["a@b.de", "\"a?body=evil&bcc=e\"@b.de", "c@d.de"],
+            "line 1\r\nline 2".into(),
+            "hi\r\nBcc: evil@attacker.com\0".into(),
+            vec![],
+        );
+        msg.recips[0].name = "Jane\r\nBcc: evil@attacker.com".to_owned();
+        let msg = msg.sanitize().unwrap();
+
+        assert_eq!(msg.subject.as_deref(), Some("hi Bcc: evil@attacker.com"));
+        // the body isn't a header, line breaks are fine there
+        assert_eq!(msg.note_text.as_deref(), Some("line 1\r\nline 2"));
+        assert_eq!(msg.recips.len(), 3);
+        assert_eq!(msg.recips[0].name, "Jane Bcc: evil@attacker.com");
+        // URI delimiters in a quoted local part are encoded, not dropped
+        assert_eq!(
+            msg.make_mailto_link(),
+            "mailto:a@b.de,%22a%3Fbody%3Devil%26bcc%3De%22@b.de,c@d.de?subject=hi%20Bcc%3A%20evil%40attacker.com&body=line%201%0D%0Aline%202"
+        );
+    }
+
+    #[test]
+    fn sanitize_refuses_hostile_recipients() {
+        let msg = Message::new(
+            vec!["a@b.de", "a@b.de\r\nBcc: evil@attacker.com"],
+            None,
+            None,
+            vec![],
+        );
+        assert_eq!(msg.sanitize().unwrap_err(), MapiStatusCode::InvalidRecips);
+
+        let mut msg = Message::new(vec!["a@b.de", "me@b.de\nBcc: x@y.z"], None, None, vec![]);
+        msg.originator = Some(msg.recips.remove(1));
+        assert_eq!(msg.sanitize().unwrap_err(), MapiStatusCode::InvalidRecips);
+    }
+
+    #[test]
+    fn has_valid_recipients_works() {
+        assert!(!Message::new(vec![], None, None, vec![]).has_valid_recipients());
+        assert!(!Message::new(vec![" "], None, None, vec![]).has_valid_recipients());
+        assert!(Message::new(vec!["", "a@b.de"], None, None, vec![]).has_valid_recipients());
+
+        let mut msg = Message::new(vec!["a@b.de"], None, None, vec![]);
+        msg.recips[0].recip_class = MapiRecipClass::Orig as ULong;
+        assert!(!msg.has_valid_recipients());
+    }
+
+    #[test]
+    fn wide_message_is_converted() {
+        let wide = |s: &str| s.encode_utf16().chain(Some(0)).collect::<Vec<u16>>();
+        let subject = wide("Rechnung für Jürgen");
+        let body = wide("Grüße aus Köln ☕");
+        let raw = RawMapiMessageW {
+            reserved: 0,
+            subject: subject.as_ptr(),
+            note_text: body.as_ptr(),
+            message_type: std::ptr::null(),
+            date_received: std::ptr::null(),
+            conversation_id: std::ptr::null(),
+            flags: MapiMessageFlags::empty(),
+            originator: std::ptr::null(),
+            recip_count: 0,
+            recips: std::ptr::null(),
+            file_count: 0,
+            files: std::ptr::null(),
+        };
+
+        let msg = Message::try_from(&raw as *const RawMapiMessageW).unwrap();
+        assert_eq!(msg.subject, Some("Rechnung für Jürgen".to_owned()));
+        assert_eq!(msg.note_text, Some("Grüße aus Köln ☕".to_owned()));
+        assert!(msg.recips.is_empty());
+        assert!(msg.originator.is_none());
+
+        assert!(Message::try_from(std::ptr::null::<RawMapiMessageW>()).is_err());
+    }
+
+    #[test]
+    fn to_eml_works() {
+        let msg = Message::new(
+            vec!["a@b.de", "c@d.de"],
+            "hello\nworld".into(),
+            "Grüße".into(),
+            vec![],
+        );
+        let eml = msg
+            .to_eml(datetime!(2022-08-01 13:05:09 UTC), AttachmentMode::Embed)
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(eml).unwrap(),
+            "Date: Mon, 01 Aug 2022 13:05:09 +0000\r\n\
+             To: <a@b.de>,\r\n <c@d.de>\r\n\
+             Subject: =?UTF-8?B?R3LDvMOfZQ==?=\r\n\
+             X-Unsent: 1\r\n\
+             MIME-Version: 1.0\r\n\
+             Content-Type: text/plain; charset=utf-8\r\n\
+             Content-Transfer-Encoding: 7bit\r\n\r\n\
+             hello\r\nworld\r\n"
+        );
+    }
+
+    #[test]
+    fn check_attachment_sizes_works() {
+        let dir = std::env::temp_dir()
+            .join("mapirs_test")
+            .join("check_attachment_sizes_works");
+        fs::create_dir_all(&dir).unwrap();
+        let (a, b) = (dir.join("a.txt"), dir.join("b.txt"));
+        fs::write(&a, b"hello").unwrap();
+        fs::write(&b, b"world!").unwrap();
+        let msg = Message::new(
+            vec!["a@b.de"],
+            None,
+            None,
+            vec![
+                FileDescriptor::new(a.to_str().unwrap(), None),
+                FileDescriptor::new(b.to_str().unwrap(), None),
+                FileDescriptor::new(dir.join("missing").to_str().unwrap(), None),
+            ],
+        );
+        assert_eq!(msg.check_attachment_sizes(None, None), Ok(()));
+        assert_eq!(msg.check_attachment_sizes(Some(6), Some(11)), Ok(()));
+        assert_eq!(
+            msg.check_attachment_sizes(Some(5), None),
+            Err(MapiStatusCode::Failure)
+        );
+        assert_eq!(
+            msg.check_attachment_sizes(None, Some(10)),
+            Err(MapiStatusCode::Failure)
+        );
+    }
+
+    #[test]
+    fn to_eml_references_attachments() {
+        let dir = std::env::temp_dir()
+            .join("mapirs_test")
+            .join("to_eml_references_attachments");
+        std::fs::create_dir_all(&dir).unwrap();
+        let source = dir.join("file.jpg");
+        std::fs::write(&source, b"jpg").unwrap();
+        let msg = Message::new(
+            vec!["a@b.de"],
+            None,
+            None,
+            vec![FileDescriptor::new(
+                source.to_str().unwrap(),
+                "Büro.pdf".into(),
+            )],
+        );
+        let eml = msg
+            .to_eml(
+                datetime!(2022-08-01 13:05:09 UTC),
+                AttachmentMode::Reference,
+            )
+            .unwrap();
+        let eml = String::from_utf8(eml).unwrap();
+        assert!(eml.contains("Content-Type: multipart/mixed; boundary="));
+        let tmp_path = environment::tmp_path().unwrap();
+        assert!(eml.contains(&format!(
+            "Content-Type: message/external-body; access-type=local-file; name*=UTF-8''{}",
+            urlencoding::encode(&tmp_path.to_string_lossy())
+        )));
+        assert!(eml.contains("Content-Disposition: attachment; filename*=UTF-8''B%C3%BCro.pdf\r\n"));
+        assert!(!eml.contains("Content-Transfer-Encoding: base64"));
+
+        // embedding needs the file to be there
+        let missing = dir.join("missing.jpg");
+        let msg = Message::new(
+            vec!["a@b.de"],
+            None,
+            None,
+            vec![FileDescriptor::new(missing.to_str().unwrap(), None)],
+        );
+        assert_eq!(
+            msg.to_eml(datetime!(2022-08-01 13:05:09 UTC), AttachmentMode::Embed),
+            Err(MapiStatusCode::AttachmentOpenFailure)
+        );
+    }
+
+    #[test]
+    fn to_manifest_works() {
+        let dir = std::env::temp_dir()
+            .join("mapirs_test")
+            .join("to_manifest_works");
+        std::fs::create_dir_all(&dir).unwrap();
+        let source = dir.join("hello.txt");
+        std::fs::write(&source, b"hello").unwrap();
+
+        let mut msg = Message::new(
+            vec!["to@b.de", "cc@b.de", "weird@b.de"],
+            "body".into(),
+            "subject".into(),
+            vec![FileDescriptor::new(
+                source.to_str().unwrap(),
+                "Büro.txt".into(),
+            )],
+        );
+        msg.recips[1].recip_class = MapiRecipClass::Cc as ULong;
+        msg.recips[2].recip_class = 17;
+        msg.originator = Some(RecipientDescriptor::new("me@b.de"));
+        msg.conversation_id = Some("conv".to_owned());
+        msg.flags = MapiMessageFlags::RECEIPT_REQUESTED;
+
+        let manifest = msg.to_manifest().unwrap();
+        assert_eq!(manifest.version, MANIFEST_VERSION);
+        assert_eq!(manifest.subject.as_deref(), Some("subject"));
+        assert_eq!(manifest.body.as_deref(), Some("body"));
+        assert_eq!(
+            manifest.originator.and_then(|o| o.address).as_deref(),
+            Some("me@b.de")
+        );
+        let classes: Vec<MapiRecipClass> = manifest.recipients.iter().map(|r| r.class).collect();
+        assert_eq!(
+            classes,
+            vec![MapiRecipClass::To, MapiRecipClass::Cc, MapiRecipClass::To]
+        );
+        assert_eq!(manifest.conversation_id.as_deref(), Some("conv"));
+        assert_eq!(manifest.flags, MapiMessageFlags::RECEIPT_REQUESTED.bits());
+        assert_eq!(manifest.attachments.len(), 1);
+        assert_eq!(manifest.attachments[0].name, "Büro.txt");
+        assert_eq!(manifest.attachments[0].size, 5);
+        assert_eq!(manifest.caller.pid, std::process::id());
+
+        msg.files = vec![FileDescriptor::new(
+            dir.join("nope").to_str().unwrap(),
+            None,
+        )];
+        assert_eq!(
+            msg.to_manifest(),
+            Err(MapiStatusCode::AttachmentOpenFailure)
+        );
+    }
+
+    #[test]
+    fn mapi_date_works() {
+        assert_eq!(
+            mapi_date("Mon, 1 Aug 2022 13:05:09 +0200"),
+            Some("2022/08/01 13:05".to_owned())
+        );
+        assert_eq!(
+            mapi_date("24 Dec 2021 08:00 GMT"),
+            Some("2021/12/24 08:00".to_owned())
+        );
+        assert_eq!(mapi_date("yesterday"), None);
+    }
+
+    #[test]
+    fn from_eml_works() {
+        let eml = b"From: \"Doe, Jane\" <jane@b.de>\r\n\
+            To: a@b.de\r\n\
+            Cc: c@b.de\r\n\
+            Date: Mon, 1 Aug 2022 13:05:09 +0200\r\n\
+            Subject: hello\r\n\
+            Content-Type: multipart/mixed; boundary=b\r\n\r\n\
+            --b\r\n\
+            Content-Type: multipart/alternative; boundary=c\r\n\r\n\
+            --c\r\n\
+            Content-Type: text/html\r\n\r\n\
+            <p>hi</p>\r\n\
+            --c\r\n\
+            Content-Type: text/plain\r\n\r\n\
+            hi\r\n\
+            --c--\r\n\
+            --b\r\n\
+            Content-Type: application/pdf; name=\"../a.pdf\"\r\n\
+            Content-Transfer-Encoding: base64\r\n\r\n\
+            JVBERg==\r\n\
+            --b--\r\n";
+        let tmp = std::env::temp_dir()
+            .join("mapirs_test")
+            .join("from_eml_works");
+        let _ = std::fs::remove_dir_all(&tmp);
+        let tmp_path = Some(tmp.clone());
+
+        let msg = Message::from_eml(eml, MapiReadMailFlags::empty(), &tmp_path).unwrap();
+        assert_eq!(msg.subject, Some("hello".to_owned()));
+        assert_eq!(msg.note_text, Some("hi".to_owned()));
+        assert_eq!(msg._date_received, Some("2022/08/01 13:05".to_owned()));
+        let originator = msg.originator.as_ref().unwrap();
+        assert_eq!(originator.name, "Doe, Jane");
+        assert_eq!(originator.recip_class, MapiRecipClass::Orig as ULong);
+        let recips: Vec<(ULong, Option<&str>)> = msg
+            .recips
+            .iter()
+            .map(|r| (r.recip_class, r.address.as_deref()))
+            .collect();
+        assert_eq!(
+            recips,
+            vec![
+                (MapiRecipClass::To as ULong, Some("a@b.de")),
+                (MapiRecipClass::Cc as ULong, Some("c@b.de"))
+            ]
+        );
+        assert_eq!(msg.files.len(), 1);
+        let file = &msg.files[0];
+        assert_eq!(file.path_name.file_name(), "a.pdf");
+        assert!(file.path_name.as_ref().starts_with(&tmp));
+        assert_eq!(std::fs::read(&file.path_name).unwrap(), b"%PDF");
+
+        let msg = Message::from_eml(eml, MapiReadMailFlags::SUPPRESS_ATTACH, &None).unwrap();
+        assert_eq!(msg.note_text, Some("hi".to_owned()));
+        assert!(msg.files.is_empty());
+
+        let msg = Message::from_eml(eml, MapiReadMailFlags::ENVELOPE_ONLY, &None).unwrap();
+        assert_eq!(msg.note_text, None);
+        assert_eq!(msg.recips.len(), 2);
+
+        let msg = Message::from_eml(eml, MapiReadMailFlags::BODY_AS_FILE, &tmp_path).unwrap();
+        assert_eq!(msg.note_text, None);
+        assert_eq!(msg.files.len(), 2);
+        assert_eq!(std::fs::read(&msg.files[0].path_name).unwrap(), b"hi");
+
+        // attachments need somewhere to go
+        assert!(Message::from_eml(eml, MapiReadMailFlags::empty(), &None).is_err());
+
+        let _ = std::fs::remove_dir_all(&tmp);
+    }
 }
@@ -1,6 +1,6 @@
-pub use file_descriptor::{FileDescriptor, FileTagExtension, RawMapiFileDesc, RawMapiFileTagExt};
-pub use message::{Message, RawMapiMessage};
-pub use recipient_descriptor::{RawMapiRecipDesc, RecipientDescriptor};
+pub use file_descriptor::{FileDescriptor, RawMapiFileDesc, RawMapiFileDescW};
+pub use message::{AttachmentMode, Message, RawMapiMessage, RawMapiMessageW};
+pub use recipient_descriptor::{RawMapiRecipDesc, RawMapiRecipDescW, RecipientDescriptor};
 
 mod file_descriptor;
 mod message;
@@ -1,8 +1,16 @@
-use std::convert::TryFrom;
-
+use crate::address;
+use crate::allocation::BufferChain;
 use crate::ffi::conversion;
+use crate::ffi::conversion::StringDecoder;
+use crate::flags::{MapiRecipClass, MapiStatusCode};
+use crate::logger;
+use crate::mime;
+use crate::mime::Mailbox;
 use crate::types::*;
 
+/// entry ids of recipients we resolved ourselves start with this
+const ENTRY_ID_PREFIX: &[u8] = b"mapirs:";
+
 #[repr(C)]
 #[derive(Debug)]
 pub struct RawMapiRecipDesc {
@@ -20,19 +28,105 @@ pub struct RawMapiRecipDesc {
     entry_id: *const libc::c_uchar,
 }
 
+/// the same as RawMapiRecipDesc, but with UTF-16 strings
+#[repr(C)]
+#[derive(Debug)]
+pub struct RawMapiRecipDescW {
+    // ULONG ulReserved - reserved for future use
+    reserved: ULong,
+    // ULONG ulRecipClass - recipient class
+    recip_class: ULong,
+    // PWSTR lpszName - recipient name
+    name: LpWStr,
+    // PWSTR lpszAddress - recitpient address (optional)
+    address: LpWStr,
+    // ULONG ulEIDSize count in bytes of size of pEntryID
+    eid_size: ULong,
+    // LPVOID lpEntryID system-specific recipient reference
+    entry_id: *const libc::c_uchar,
+}
+
 #[derive(Debug)]
 pub struct RecipientDescriptor {
-    _recip_class: ULong,
-    _name: String,
+    pub recip_class: ULong,
+    pub name: String,
     pub address: Option<String>,
     _entry_id: Vec<u8>,
 }
 
-impl TryFrom<*const RawMapiRecipDesc> for RecipientDescriptor {
-    type Error = ();
-    fn try_from(raw_ptr: *const RawMapiRecipDesc) -> Result<Self, Self::Error> {
+impl RawMapiRecipDescW {
+    /// the strings have to outlive the descriptor
+    #[cfg(test)]
+    pub fn new(recip_class: ULong, name: LpWStr, address: LpWStr) -> Self {
+        Self {
+            reserved: 0,
+            recip_class,
+            name,
+            address,
+            eid_size: 0,
+            entry_id: std::ptr::null(),
+        }
+    }
+}
+
+/// a single RawMapiRecipDesc can describe more than one recipient if the
+/// calling app put an address list into it, so it's converted into a Vec.
+/// the conversion fails if one of the addresses can't be normalized.
+impl TryFrom<&RawMapiRecipDesc> for Vec<RecipientDescriptor> {
+    type Error = MapiStatusCode;
+    fn try_from(raw: &RawMapiRecipDesc) -> Result<Self, Self::Error> {
+        RecipientDescriptor::from_raw(raw, &StringDecoder::default())
+    }
+}
+
+/// see TryFrom<&RawMapiRecipDesc>
+impl TryFrom<&RawMapiRecipDescW> for Vec<RecipientDescriptor> {
+    type Error = MapiStatusCode;
+    fn try_from(raw: &RawMapiRecipDescW) -> Result<Self, Self::Error> {
+        RecipientDescriptor::from_parts(
+            raw.recip_class,
+            conversion::maybe_string_from_raw_wide_ptr(raw.name),
+            conversion::maybe_string_from_raw_wide_ptr(raw.address),
+            conversion::copy_c_array_to_vec(raw.entry_id, raw.eid_size as usize),
+        )
+    }
+}
+
+impl RecipientDescriptor {
+    /// convert a RawMapiRecipDesc, decoding its strings with the
+    /// code page of the message it belongs to
+    pub fn from_raw(
+        raw: &RawMapiRecipDesc,
+        decoder: &StringDecoder,
+    ) -> Result<Vec<Self>, MapiStatusCode> {
+        Self::from_parts(
+            raw.recip_class,
+            decoder.decode(raw.name, "recipient name"),
+            decoder.decode(raw.address, "recipient address"),
+            conversion::copy_c_array_to_vec(raw.entry_id, raw.eid_size as usize),
+        )
+    }
+
+    /// like TryFrom<&RawMapiRecipDescW>, but for a pointer that may be null
+    pub fn try_from_raw_wide_ptr(
+        raw_ptr: *const RawMapiRecipDescW,
+    ) -> Result<Vec<Self>, MapiStatusCode> {
         if raw_ptr.is_null() {
-            Err(())
+            Err(MapiStatusCode::Failure)
+        } else {
+            // SAFETY: see try_from_raw_ptr
+            let raw: &RawMapiRecipDescW = unsafe { &*raw_ptr };
+            Vec::try_from(raw)
+        }
+    }
+
+    /// like from_raw, but for a pointer that may be null
+    pub fn try_from_raw_ptr(
+        raw_ptr: *const RawMapiRecipDesc,
+        decoder: &StringDecoder,
+    ) -> Result<Vec<Self>, MapiStatusCode> {
+        if raw_ptr.is_null() {
+            Err(MapiStatusCode::Failure)
         } else {
             /*
             SAFETY: https://doc.rust-lang.org/book/ch19-01-unsafe-rust.html#dereferencing-a-raw-pointer
@@ -50,52 +144,153 @@ impl TryFrom<*const RawMapiRecipDesc> for RecipientDescriptor {
                 -> we got the ptr over ffi, so the calling app needs to clean this up
             */
             let raw: &RawMapiRecipDesc = unsafe { &*raw_ptr };
-            Ok(Self::from(raw))
+            Self::from_raw(raw, decoder)
         }
     }
-}
 
-impl From<&RawMapiRecipDesc> for RecipientDescriptor {
-    fn from(raw: &RawMapiRecipDesc) -> Self {
-        // some applications (Sage50) prefix the mail addresses with SMTP: which is
-        // technically not valid, but we're going to make a best effort to allow this.
-        // ":" is only allowed in quoted local parts so we're not going to destroy
-        // valid mail addresses with this.
-        let address = conversion::maybe_string_from_raw_ptr(raw.address).map(|a| {
-            if a.starts_with("SMTP:") {
-                a.strip_prefix("SMTP:").unwrap().to_owned()
-            } else {
-                a
-            }
-        });
+    /// a recipient we resolved ourselves, with an entry id we can recognize later.
+    pub fn resolved(name: String, address: String) -> Self {
+        Self::issued(MapiRecipClass::To, name, address)
+    }
 
+    /// a recipient we made ourselves (by resolving it or reading it from a message),
+    /// with an entry id we can recognize later.
+    pub fn issued(recip_class: MapiRecipClass, name: String, address: String) -> Self {
+        let mut entry_id = ENTRY_ID_PREFIX.to_vec();
+        entry_id.extend_from_slice(address.as_bytes());
         RecipientDescriptor {
-            _recip_class: raw.recip_class,
-            _name: conversion::maybe_string_from_raw_ptr(raw.name)
-                .unwrap_or_else(|| "MISSING_RECIP_NAME".to_owned()),
-            address,
-            _entry_id: conversion::copy_c_array_to_vec(raw.entry_id, raw.eid_size as usize),
+            recip_class: recip_class as ULong,
+            name,
+            address: Some(address),
+            _entry_id: entry_id,
         }
     }
-}
 
-impl RecipientDescriptor {
+    /// the address in the entry id if it's one we issued (see issued), None otherwise
+    pub fn issued_address(&self) -> Option<&str> {
+        self._entry_id
+            .strip_prefix(ENTRY_ID_PREFIX)
+            .and_then(|address| std::str::from_utf8(address).ok())
+    }
+
+    /// true if the recipient doesn't have an entry id, which means it was never resolved.
+    pub fn is_unresolved(&self) -> bool {
+        self._entry_id.is_empty()
+    }
+
+    /// build a RawMapiRecipDesc that can be handed to the calling app,
+    /// encoding the strings with the code page it expects. the strings and
+    /// the entry id are added to chain, so they're freed together with the
+    /// allocation the RawMapiRecipDesc ends up in.
+    pub fn to_raw(&self, chain: &mut BufferChain, encoder: &StringDecoder) -> RawMapiRecipDesc {
+        RawMapiRecipDesc {
+            reserved: 0,
+            recip_class: self.recip_class,
+            name: chain.add_string(encoder.encode(&self.name)),
+            address: chain.add_string(encoder.encode(self.address.as_deref().unwrap_or(""))),
+            eid_size: self._entry_id.len() as ULong,
+            entry_id: chain.add_vec(self._entry_id.clone()),
+        }
+    }
+
+    /// build RecipientDescriptors from the already converted fields
+    /// of a RawMapiRecipDesc or RawMapiRecipDescW. address lists like
+    /// "a@x.de; Jane <b@y.de>" are split into one descriptor per address,
+    /// which are normalized with address::normalize.
+    fn from_parts(
+        recip_class: ULong,
+        name: Option<String>,
+        address: Option<String>,
+        entry_id: Vec<u8>,
+    ) -> Result<Vec<Self>, MapiStatusCode> {
+        let address = address.filter(|a| !a.trim().is_empty());
+        let from_name = address.is_none();
+        let mailboxes = match (&address, &name) {
+            (Some(address), _) => normalize_all(split_addresses(address))?,
+            // many apps leave lpszAddress empty and put the address into lpszName,
+            // either on its own or as "Jane Doe <jane@example.com>".
+            // the name may just as well be only a name, so failing to normalize is fine.
+            (None, Some(name)) => split_addresses(name)
+                .into_iter()
+                .filter(|mailbox| mailbox.address.contains('@'))
+                .filter_map(|mailbox| normalize_all(vec![mailbox]).ok())
+                .flatten()
+                .collect(),
+            (None, None) => vec![],
+        };
+
+        if mailboxes.is_empty() {
+            logger::warn(
+                "RecipientDescriptor::from_parts",
+                &format!("recipient {:?} has no usable address", name),
+            );
+            return Ok(vec![RecipientDescriptor {
+                recip_class,
+                name: name.unwrap_or_else(|| "MISSING_RECIP_NAME".to_owned()),
+                address,
+                _entry_id: entry_id,
+            }]);
+        }
+
+        // the entry id and the name are about the whole descriptor, so they
+        // only stay if it doesn't turn into more than one.
+        let single = mailboxes.len() == 1;
+        Ok(mailboxes
+            .into_iter()
+            .map(|mailbox| {
+                let name = match (&name, mailbox.name.is_empty()) {
+                    (Some(name), _) if single && !from_name => name.clone(),
+                    (_, false) => mailbox.name,
+                    (_, true) => mailbox.address.clone(),
+                };
+                RecipientDescriptor {
+                    recip_class,
+                    name,
+                    address: Some(mailbox.address),
+                    _entry_id: if single { entry_id.clone() } else { vec![] },
+                }
+            })
+            .collect())
+    }
+
     #[cfg(test)]
     pub fn new(address: &str) -> Self {
         Self {
-            _recip_class: 0,
-            _name: "".to_owned(),
+            recip_class: MapiRecipClass::To as ULong,
+            name: "".to_owned(),
             address: Some(address.to_owned()),
             _entry_id: vec![0, 0, 0, 0],
         }
     }
 }
 
+/// split an address list separated by ; or , into its mailboxes, respecting
+/// quoted local parts and display names.
+fn split_addresses(addresses: &str) -> Vec<Mailbox> {
+    mime::split_address_list(addresses)
+}
+
+/// normalize the addresses of the mailboxes, failing if any of them can't be.
+fn normalize_all(mailboxes: Vec<Mailbox>) -> Result<Vec<Mailbox>, MapiStatusCode> {
+    mailboxes
+        .into_iter()
+        .map(|mailbox| {
+            Ok(Mailbox {
+                address: address::normalize(&mailbox.address)?,
+                name: mailbox.name,
+            })
+        })
+        .collect()
+}
+
 #[cfg(test)]
 mod test {
     use std::ffi::CStr;
 
-    use crate::structs::{RawMapiRecipDesc, RecipientDescriptor};
+    use crate::allocation::BufferChain;
+    use crate::ffi::conversion::StringDecoder;
+    use crate::flags::{MapiStatusCode, MessageCodePage};
+    use crate::structs::{RawMapiRecipDesc, RawMapiRecipDescW, RecipientDescriptor};
 
     #[test]
     fn smtp_prefix_is_stripped() {
@@ -110,13 +305,172 @@ mod test {
 
         let address1 = raw(&"SMTP:a@b.c\0");
         let address2 = raw(&"\"SMTP:a\"@b.c\0");
+        let address3 = raw("SMTP:a@b.c; SMTP:d@e.f\0");
+        let addresses = |raw| {
+            Vec::<RecipientDescriptor>::try_from(raw)
+                .unwrap()
+                .into_iter()
+                .map(|r| r.address.unwrap())
+                .collect::<Vec<String>>()
+        };
+        assert_eq!(addresses(&address1), vec!["a@b.c"]);
+        assert_eq!(addresses(&address2), vec!["\"SMTP:a\"@b.c"]);
+        assert_eq!(addresses(&address3), vec!["a@b.c", "d@e.f"]);
+    }
+
+    #[test]
+    fn address_is_taken_from_name() {
+        let recip = |name: Option<&str>, address: Option<&str>| {
+            RecipientDescriptor::from_parts(
+                1,
+                name.map(|n| n.to_owned()),
+                address.map(|a| a.to_owned()),
+                vec![],
+            )
+            .unwrap()
+            .remove(0)
+        };
+
+        let jane = recip(Some("Jane Doe <jane@example.com>"), None);
+        assert_eq!(jane.name, "Jane Doe");
+        assert_eq!(jane.address, Some("jane@example.com".to_owned()));
+
+        let jane = recip(Some("\"Doe, Jane\" <jane@example.com>"), Some(""));
+        assert_eq!(jane.name, "Doe, Jane");
+        assert_eq!(jane.address, Some("jane@example.com".to_owned()));
+
+        let bare = recip(Some(" jane@example.com "), None);
+        assert_eq!(bare.name, "jane@example.com");
+        assert_eq!(bare.address, Some("jane@example.com".to_owned()));
+
+        // an address wins over the name
+        let both = recip(Some("x@y.de"), Some("jane@example.com"));
+        assert_eq!(both.name, "x@y.de");
+        assert_eq!(both.address, Some("jane@example.com".to_owned()));
+
+        let nothing = recip(Some("Jane Doe"), None);
+        assert_eq!(nothing.name, "Jane Doe");
+        assert_eq!(nothing.address, None);
+        assert_eq!(recip(None, None).address, None);
+        // names that look like addresses but aren't ones are treated as names
+        let broken = recip(Some("Jane <jane@>"), None);
+        assert_eq!(broken.name, "Jane <jane@>");
+        assert_eq!(broken.address, None);
+    }
+
+    #[test]
+    fn addresses_are_normalized() {
+        let recips = |address: &str| {
+            RecipientDescriptor::from_parts(1, None, Some(address.to_owned()), vec![]).map(|r| {
+                r.into_iter()
+                    .map(|r| r.address.unwrap())
+                    .collect::<Vec<String>>()
+            })
+        };
+        assert_eq!(
+            recips("smtp:a@b.c; mailto:d@e.f, <g@h.i>"),
+            Ok(vec![
+                "a@b.c".to_owned(),
+                "d@e.f".to_owned(),
+                "g@h.i".to_owned()
+            ])
+        );
         assert_eq!(
-            RecipientDescriptor::from(&address1).address,
-            Some("a@b.c".to_owned())
+            recips("a@bücher.de"),
+            Ok(vec!["a@xn--bcher-kva.de".to_owned()])
         );
         assert_eq!(
-            RecipientDescriptor::from(&address2).address,
-            Some("\"SMTP:a\"@b.c".to_owned())
+            recips("a@b.c; EX:/o=Org/ou=Site/cn=Recipients/cn=jdoe"),
+            Err(MapiStatusCode::UnknownRecipient)
         );
+        assert_eq!(recips("a@b.c; d@"), Err(MapiStatusCode::InvalidRecips));
+    }
+
+    #[test]
+    fn address_lists_are_split() {
+        let split = |name: Option<&str>, address: Option<&str>| {
+            RecipientDescriptor::from_parts(
+                2,
+                name.map(|n| n.to_owned()),
+                address.map(|a| a.to_owned()),
+                b"mapirs:x".to_vec(),
+            )
+            .unwrap()
+            .into_iter()
+            .map(|r| {
+                assert_eq!(r.recip_class, 2);
+                (r.name, r.address.unwrap_or_default(), r._entry_id.len())
+            })
+            .collect::<Vec<(String, String, usize)>>()
+        };
+
+        assert_eq!(
+            split(Some("Team"), Some("a@x.de; b@y.de, c@z.de")),
+            vec![
+                ("a@x.de".to_owned(), "a@x.de".to_owned(), 0),
+                ("b@y.de".to_owned(), "b@y.de".to_owned(), 0),
+                ("c@z.de".to_owned(), "c@z.de".to_owned(), 0),
+            ]
+        );
+        assert_eq!(
+            split(None, Some("\"Doe; Jane\" <j@x.de>,\"a,b\"@y.de")),
+            vec![
+                ("Doe; Jane".to_owned(), "j@x.de".to_owned(), 0),
+                ("\"a,b\"@y.de".to_owned(), "\"a,b\"@y.de".to_owned(), 0),
+            ]
+        );
+        assert_eq!(
+            split(Some("Jane <j@x.de>; b@y.de"), None),
+            vec![
+                ("Jane".to_owned(), "j@x.de".to_owned(), 0),
+                ("b@y.de".to_owned(), "b@y.de".to_owned(), 0),
+            ]
+        );
+        // a single address keeps the name and entry id
+        assert_eq!(
+            split(Some("Jane"), Some("j@x.de;")),
+            vec![("Jane".to_owned(), "j@x.de".to_owned(), 8)]
+        );
+    }
+
+    #[test]
+    fn wide_recipient_is_converted() {
+        let name: Vec<u16> = "Jürgen Wölk\0".encode_utf16().collect();
+        let address: Vec<u16> = "SMTP:jürgen@wölk.de\0".encode_utf16().collect();
+        let raw = RawMapiRecipDescW {
+            reserved: 0,
+            recip_class: 1,
+            name: name.as_ptr(),
+            address: address.as_ptr(),
+            eid_size: 0,
+            entry_id: std::ptr::null(),
+        };
+
+        let recip = Vec::<RecipientDescriptor>::try_from(&raw)
+            .unwrap()
+            .remove(0);
+        assert_eq!(recip.name, "Jürgen Wölk");
+        assert_eq!(recip.address, Some("jürgen@xn--wlk-sna.de".to_owned()));
+        assert_eq!(recip.recip_class, 1);
+    }
+
+    #[test]
+    fn to_raw_round_trips() {
+        let encoder = StringDecoder::new(MessageCodePage::Ansi);
+        let recip = RecipientDescriptor::resolved("Jürgen".to_owned(), "j@b.de".to_owned());
+        let mut chain = BufferChain::new();
+        let raw = recip.to_raw(&mut chain, &encoder);
+        assert_eq!(raw.recip_class, 1);
+        assert_eq!(unsafe { CStr::from_ptr(raw.name) }.to_bytes(), b"J\xFCrgen");
+
+        let back = RecipientDescriptor::from_raw(&raw, &encoder)
+            .unwrap()
+            .remove(0);
+        assert_eq!(back.name, "Jürgen");
+        assert_eq!(back.address, Some("j@b.de".to_owned()));
+        assert_eq!(back._entry_id, b"mapirs:j@b.de");
+        assert_eq!(back.issued_address(), Some("j@b.de"));
+        assert!(!back.is_unresolved());
+        assert_eq!(RecipientDescriptor::new("j@b.de").issued_address(), None);
     }
 }
@@ -0,0 +1,304 @@
+use std::ffi::OsStr;
+use std::fmt::Debug;
+use std::io;
+
+use time::OffsetDateTime;
+
+use crate::commands::SendMode;
+use crate::environment;
+use crate::environment::client_path;
+use crate::flags::MapiStatusCode;
+use crate::ipc::IpcTransport;
+use crate::logger;
+use crate::manifest;
+use crate::settings;
+use crate::settings::Handoff;
+use crate::store::MessageStore;
+use crate::structs::{AttachmentMode, Message};
+
+/// tells the client to send the message right away instead of opening the composer
+const UNATTENDED_SWITCH: &str = "--mapi-unattended";
+/// tells the client that the next argument is the path of an .eml file with the message
+const EML_SWITCH: &str = "--mapi-eml";
+/// tells the client that the next argument is the correlation id of the call in our log
+const CALL_ID_SWITCH: &str = "--mapi-call";
+
+/// windows doesn't start processes with a longer command line (including the executable)
+const MAX_COMMAND_LINE_LENGTH: usize = 32767;
+
+/// a way to get a message to the client. the message is already sanitized and
+/// checked, the transport only needs to deliver it. the error is what the
+/// calling app gets back from MAPISendMail.
+pub trait Transport: Debug {
+    fn deliver(&self, msg: &Message, mode: SendMode) -> Result<(), MapiStatusCode>;
+}
+
+/// the transport for the configured handoff
+pub fn configured() -> Box<dyn Transport> {
+    for_handoff(settings::get().handoff)
+}
+
+fn for_handoff(handoff: Handoff) -> Box<dyn Transport> {
+    match handoff {
+        Handoff::Mailto => Box::new(MailtoTransport),
+        Handoff::Eml => Box::new(FileTransport(FileFormat::Eml(AttachmentMode::Embed))),
+        Handoff::EmlReference => {
+            Box::new(FileTransport(FileFormat::Eml(AttachmentMode::Reference)))
+        }
+        Handoff::Manifest => Box::new(FileTransport(FileFormat::Manifest)),
+        Handoff::Ipc => Box::new(IpcTransport::new(Box::new(MailtoTransport))),
+        #[cfg(test)]
+        Handoff::Recording => Box::new(RecordingTransport),
+    }
+}
+
+/// starts the client with a mailto: link on the command line. if that gets
+/// too long, it uses an .eml file with embedded attachments instead.
+#[derive(Debug)]
+pub struct MailtoTransport;
+
+impl MailtoTransport {
+    /// exe_len is the length of the path to the client, which counts
+    /// towards the length of the command line.
+    fn args(msg: &Message, mode: SendMode, exe_len: usize) -> io::Result<Vec<String>> {
+        let mut args = common_args(mode);
+        args.push(msg.make_mailto_link());
+        if command_line_length(exe_len, &args) <= MAX_COMMAND_LINE_LENGTH {
+            return Ok(args);
+        }
+        logger::info("mailto", "mailto link is too long, using an .eml file");
+        args.pop();
+        args.push(EML_SWITCH.to_owned());
+        args.push(write_eml(msg, AttachmentMode::Embed)?);
+        Ok(args)
+    }
+}
+
+impl Transport for MailtoTransport {
+    fn deliver(&self, msg: &Message, mode: SendMode) -> Result<(), MapiStatusCode> {
+        client_path()
+            .and_then(|exe| {
+                let args = Self::args(msg, mode, exe.len())?;
+                spawn_client(&exe, args, mode)
+            })
+            .map_err(could_not_start)
+    }
+}
+
+/// the kinds of files a FileTransport can hand off
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileFormat {
+    /// an .eml file in TMPPath, its path goes on the command line
+    Eml(AttachmentMode),
+    /// a JSON manifest in TMPPath, its tutanota://mapi/<id> url goes on the command line
+    Manifest,
+}
+
+/// writes the message to a file and starts the client with a reference to it
+#[derive(Debug)]
+pub struct FileTransport(pub FileFormat);
+
+impl FileTransport {
+    fn args(&self, msg: &Message, mode: SendMode) -> io::Result<Vec<String>> {
+        let mut args = common_args(mode);
+        match self.0 {
+            FileFormat::Eml(attachment_mode) => {
+                args.push(EML_SWITCH.to_owned());
+                args.push(write_eml(msg, attachment_mode)?);
+            }
+            FileFormat::Manifest => args.push(write_manifest(msg)?),
+        }
+        Ok(args)
+    }
+}
+
+impl Transport for FileTransport {
+    fn deliver(&self, msg: &Message, mode: SendMode) -> Result<(), MapiStatusCode> {
+        client_path()
+            .and_then(|exe| {
+                let args = self.args(msg, mode)?;
+                spawn_client(&exe, args, mode)
+            })
+            .map_err(could_not_start)
+    }
+}
+
+/// what a RecordingTransport was asked to deliver
+#[cfg(test)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Delivery {
+    pub mode: SendMode,
+    /// the correlation id of the call that delivered it
+    pub call_id: Option<String>,
+    /// the mailto link for the message, it contains everything but the attachment contents
+    pub mailto: String,
+}
+
+#[cfg(test)]
+thread_local! {
+    static DELIVERIES: std::cell::RefCell<Vec<Delivery>> = const { std::cell::RefCell::new(vec![]) };
+}
+
+/// doesn't write or start anything, remembers the deliveries of the current thread instead
+#[cfg(test)]
+#[derive(Debug)]
+pub struct RecordingTransport;
+
+#[cfg(test)]
+impl RecordingTransport {
+    /// the deliveries recorded on this thread since the last call
+    pub fn take() -> Vec<Delivery> {
+        DELIVERIES.with(|d| d.take())
+    }
+}
+
+#[cfg(test)]
+impl Transport for RecordingTransport {
+    fn deliver(&self, msg: &Message, mode: SendMode) -> Result<(), MapiStatusCode> {
+        let delivery = Delivery {
+            mode,
+            call_id: logger::call_id(),
+            mailto: msg.make_mailto_link(),
+        };
+        DELIVERIES.with(|d| d.borrow_mut().push(delivery));
+        Ok(())
+    }
+}
+
+/// the switches for the mode and the current call, they go before the message
+fn common_args(mode: SendMode) -> Vec<String> {
+    let mut args = match mode {
+        SendMode::Unattended => vec![UNATTENDED_SWITCH.to_owned()],
+        SendMode::Compose => vec![],
+    };
+    if let Some(call_id) = logger::call_id() {
+        args.push(CALL_ID_SWITCH.to_owned());
+        args.push(call_id);
+    }
+    args
+}
+
+fn spawn_client(exe: &OsStr, args: Vec<String>, mode: SendMode) -> io::Result<()> {
+    // the arguments can contain the whole message, so they're only logged when asked for
+    logger::trace(
+        "spawn_client",
+        &format!("starting {:?} with {:?}", exe, args),
+    );
+    environment::spawn(exe, &args)?;
+    logger::info(
+        "spawn_client",
+        &format!("spawned tutanota client ({:?})", mode),
+    );
+    Ok(())
+}
+
+fn could_not_start(e: io::Error) -> MapiStatusCode {
+    logger::error("transport", &format!("could not start the client: {:?}", e));
+    MapiStatusCode::Failure
+}
+
+/// the length of the command line windows builds from the executable path and
+/// the arguments, assuming they're all quoted.
+fn command_line_length(exe_len: usize, args: &[String]) -> usize {
+    args.iter()
+        .fold(exe_len + 2, |len, arg| len + arg.len() + 3)
+}
+
+/// write the message to a new manifest and return the url for it
+fn write_manifest(msg: &Message) -> io::Result<String> {
+    let manifest = msg
+        .to_manifest()
+        .map_err(|e| io::Error::other(format!("{:?}", e)))?;
+    let id = manifest::write(&manifest)?;
+    Ok(manifest::url(&id))
+}
+
+/// write the message to a new .eml file in the outbox and return its path
+fn write_eml(msg: &Message, attachment_mode: AttachmentMode) -> io::Result<String> {
+    let to_io_error = |e| io::Error::other(format!("{:?}", e));
+    let contents = msg
+        .to_eml(OffsetDateTime::now_utc(), attachment_mode)
+        .map_err(to_io_error)?;
+    let outbox = MessageStore::outbox()?;
+    let id = outbox.save(None, &contents).map_err(to_io_error)?;
+    let path = outbox
+        .path_for(&id)
+        .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+    Ok(path.to_string_lossy().into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::commands::SendMode;
+    use crate::logger;
+    use crate::settings::Handoff;
+    use crate::structs::Message;
+    use crate::transport::{
+        command_line_length, for_handoff, Delivery, MailtoTransport, RecordingTransport, Transport,
+        MAX_COMMAND_LINE_LENGTH,
+    };
+
+    #[test]
+    fn mailto_args_work() {
+        let msg = Message::new(vec!["a@b.de"], None, None, vec![]);
+        let args = |mode| MailtoTransport::args(&msg, mode, 20).unwrap();
+        assert_eq!(args(SendMode::Compose), vec!["mailto:a@b.de"]);
+        assert_eq!(
+            args(SendMode::Unattended),
+            vec!["--mapi-unattended", "mailto:a@b.de"]
+        );
+
+        let _call = logger::begin_call("mailto_args_work");
+        let call_id = logger::call_id().unwrap();
+        assert_eq!(
+            args(SendMode::Compose),
+            vec!["--mapi-call", &call_id, "mailto:a@b.de"]
+        );
+    }
+
+    #[test]
+    fn for_handoff_works() {
+        let transport = |handoff| format!("{:?}", for_handoff(handoff));
+        assert_eq!(transport(Handoff::Mailto), "MailtoTransport");
+        assert_eq!(transport(Handoff::Eml), "FileTransport(Eml(Embed))");
+        assert_eq!(
+            transport(Handoff::EmlReference),
+            "FileTransport(Eml(Reference))"
+        );
+        assert_eq!(transport(Handoff::Manifest), "FileTransport(Manifest)");
+        assert!(transport(Handoff::Ipc).starts_with("IpcTransport {"));
+        assert_eq!(transport(Handoff::Recording), "RecordingTransport");
+    }
+
+    #[test]
+    fn command_line_length_works() {
+        assert_eq!(command_line_length(10, &[]), 12);
+        assert_eq!(
+            command_line_length(
+                10,
+                &["--mapi-unattended".to_owned(), "mailto:a@b.de".to_owned()]
+            ),
+            12 + 20 + 16
+        );
+
+        let body = "a".repeat(MAX_COMMAND_LINE_LENGTH);
+        let msg = Message::new(vec!["a@b.de"], Some(&body), None, vec![]);
+        let link = msg.make_mailto_link();
+        assert!(command_line_length(20, &[link]) > MAX_COMMAND_LINE_LENGTH);
+    }
+
+    #[test]
+    fn recording_transport_works() {
+        let msg = Message::new(vec!["a@b.de"], None, None, vec![]);
+        RecordingTransport.deliver(&msg, SendMode::Compose).unwrap();
+        assert_eq!(
+            RecordingTransport::take(),
+            vec![Delivery {
+                mode: SendMode::Compose,
+                call_id: None,
+                mailto: "mailto:a@b.de".to_owned(),
+            }]
+        );
+        assert!(RecordingTransport::take().is_empty());
+    }
+}
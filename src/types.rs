@@ -4,3 +4,8 @@ pub type LpByte = *const u8;
 pub type LpStr = *const libc::c_char;
 pub type InLpStr = *mut libc::c_char;
 pub type LpVoid = *const libc::c_void;
+// PWSTR, windows' wchar_t is 16 bits wide
+pub type LpWStr = *const u16;
+// LHANDLE is a ULONG_PTR
+pub type LHandle = usize;
+pub type LpLHandle = *mut LHandle;